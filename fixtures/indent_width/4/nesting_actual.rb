@@ -0,0 +1,32 @@
+class Foo
+  # namespace
+  stable_id "really_stable_id"
+
+  def example
+    things
+      .map do |thing|
+        case thing
+        when Paul
+          thing.call
+        when Blart
+          thing.also_call
+        end
+      end
+      .uniq
+  end
+
+  def heredocs
+    c = <<~EOD
+      part 1 of heredoc
+    EOD
+
+    description(
+      <<~DESC
+        thing
+      DESC
+    )
+
+    # the end
+    puts(c)
+  end
+end
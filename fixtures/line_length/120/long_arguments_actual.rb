@@ -0,0 +1 @@
+result = process(fetch_records(:alpha, :bravo, :charlie), transform_each(:delta, :echo, :foxtrot))
@@ -0,0 +1,35 @@
+foo(
+  :alpha,
+  :bravo,
+  :charlie,
+  :delta,
+  :echo,
+  :foxtrot
+)
+
+[
+  1000,
+  2000,
+  3000,
+  4000,
+  5000,
+  6000,
+  7000,
+  8000,
+  9000,
+  10000
+]
+
+bar(
+  :alpha,
+  :bravo,
+  :charlie,
+  :delta,
+  :echo,
+  :foxtrot,
+  :golf,
+  :hotel,
+  :india,
+  :juliett,
+  :kilo
+)
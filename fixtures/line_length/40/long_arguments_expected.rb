@@ -0,0 +1,12 @@
+result = process(
+  fetch_records(
+    :alpha,
+    :bravo,
+    :charlie
+  ),
+  transform_each(
+    :delta,
+    :echo,
+    :foxtrot
+  )
+)
@@ -0,0 +1,7 @@
+greeting = 'hello'
+name = 'world'
+interpolated = "hello #{name}"
+literal_interpolation = "\#{name}"
+newline = "line\n"
+apostrophe = "it's"
+empty = ''
@@ -0,0 +1,54 @@
+expected = 1
+
+case value
+in [Integer => a, String]
+  a
+in {name: String => name, age:} if age > 18
+  name
+in [*, 3, *post]
+  post
+in {status: :ok, **nil}
+  :ok
+in ^expected | nil
+  nil
+in Point(x:, y:)
+  x + y
+in Foo[a, *rest] unless rest.empty?
+  rest
+in Integer | Float => number
+  number
+in first, *
+  first
+in 1
+  :one
+in 1..5
+  :small
+in []
+  nil
+in {}
+  nil
+else
+  raise
+end
+
+case response
+in {
+  status: Integer => status,
+  headers: {content_type: String => content_type},
+  body: {data: [*, {id: Integer => id}, *]},
+  meta: {request_id: String => request_id}
+}
+  id
+end
+
+case state
+in (
+  :accepted_for_processing |
+  :awaiting_manual_review |
+  :pending_external_verification |
+  :requires_additional_identity_documents
+)
+  :waiting
+in Config(name: String => name, **nil)
+  name
+end
@@ -0,0 +1,18 @@
+for item in items
+  item => {name:}
+end
+
+case event
+in {type: String => type}
+  matched = ({"type" => type} in Hash)
+end
+
+values.each do |value|
+  value in [] | {}
+end
+
+begin
+  run
+rescue StandardError => e
+  {"message" => e.message} => {}
+end
@@ -0,0 +1,32 @@
+config = {host: "localhost", port: 8080}
+config => {host:, port:}
+
+response.fetch(:data)   =>   [first, *rest]
+value => Integer | Float => number
+
+if value in [Integer, Integer]
+  puts "pair"
+end
+
+puts "pair" if value in [Integer, Integer]
+matched = (value in {name: String})
+
+case thing
+in [x, y]
+  x in Integer
+end
+
+event => {type: "user.created", payload: {user: {id: user_id, email: user_email, name: user_name}}}
+
+begin
+  run
+rescue StandardError => e
+  e in {message: String}
+end
+
+if {"env" => env} in Hash
+  puts "hash"
+end
+
+settings = {"retries" => 3}
+settings => {}
@@ -0,0 +1,8 @@
+case state
+in ^(limit   +   1)
+  :over
+in ^@threshold
+  :at_threshold
+in [^@@minimum, ^$maximum]
+  :bounds
+end
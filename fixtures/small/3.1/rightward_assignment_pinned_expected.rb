@@ -0,0 +1,3 @@
+matched = (value in ^({"role" => "admin"}))
+value in ^({"a" => {"b" => 1}}) | nil
+value => ^(limit) => pinned
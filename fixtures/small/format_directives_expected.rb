@@ -0,0 +1,20 @@
+a = 1
+
+# rubyfmt:disable
+LOOKUP = {
+  a:     1,
+  bb:    2,
+  ccc:   3,
+}
+
+foo   bar,   baz
+# rubyfmt:enable
+b = 2
+
+class Foo
+  def bar
+    # rubyfmt:disable-next
+    x   =   [1,2,3]
+    y = [1, 2, 3]
+  end
+end
@@ -0,0 +1,8 @@
+puts 'a\b'
+puts 'C:\path\to\file'
+puts '\\1'
+puts 'it\'s'
+puts 'say "hi"'
+puts '#{not_interpolated}'
+puts '\n'
+puts :'sym\bol'
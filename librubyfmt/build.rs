@@ -14,6 +14,8 @@ struct RubyConfig {
 }
 
 fn main() -> Output {
+    generate_header()?;
+
     #[cfg(target_os = "linux")]
     let libname = "ruby-static";
     #[cfg(target_os = "macos")]
@@ -82,6 +84,16 @@ fn main() -> Output {
     Ok(())
 }
 
+// Generate rubyfmt.h from the C API in src/lib.rs, configured by
+// cbindgen.toml. Builds shouldn't write to the source tree, so it goes in
+// OUT_DIR, and script/update_c_header copies it to include/rubyfmt.h.
+fn generate_header() -> Output {
+    let crate_dir = env::var("CARGO_MANIFEST_DIR")?;
+    let out_dir = env::var("OUT_DIR")?;
+    cbindgen::generate(&crate_dir)?.write_to_file(Path::new(&out_dir).join("rubyfmt.h"));
+    Ok(())
+}
+
 fn extract_ruby_arch(ruby_checkout_path: &Path) -> String {
     let rbconfig_rb = ruby_checkout_path.join("rbconfig.rb");
     let f = File::open(rbconfig_rb).expect("cannot find rbconfig.rb");
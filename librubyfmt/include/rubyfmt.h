@@ -1,49 +1,312 @@
+// Generated by cbindgen from librubyfmt/src/lib.rs, run script/update_c_header instead of editing it by hand.
+
 #ifndef RUBYFMT_H
 #define RUBYFMT_H
 
-int RUBYFMT_INIT_STATUS_OK = 0;
-int RUBYFMT_INIT_STATUS_ERROR = 1;
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// The name this error had before the header was generated
+#define RUBYFMT_OTHER_RUBY_ERROR RUBYFMT_FORMAT_ERROR_OTHER_RUBY_ERROR
+
+// The version of the C API in `rubyfmt.h`, which changes whenever a
+// function's signature or behaviour does
+#define RUBYFMT_API_VERSION 2
+
+typedef enum Rubyfmt_FormatError {
+  RUBYFMT_FORMAT_ERROR_OK = 0,
+  // The buffer has a ruby syntax error. Non fatal, rubyfmt can still be
+  // used to format other buffers.
+  RUBYFMT_FORMAT_ERROR_SYNTAX_ERROR = 1,
+  // Rubyfmt couldn't deserialize ripper's tree. This is fatal, and a bug in
+  // rubyfmt that should be reported with the file that caused it.
+  RUBYFMT_FORMAT_ERROR_RIPPER_PARSE_FAILURE = 2,
+  // An IO error while formatting, which should be impossible and most
+  // likely a bug in rubyfmt
+  RUBYFMT_FORMAT_ERROR_IO_ERROR = 3,
+  // An unexpected ruby error, which is a bug in rubyfmt. The process should
+  // be restarted before formatting anything else.
+  RUBYFMT_FORMAT_ERROR_OTHER_RUBY_ERROR = 4,
+  // Only from the CLI's `--check` mode
+  RUBYFMT_FORMAT_ERROR_DIFF_DETECTED = 5,
+  // The formatted output doesn't parse to the same program as the input
+  RUBYFMT_FORMAT_ERROR_VERIFICATION_FAILED = 6,
+  // Rubyfmt panicked while formatting, which is a bug in rubyfmt. Non
+  // fatal, but if the ruby VM is no longer usable, later calls fail with
+  // the unexpected ruby error.
+  RUBYFMT_FORMAT_ERROR_INTERNAL_ERROR = 7,
+  // A request to a formatter was cancelled before it finished
+  RUBYFMT_FORMAT_ERROR_CANCELLED = 8,
+  // A request to a formatter didn't finish within its timeout, and was
+  // cancelled
+  RUBYFMT_FORMAT_ERROR_TIMED_OUT = 9,
+  // The buffer passed through the C API isn't valid UTF-8
+  RUBYFMT_FORMAT_ERROR_INVALID_UTF8 = 10,
+  // An option passed through the C API is out of range, e.g. a line
+  // length of 0
+  RUBYFMT_FORMAT_ERROR_INVALID_OPTION = 11,
+} Rubyfmt_FormatError;
+
+// How the keys of hashes and keyword arguments are written
+typedef enum RubyfmtHashStyle {
+  // `key: value` when every key can be written that way, and
+  // `:key => value` otherwise
+  RUBYFMT_HASH_STYLE_LABELS = 0,
+  // Always `:key => value`, except for keys without a value, like `{x:}`
+  RUBYFMT_HASH_STYLE_HASH_ROCKETS = 1,
+} RubyfmtHashStyle;
+
+typedef enum Rubyfmt_InitStatus {
+  RUBYFMT_INIT_STATUS_OK = 0,
+  RUBYFMT_INIT_STATUS_ERROR = 1,
+} Rubyfmt_InitStatus;
+
+// When method calls have parentheses around their arguments
+typedef enum RubyfmtParenStyle {
+  // Add or remove parentheses following rubyfmt's rules for each method
+  RUBYFMT_PAREN_STYLE_NORMALIZE = 0,
+  // Keep parentheses where the input had them, adding them only where
+  // leaving them out would change the meaning of the code
+  RUBYFMT_PAREN_STYLE_PRESERVE = 1,
+} RubyfmtParenStyle;
+
+// Which quotes string literals are written with
+typedef enum RubyfmtQuoteStyle {
+  // Always use double quotes
+  RUBYFMT_QUOTE_STYLE_DOUBLE = 0,
+  // Use single quotes for strings without interpolation or escapes
+  RUBYFMT_QUOTE_STYLE_SINGLE = 1,
+} RubyfmtQuoteStyle;
+
+// Options for formatting, each of which starts with its default. The CLI
+// reads them from the nearest `.rubyfmt.toml`.
+typedef struct RubyfmtOptions RubyfmtOptions;
+
+// A request to format a buffer, which can be waited on or cancelled from
+// any thread. Its result can only be waited for once, and it's cancelled if
+// it's freed before it finishes.
+typedef struct RubyfmtRequest RubyfmtRequest;
+
+// A handle for formatting on rubyfmt's VM thread, which can be used from
+// any number of threads at once
+typedef struct RubyfmtFormatter RubyfmtFormatter;
+
+typedef struct RubyfmtString RubyfmtString;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Sets up rubyfmt, once per process, returning `RUBYFMT_INIT_STATUS_ERROR`
+// if that failed. Formatting functions can only be called from the thread
+// that called this.
+int rubyfmt_init(void);
+
+// Formats the `len` bytes at `ptr`, returning the formatted buffer, which
+// must be freed with `rubyfmt_string_free`. If they aren't valid UTF-8 or
+// can't be formatted, this returns null and sets `*err` to why, which
+// `rubyfmt_last_error_message` describes.
+//
+// # Safety
+// `ptr` must point to at least `len` readable bytes, and `err` must not be
+// null.
+struct RubyfmtString *rubyfmt_format_buffer(const uint8_t *ptr,
+                                            size_t len,
+                                            enum Rubyfmt_FormatError *err);
+
+// Deprecated: use `rubyfmt_options_set_line_length` with
+// `rubyfmt_format_buffer_with_options` instead. This is kept for callers of
+// earlier versions, and is the same as `rubyfmt_format_buffer`, but breaks
+// up lines longer than `line_length` instead of the default of 120 columns.
+// A `line_length` of 0 is rejected with
+// `RUBYFMT_FORMAT_ERROR_INVALID_OPTION`.
+//
+// # Safety
+// The same as `rubyfmt_format_buffer`.
+struct RubyfmtString *rubyfmt_format_buffer_with_line_length(const uint8_t *ptr,
+                                                             size_t len,
+                                                             size_t line_length,
+                                                             enum Rubyfmt_FormatError *err);
+
+// The same as `rubyfmt_format_buffer`, but formats with `options`, which
+// must have come from `rubyfmt_options_new`, or be null for the defaults.
+//
+// # Safety
+// The same as `rubyfmt_format_buffer`, and `options` must be null or not
+// have been freed.
+struct RubyfmtString *rubyfmt_format_buffer_with_options(const uint8_t *ptr,
+                                                         size_t len,
+                                                         const struct RubyfmtOptions *options,
+                                                         enum Rubyfmt_FormatError *err);
+
+// Options for formatting, starting with the defaults. Free them with
+// `rubyfmt_options_free`.
+struct RubyfmtOptions *rubyfmt_options_new(void);
+
+// Frees options from `rubyfmt_options_new`, which can't be used afterwards.
+// Does nothing if `options` is null.
+//
+// # Safety
+// `options` must be null or have come from `rubyfmt_options_new`, and not
+// have been freed already.
+void rubyfmt_options_free(struct RubyfmtOptions *options);
+
+// Sets the line length that longer lines are broken up at. Returns false,
+// leaving the options as they were, for 0 or if `options` is null.
+//
+// # Safety
+// `options` must be null or have come from `rubyfmt_options_new`, and not
+// have been freed. The same goes for the other `rubyfmt_options_` setters.
+bool rubyfmt_options_set_line_length(struct RubyfmtOptions *options, size_t line_length);
+
+// Sets the number of spaces for each level of indentation. Returns false,
+// leaving the options as they were, for 0 or if `options` is null.
+//
+// # Safety
+// The same as `rubyfmt_options_set_line_length`.
+bool rubyfmt_options_set_indent_width(struct RubyfmtOptions *options, size_t indent_width);
+
+// Sets whether the output is checked to parse the same as the input.
+// Returns false if `options` is null.
+//
+// # Safety
+// The same as `rubyfmt_options_set_line_length`.
+bool rubyfmt_options_set_verify(struct RubyfmtOptions *options, bool verify);
+
+// Sets how strings are quoted to one of the `RubyfmtQuoteStyle` values.
+// Returns false, leaving the options as they were, for any other value or
+// if `options` is null.
+//
+// # Safety
+// The same as `rubyfmt_options_set_line_length`.
+bool rubyfmt_options_set_quote_style(struct RubyfmtOptions *options, uint32_t quote_style);
+
+// Sets how hash keys are written to one of the `RubyfmtHashStyle` values.
+// Returns false, leaving the options as they were, for any other value or
+// if `options` is null.
+//
+// # Safety
+// The same as `rubyfmt_options_set_line_length`.
+bool rubyfmt_options_set_hash_style(struct RubyfmtOptions *options, uint32_t hash_style);
+
+// Sets when method calls have parentheses to one of the `RubyfmtParenStyle`
+// values. Returns false, leaving the options as they were, for any other
+// value or if `options` is null.
+//
+// # Safety
+// The same as `rubyfmt_options_set_line_length`.
+bool rubyfmt_options_set_paren_style(struct RubyfmtOptions *options, uint32_t paren_style);
+
+// Only formats the statements touching lines `start` to `end`, which count
+// from 1 and are inclusive, as well as any other ranges already added.
+// Returns false, without adding it, if the range is empty or starts at 0,
+// or if `options` is null.
+//
+// # Safety
+// The same as `rubyfmt_options_set_line_length`.
+bool rubyfmt_options_add_line_range(struct RubyfmtOptions *options, size_t start, size_t end);
+
+// A description of the last error from a C API call on this thread, as a
+// null terminated string, or null if the last call succeeded. It's valid
+// until the next call on this thread. Reading the last error, with this,
+// `rubyfmt_last_error_line` or `rubyfmt_last_error_column`, doesn't clear
+// it.
+const char *rubyfmt_last_error_message(void);
+
+// The line of the input that the last error on this thread was on, counting
+// from 1, or 0 if it wasn't at a particular line
+size_t rubyfmt_last_error_line(void);
+
+// The column, in characters, that the last error on this thread was at,
+// counting from 1, or 0 if it wasn't at a particular column
+size_t rubyfmt_last_error_column(void);
+
+// Rubyfmt's version, as a null terminated string
+const char *rubyfmt_version(void);
+
+// The version of the C API this library has, which callers can compare
+// with the `RUBYFMT_API_VERSION` they were built against
+uint32_t rubyfmt_api_version(void);
+
+// Starts rubyfmt's VM thread if it isn't running yet, returning a handle for
+// formatting on it from any thread, or null if the VM couldn't be set up.
+// This replaces `rubyfmt_init`, which must not be called as well.
+struct RubyfmtFormatter *rubyfmt_formatter_new(void);
 
-enum Rubyfmt_FormatError {
-    RUBYFMT_FORMAT_ERROR_OK = 0,
+// Frees a formatter from `rubyfmt_formatter_new`, which can't be used
+// afterwards. Requests it submitted can still be waited on.
+//
+// # Safety
+// `formatter` must have come from `rubyfmt_formatter_new`, and not have
+// been freed already.
+void rubyfmt_formatter_free(struct RubyfmtFormatter *formatter);
 
-    // passed buffer contained a ruby syntax error. Non fatal, user should feel
-    // free to continue to call rubyfmt with non-error strings.
-    RUBYFMT_FORMAT_ERROR_SYNTAX_ERROR = 1,
+// The same as `rubyfmt_format_buffer_with_options`, but formats on the
+// formatter's VM thread, so it can be called from any thread. Formatting is
+// cancelled if it takes longer than `timeout_ms` milliseconds, unless
+// that's 0.
+//
+// # Safety
+// The same as `rubyfmt_format_buffer_with_options`.
+struct RubyfmtString *rubyfmt_formatter_format(const struct RubyfmtFormatter *formatter,
+                                               const uint8_t *ptr,
+                                               size_t len,
+                                               const struct RubyfmtOptions *options,
+                                               uint64_t timeout_ms,
+                                               enum Rubyfmt_FormatError *err);
 
-    // this error is fatal, the calling program should not continue to execute
-    // rubyfmt and you should report a bug with the file that crashed rubyfmt
-    RUBYFMT_FORMAT_ERROR_RIPPER_PARSE_FAILURE = 2,
+// The same as `rubyfmt_formatter_format`, but only queues the buffer to be
+// formatted, returning a request that can be waited on or cancelled from
+// any thread until it's freed with `rubyfmt_request_free`. The options are
+// copied, so they can be freed straight away. Returns null if the buffer
+// isn't valid UTF-8.
+//
+// # Safety
+// `ptr` must point to at least `len` readable bytes, and `options` must be
+// null or not have been freed.
+struct RubyfmtRequest *rubyfmt_formatter_submit(const struct RubyfmtFormatter *formatter,
+                                                const uint8_t *ptr,
+                                                size_t len,
+                                                const struct RubyfmtOptions *options);
 
-    // an error occured during IO within the function, should be impossible
-    // and most likely indicates a programming error within rubyfmt, please
-    // file a bug
-    RUBYFMT_FORMAT_ERROR_IO_ERROR = 3,
+// Stops a request, if it hasn't finished already. Waiting on it then fails
+// with `RUBYFMT_FORMAT_ERROR_CANCELLED`.
+void rubyfmt_request_cancel(const struct RubyfmtRequest *request);
 
-    // some unknown ruby error occured during execution fo Rubyfmt. This indicates
-    // a programming error. Please file a bug report and terminate the process
-    // and restart.
-    RUBYFMT_OTHER_RUBY_ERROR = 4,
-};
+// Waits for a request to finish, for at most `timeout_ms` milliseconds
+// unless that's 0, and returns its output in the same way as
+// `rubyfmt_format_buffer`. A request can only be waited on once.
+//
+// # Safety
+// `err` must not be null.
+struct RubyfmtString *rubyfmt_request_wait(const struct RubyfmtRequest *request,
+                                           uint64_t timeout_ms,
+                                           enum Rubyfmt_FormatError *err);
 
-typedef struct _RubyfmtString RubyfmtString;
+// Frees a request from `rubyfmt_formatter_submit`, which can't be used
+// afterwards, cancelling it if it hasn't finished
+//
+// # Safety
+// `request` must have come from `rubyfmt_formatter_submit`, and not have
+// been freed already.
+void rubyfmt_request_free(struct RubyfmtRequest *request);
 
-// setup rubyfmt, call once per process. Will return non zero (RUBYFMT_INIT_STATUS_ERROR)
-// if initialization failed
-int rubyfmt_init();
+// A pointer to the bytes of a `RubyfmtString`, which aren't null
+// terminated, so should be used with `rubyfmt_string_len`
+const uint8_t *rubyfmt_string_ptr(const struct RubyfmtString *s);
 
-// ask rubyfmt to format the passed buffer. Must be utf-8 encoded, and len
-// bytes long. Returns NULL and populates the err pointer with non zero if
-// an error occurs
-RubyfmtString *rubyfmt_format_buffer(unsigned char* buf, size_t len, enum Rubyfmt_FormatError* err);
+size_t rubyfmt_string_len(const struct RubyfmtString *s);
 
-// free a RubyfmtString after use
-void rubyfmt_string_free(RubyfmtString*);
+// Frees a string returned by rubyfmt, which can't be used afterwards
+//
+// # Safety
+// `rubyfmt_string` must have come from rubyfmt, and not have been freed
+// already.
+void rubyfmt_string_free(struct RubyfmtString *rubyfmt_string);
 
-// Get a byte pointer from a RubyfmtString, is not a null terminated string,
-// but instead should be used with rubyfmt_string_len to get the number
-// of bytes in the string
-unsigned char* rubyfmt_string_ptr(const RubyfmtString*);
-size_t rubyfmt_string_len(const RubyfmtString*);
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
 
-#endif
+#endif /* RUBYFMT_H */
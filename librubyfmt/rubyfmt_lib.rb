@@ -1,7 +1,9 @@
 module TrackAllScannerEvents
   Ripper::SCANNER_EVENTS.reject { |x| x == :sp || x == :nl || x == :ignored_nl }.each do |se|
     define_method(:"on_#{se}") do |*args|
-      @lines_with_any_ruby[lineno] = true
+      # Keep track of where the first token on each line is, so that we
+      # can tell whether a statement shares its lines with anything else
+      @lines_with_any_ruby[lineno] ||= column
       super(*args)
     end
   end
@@ -22,11 +24,117 @@ class Parser < Ripper::SexpBuilderPP
 
   include TrackAllScannerEvents
 
-  def initialize(file_data)
+  # A line-per-node dump of a parse tree, with locations stripped and the
+  # rewrites rubyfmt makes normalised. If a file and its formatted output
+  # don't have the same dump, formatting has changed what the program means.
+  def self.verification_dump(tree)
+    lines = []
+    dump_verification_node(normalize_for_verification(tree), 0, lines)
+    lines.map { |line| "#{line}\n" }.join
+  end
+
+  def self.normalize_for_verification(node)
+    return node unless node.is_a?(Array)
+
+    node = node
+      .reject { |child| location_node?(child) }
+      .map { |child| normalize_for_verification(child) }
+      .reject { |child| child == [:void_stmt] }
+
+    type, *children = node
+    case type
+    when :command
+      # `foo bar` is formatted as `foo(bar)`
+      name, args = children
+      [:method_add_arg, [:fcall, name], args]
+    when :command_call
+      receiver, op, name, *rest = children
+      [:method_add_arg, [:call, receiver, op, name], *rest]
+    when :arg_paren, :paren
+      # Parens are added and removed around e.g. arguments and conditions
+      children.length == 1 ? children[0] : node
+    when :args_add_block
+      children[1] == false ? children[0] : node
+    when :@label
+      # `a: 1` and `:a => 1` are the same hash
+      [:symbol, children[0].delete_suffix(":")]
+    when :symbol
+      token = children[0]
+      token.is_a?(Array) && token[0].to_s.start_with?("@") ? [:symbol, token[1]] : node
+    when :symbol_literal
+      children.length == 1 && children[0].is_a?(Array) && children[0][0] == :symbol ? children[0] : node
+    when :brace_block, :do_block
+      # Blocks switch between `{ ... }` and `do ... end` depending on their length
+      [:block, *children]
+    when :bodystmt
+      children[1..].all?(&:nil?) ? children[0] : node
+    when :string_literal, :dyna_symbol
+      # Strings are rewritten with different quotes and escapes, e.g. `'a\b'`
+      # becomes `"a\\b"`, so compare the strings they evaluate to
+      [type, *children.map { |child| evaluate_string_content(child) }]
+    when :_rubyfmt_verbatim
+      # Verbatim statements are copied through as-is
+      [type]
+    else
+      node
+    end
+  end
+
+  def self.evaluate_string_content(node)
+    return node unless node.is_a?(Array) && node[0] == :string_content
+
+    node.map do |part|
+      next part unless part.is_a?(Array) && part[0] == :@tstring_content
+
+      # By now every string's contents are escaped as if it was double quoted
+      value = begin
+        eval("\"#{part[1]}\"")
+      rescue SyntaxError
+        part[1]
+      end
+      [:@tstring_content, value]
+    end
+  end
+
+  # Locations are `[line, column]` or `[start_line, end_line]` pairs (where a
+  # line can be missing), or statement spans
+  def self.location_node?(node)
+    node.is_a?(Integer) ||
+      (node.is_a?(Array) && node.any? { |n| n.is_a?(Integer) } && node.all? { |n| n.nil? || n.is_a?(Integer) }) ||
+      (node.is_a?(Array) && node[0] == :_rubyfmt_span)
+  end
+
+  def self.dump_verification_node(node, depth, lines)
+    indent = "  " * depth
+    if node.is_a?(Array) && node[0].is_a?(Symbol)
+      if node[0].start_with?("@")
+        lines << "#{indent}#{node[0]} #{node[1..].map(&:inspect).join(" ")}"
+      else
+        lines << "#{indent}#{node[0]}"
+        node[1..].each { |child| dump_verification_node(child, depth + 1, lines) }
+      end
+    elsif node.is_a?(Array)
+      lines << "#{indent}["
+      node.each { |child| dump_verification_node(child, depth + 1, lines) }
+      lines << "#{indent}]"
+    else
+      lines << "#{indent}#{node.inspect}"
+    end
+  end
+
+  def initialize(file_data, line_ranges = [])
     super(file_data)
     @file_lines = file_data.lines
+    # [start_line, end_line] for each range of lines to format, or nothing
+    # to format the whole file
+    @line_ranges = line_ranges
 
     @lines_with_any_ruby = {}
+    # The `[:_rubyfmt_span, start_line, end_line, alone]` of each statement.
+    # These aren't part of the tree we hand to rubyfmt, apart from in the
+    # nodes for statements that are copied through as-is. rubyfmt looks up
+    # statements it can't deserialize in here, to copy them through instead
+    @statement_spans = {}.compare_by_identity
 
     # heredoc stack is the stack of identified heredocs
     @heredoc_stack = []
@@ -44,6 +152,7 @@ class Parser < Ripper::SexpBuilderPP
       "next" => [],
       "return" => [],
       "when" => [],
+      "in" => [],
       "case" => [],
       "yield" => [],
       "break" => [],
@@ -60,13 +169,23 @@ class Parser < Ripper::SexpBuilderPP
       "until" => [],
     }
     @op_locations = []
+    # [line, column, token] for every `in` keyword and `=>` operator, in the
+    # order they were scanned, which one-line pattern matches
+    # (`expr in pat`/`expr => pat`) look through for their operator
+    @pattern_operators = []
     @tlambda_stack = []
     @array_location_stacks = []
     @rbracket_stack = []
     @lbrace_stack = []
     @comments = {}
+    # [start_line, end_line, claimed] for each region of lines marked with
+    # `# rubyfmt:disable`/`# rubyfmt:enable` or `# rubyfmt:disable-next`,
+    # end_line is nil until we see the matching `enable`
+    @disabled_regions = []
     # binary contents comming after a `__END__` node
     @data_contents_start_line = nil
+    # [line, column, message] for the first syntax error Ripper reports
+    @syntax_error = nil
   end
 
   # This method has incorrect behavior inside Ripper,
@@ -106,10 +225,52 @@ class Parser < Ripper::SexpBuilderPP
         nil
       end
 
-      [res, @comments, @lines_with_any_ruby, @file_lines.count, data_contents]
+      select_line_ranges(res[1]) unless @line_ranges.empty?
+
+      disabled_regions = @disabled_regions.map do |start_line, end_line, _|
+        [start_line, end_line || @file_lines.length]
+      end
+
+      [res, @comments, @lines_with_any_ruby, @file_lines, data_contents, disabled_regions, @statement_spans]
+    end
+  end
+
+  # [line, column, message] for the first syntax error in the file, once
+  # `parse` has returned nil
+  def syntax_error
+    @syntax_error || [[@file_lines.length, 1].max, 1, "syntax error"]
+  end
+
+  # Ripper reports syntax errors through these while it parses, positioned
+  # at the token it had reached. Only the first error is kept, since the
+  # parser recovering from it often causes more.
+  def on_parse_error(message)
+    record_syntax_error(message)
+    super
+  end
+
+  def compile_error(message)
+    record_syntax_error(message)
+    super
+  end
+
+  %i[alias_error assign_error class_name_error param_error].each do |event|
+    define_method(:"on_#{event}") do |message, *args|
+      record_syntax_error(message)
+      super(message, *args)
     end
   end
 
+  def record_syntax_error(message)
+    return if @syntax_error
+
+    # Ripper counts columns in bytes from 0, but people count characters
+    # from 1
+    line = @file_lines[lineno - 1] || ""
+    characters = (line.byteslice(0, column) || line).length
+    @syntax_error = [lineno, characters + 1, message.to_s]
+  end
+
   DELIM_CLOSE_PAREN={ '{' => '}', '[' => ']', '(' => ')', '<' => '>' }
 
   def escape_percent_array_paren_content(part, pattern)
@@ -164,8 +325,9 @@ class Parser < Ripper::SexpBuilderPP
     end
   end
 
-  def on_op(*_args)
+  def on_op(*args)
     @op_locations << lineno
+    @pattern_operators << [lineno, column, args[0]] if args[0] == '=>'
     super + [[lineno, lineno]]
   end
 
@@ -276,6 +438,10 @@ class Parser < Ripper::SexpBuilderPP
   end
 
   def on_begin(*args)
+    # Pinned expressions in patterns (`in ^(expr)`) are dispatched as a `begin`
+    # wrapping a bare expression, and don't have a `begin` keyword
+    return [:pinned_begin, args[0]] if args[0][0] != :bodystmt
+
     beg, statements = super
     [beg, start_end_for_keyword('begin'), statements]
   end
@@ -355,6 +521,7 @@ class Parser < Ripper::SexpBuilderPP
     if stack = @kw_stacks[kw]
       stack << lineno
     end
+    @pattern_operators << [lineno, column, kw] if kw == 'in'
     super
   end
 
@@ -375,9 +542,64 @@ class Parser < Ripper::SexpBuilderPP
   end
 
   def on_case(cond, body)
+    # One-line pattern matches are dispatched as a `case` with a single,
+    # bodiless `in`, but they don't have a `case` keyword
+    return [:rassign, cond, body[2], body[1]] if body[0] == :one_line_in
+
     [:case, cond, body, start_end_for_keyword('case')]
   end
 
+  def on_stmts_add(stmts, stmt)
+    return super if stmt[0] == :void_stmt
+
+    start_line, end_line, alone_at_start, alone_at_end = statement_bounds(stmt)
+    span = [:_rubyfmt_span, start_line, end_line, alone_at_start && alone_at_end]
+    @statement_spans[stmt] = span
+    return super(stmts, stmt) unless formatting_disabled_for?(start_line, end_line)
+
+    # Neighbouring disabled statements are merged, so that everything
+    # between them gets copied through as well
+    previous = stmts.last
+    if previous && previous[0] == :_rubyfmt_verbatim
+      previous_start_line = previous[1][1]
+      previous_alone_at_start = previous[2]
+      previous[1] = [:_rubyfmt_span, previous_start_line, end_line, previous_alone_at_start && alone_at_end]
+      stmts
+    else
+      super(stmts, [:_rubyfmt_verbatim, span, alone_at_start, []])
+    end
+  end
+
+  def on_in(pattern, body, tail)
+    if body.nil?
+      # This is a one-line pattern match, `expr in pattern` or `expr => pattern`
+      operator = one_line_pattern_operator(pattern)
+      @kw_stacks['in'].pop if operator == 'in'
+      return [:one_line_in, pattern, operator]
+    end
+
+    [:in, pattern, body, tail, start_end_for_keyword('in')]
+  end
+
+  def on_for(*args)
+    # `for` loops also use the `in` keyword, but we don't use
+    # its line here, so just remove it
+    @kw_stacks['in'].pop
+    super
+  end
+
+  def on_aryptn(*args)
+    super + [pattern_open_delim]
+  end
+
+  def on_fndptn(*args)
+    super + [pattern_open_delim]
+  end
+
+  def on_hshptn(*args)
+    super + [pattern_open_delim]
+  end
+
   def on_yield(arg)
     [:yield, arg, start_end_for_keyword('yield')]
   end
@@ -497,8 +719,21 @@ class Parser < Ripper::SexpBuilderPP
     super(*args)
   end
 
+  FORMAT_DIRECTIVE = /\A#\s*rubyfmt:\s*(disable-next|disable|enable)\s*\z/.freeze
+
   def on_comment(comment)
     @comments[lineno] = comment
+
+    case comment[FORMAT_DIRECTIVE, 1]
+    when "disable"
+      @disabled_regions << [lineno + 1, nil, false] unless open_disabled_region
+    when "enable"
+      region = open_disabled_region
+      region[1] = lineno - 1 if region
+    when "disable-next"
+      next_line = next_line_with_code(lineno)
+      @disabled_regions << [next_line, next_line, false] if next_line && !open_disabled_region
+    end
   end
 
   def on___end__(val)
@@ -510,6 +745,268 @@ class Parser < Ripper::SexpBuilderPP
     [@kw_stacks[keyword].pop, lineno]
   end
 
+  # The operator of a one-line pattern match is the last `in` or `=>` before
+  # its pattern. Everything in the matched expression comes before it, and
+  # everything in the pattern (bindings, or hash rockets in pinned
+  # expressions) after it. Patterns without any tokens of their own, like
+  # `{}`, are the last thing scanned, so their operator is the last one.
+  private def one_line_pattern_operator(pattern)
+    pattern_start = first_token_position(pattern)
+    operator = @pattern_operators.reverse_each.find do |line, column, _|
+      pattern_start.nil? || ([line, column] <=> pattern_start) < 0
+    end
+    operator[2]
+  end
+
+  # The [line, column] of the first scanner token in a node, if it has any
+  private def first_token_position(node)
+    return nil unless node.is_a?(Array)
+    return node[2] if node[0].is_a?(Symbol) && node[0].start_with?('@') && node[2].is_a?(Array)
+
+    node.filter_map { |child| first_token_position(child) }.min
+  end
+
+  PATTERN_CLOSE_DELIMS = { ']' => '[', ')' => '(', '}' => '{' }.freeze
+
+  # Delimited patterns (`[a, b]`, `Const(a:)`, `{a:}`) are reduced as soon as
+  # the closing delimiter is scanned, so the current token tells us which
+  # delimiters the pattern was written with, if any.
+  private def pattern_open_delim
+    current_line = @file_lines[lineno - 1]
+    delim = PATTERN_CLOSE_DELIMS[current_line && current_line.byteslice(column, 1)]
+    case delim
+    when '['
+      # Pattern brackets aren't arrays or arefs, so drop their locations
+      @array_location_stacks.pop
+      @rbracket_stack.pop
+    when '{'
+      # Likewise, pattern braces aren't hashes or blocks
+      @lbrace_stack.pop
+    end
+    delim
+  end
+
+  STATEMENT_PREFIX = %r{
+    \A(?:
+      \s+
+      | (?:def|if|unless|while|until|case|class|module|begin|for|not|alias|undef|
+          return|yield|break|next|redo|retry|super|BEGIN|END)\b
+      | defined\?|->|::|%[qQwWiIrsx]?|[-+!~*&(\[{:"'`/]
+    )*\z
+  }x.freeze
+
+  # Statements are tagged with the lines they span so that any statement
+  # we don't know how to format can be copied through verbatim instead.
+  # This also records whether the statement is the only code on those lines,
+  # since otherwise copying the lines would duplicate the code around it.
+  private def statement_bounds(stmt)
+    bounds = [nil, nil, nil]
+    collect_statement_bounds(stmt, bounds)
+    min_line, max_line, first_token = bounds
+
+    # We're reducing the statement on the token that follows it,
+    # so the current line is the last line of the statement
+    start_line = min_line || lineno
+    end_line = [max_line || lineno, lineno].max
+
+    start_line_contents = @file_lines[start_line - 1] || ""
+    first_column = @lines_with_any_ruby[start_line] || 0
+    prefix = if first_token && first_token[0] == start_line
+      start_line_contents.byteslice(first_column...first_token[1])
+    else
+      start_line_contents.byteslice(first_column..).sub(/#.*/m, "")
+    end
+    alone_at_start = STATEMENT_PREFIX.match?(prefix || "")
+
+    rest = @file_lines[lineno - 1]&.byteslice(column..) if lineno == end_line
+    alone_at_end = rest.nil? || /\A[\s;]*(?:#.*)?\z/m.match?(rest)
+
+    [start_line, end_line, alone_at_start, alone_at_end]
+  end
+
+  private def open_disabled_region
+    region = @disabled_regions.last
+    region if region && region[1].nil?
+  end
+
+  # The first line after the given one that isn't blank or a comment
+  private def next_line_with_code(line)
+    index = (line...@file_lines.length).find do |i|
+      !/\A\s*(?:#.*)?\z/m.match?(@file_lines[i])
+    end
+    index && index + 1
+  end
+
+  # A statement is left alone if it starts or ends in a disabled region, or if
+  # it contains a whole region that none of its nested statements touched,
+  # e.g. a region inside a method call's arguments.
+  private def formatting_disabled_for?(start_line, end_line)
+    disabled = false
+    @disabled_regions.each do |region|
+      region_start, region_end, claimed = region
+      region_end ||= Float::INFINITY
+      next if region_end < region_start
+
+      lines = region_start..region_end
+      touches = lines.cover?(start_line) || lines.cover?(end_line)
+      contains = !claimed && start_line < region_start && region_end < end_line
+      next unless touches || contains
+
+      region[2] = true
+      disabled = true
+    end
+    disabled
+  end
+
+  private def collect_statement_bounds(node, bounds)
+    return unless node.is_a?(Array)
+
+    first = node[0]
+    if node.length == 2 && first.is_a?(Integer) && node[1].is_a?(Integer)
+      # Either a `[line, column]` or a `[start_line, end_line]` pair,
+      # but in both cases the first element is a line number
+      update_statement_lines(bounds, first, first)
+      return
+    end
+
+    span = first == :_rubyfmt_span ? node : @statement_spans[node]
+    if span
+      # Nested statements have already been measured
+      update_statement_lines(bounds, span[1], span[2])
+      return
+    end
+
+    case first
+    when :heredoc_string_literal
+      # Heredocs end on the line of their closing identifier
+      update_statement_lines(bounds, node[2][0], node[2][1])
+    when Symbol
+      position = node[2]
+      if first.start_with?("@") && position.is_a?(Array) && (bounds[2].nil? || (position <=> bounds[2]) < 0)
+        bounds[2] = position
+      end
+    end
+
+    node.each { |child| collect_statement_bounds(child, bounds) }
+  end
+
+  private def update_statement_lines(bounds, start_line, end_line)
+    bounds[0] = start_line if bounds[0].nil? || start_line < bounds[0]
+    bounds[1] = end_line if bounds[1].nil? || end_line > bounds[1]
+  end
+
+  # When only some lines are being formatted, statements that don't touch
+  # those lines are copied through verbatim. So is a statement whose selected
+  # lines are all inside its nested statements, apart from those nested
+  # statements, so that only the smallest statements around the selected
+  # lines get formatted. `depth` is how many statements these statements
+  # are nested in.
+  private def select_line_ranges(stmts, depth = 0)
+    stmts.each_with_index do |stmt, index|
+      span = statement_span(stmt)
+      next if span.nil? || stmt[0] == :_rubyfmt_verbatim
+
+      selected = selected_lines_within(span[1], span[2])
+      if selected.empty?
+        stmts[index] = [:_rubyfmt_verbatim, span, span[3], []]
+        next
+      end
+
+      # Copying lines around a nested statement only works if it's the
+      # only code on its lines
+      lists = nested_statement_lists(stmt)
+      nested_spans = lists.flatten(1).map { |nested| statement_span(nested) }.compact
+      next unless nested_spans.all? { |nested_span| nested_span[3] }
+      next unless selected.all? { |lines| lines_covered?(lines, nested_spans) }
+
+      lists.each { |list| select_line_ranges(list, depth + 1) }
+      holes = statement_holes(lists, depth + 1).sort_by { |_, hole_span, _| hole_span[1] }
+      stmts[index] = [:_rubyfmt_verbatim, span, span[3], holes]
+    end
+
+    extend_verbatim_spans(stmts)
+  end
+
+  private def statement_span(stmt)
+    return nil unless stmt.is_a?(Array)
+    return stmt[1] if stmt[0] == :_rubyfmt_verbatim
+
+    @statement_spans[stmt]
+  end
+
+  private def selected_lines_within(start_line, end_line)
+    @line_ranges.map do |range_start, range_end|
+      first_line = [range_start, start_line].max
+      last_line = [range_end, end_line].min
+      [first_line, last_line] if first_line <= last_line
+    end.compact
+  end
+
+  private def lines_covered?(lines, spans)
+    next_line, last_line = lines
+    spans.sort_by { |span| span[1] }.each do |span|
+      break if span[1] > next_line
+
+      next_line = [next_line, span[2] + 1].max
+    end
+    next_line > last_line
+  end
+
+  # The lists of statements nested in a statement, e.g. a method's body,
+  # but not any lists nested inside those lists' statements
+  private def nested_statement_lists(node, lists = [])
+    node.each do |child|
+      next unless child.is_a?(Array) && child[0] != :_rubyfmt_span
+
+      if statement_list?(child)
+        lists << child
+      else
+        nested_statement_lists(child, lists)
+      end
+    end
+    lists
+  end
+
+  private def statement_list?(node)
+    node.any? { |child| statement_span(child) } &&
+      node.all? { |child| child == [:void_stmt] || statement_span(child) }
+  end
+
+  # The statements to format inside a statement that's otherwise copied
+  # through verbatim, along with how deeply each one is nested, which is
+  # how far rubyfmt would indent it when formatting the whole file
+  private def statement_holes(lists, depth)
+    lists.flat_map do |list|
+      list.flat_map do |stmt|
+        if stmt[0] == :_rubyfmt_verbatim
+          stmt[3]
+        elsif (span = statement_span(stmt))
+          [[depth, span, stmt]]
+        else
+          []
+        end
+      end
+    end
+  end
+
+  # Any blank lines and comments before a verbatim statement are copied
+  # through with it, as are those after it if it's followed by a statement
+  # that's being formatted, so that they're left exactly as they were
+  private def extend_verbatim_spans(stmts)
+    spans = stmts.map { |stmt| statement_span(stmt) }
+    stmts.each_with_index do |stmt, index|
+      next unless stmt[0] == :_rubyfmt_verbatim
+
+      span = stmt[1]
+      previous_span = spans[0...index].compact.last
+      next_index = (index + 1...stmts.length).find { |i| spans[i] }
+      span[1] = [span[1], previous_span[2] + 1].min if previous_span
+      if next_index && stmts[next_index][0] != :_rubyfmt_verbatim
+        span[2] = [span[2], spans[next_index][1] - 1].max
+      end
+    end
+  end
+
   private def with_lineno(&blk)
     start_line = lineno
     res = yield
@@ -1,10 +1,36 @@
-use crate::ruby::{self, VALUE};
+use crate::ruby::{self, rb_intern, VALUE};
 use crate::ruby_ops::RipperTree;
 use serde::de::{self, Error as _};
+use std::cell::Cell;
 use std::mem::size_of;
 
-pub fn from_value<T: de::DeserializeOwned>(v: RipperTree) -> Result<T> {
-    T::deserialize(Deserializer(v.into_value()))
+thread_local! {
+    // The statement spans of the tree that's being deserialized
+    static STATEMENT_SPANS: Cell<VALUE> = Cell::new(ruby::Qnil);
+}
+
+pub fn from_value<T: de::DeserializeOwned>(v: &RipperTree) -> Result<T> {
+    let previous_spans = STATEMENT_SPANS.with(|spans| spans.replace(v.statement_spans()));
+    let result = T::deserialize(Deserializer(v.value()));
+    STATEMENT_SPANS.with(|spans| spans.set(previous_spans));
+    result
+}
+
+/// The `[:_rubyfmt_span, start_line, end_line, alone]` of `node`, if it's a
+/// statement in the tree that's being deserialized
+pub(crate) fn statement_span(node: VALUE) -> Option<VALUE> {
+    let spans = STATEMENT_SPANS.with(Cell::get);
+    if spans == ruby::Qnil {
+        return None;
+    }
+
+    // The hash compares by identity, so this can't call back into any Ruby code
+    let span = unsafe { ruby::rb_funcall(spans, intern!("[]"), 1, node) };
+    if span == ruby::Qnil {
+        None
+    } else {
+        Some(span)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -61,6 +61,13 @@ impl BreakableDelims {
         }
     }
 
+    pub fn for_pattern_alternatives() -> Self {
+        BreakableDelims {
+            single_line: DelimiterPair::new("".to_string(), "".to_string()),
+            multi_line: DelimiterPair::new("(".to_string(), ")".to_string()),
+        }
+    }
+
     pub fn for_hash() -> Self {
         BreakableDelims {
             single_line: DelimiterPair::new("{".to_string(), "}".to_string()),
@@ -14,19 +14,65 @@ pub struct FileComments {
     other_comments: BTreeMap<LineNumber, String>,
     lines_with_ruby: BTreeSet<LineNumber>,
     last_lineno: LineNumber,
+    source_lines: Vec<String>,
+    disabled_regions: Vec<(LineNumber, LineNumber)>,
+    // The last line of the longest statement starting on each line,
+    // for statements that are the only code on their lines
+    lone_statement_ends: BTreeMap<LineNumber, LineNumber>,
 }
 
 impl FileComments {
-    pub fn from_ruby_hash(h: VALUE, rl: VALUE, last_lineno: VALUE) -> Self {
+    pub fn from_ruby_hash(
+        h: VALUE,
+        rl: VALUE,
+        file_lines: VALUE,
+        disabled_regions: VALUE,
+        statement_spans: VALUE,
+    ) -> Self {
         let mut fc = FileComments::default();
         let keys;
         let values;
         let lines;
+        let spans;
         unsafe {
-            keys = ruby_array_to_slice(rb_funcall(h, intern!("keys"), 0));
-            values = ruby_array_to_slice(rb_funcall(h, intern!("values"), 0));
-            lines = ruby_array_to_slice(rb_funcall(rl, intern!("keys"), 0));
-            fc.last_lineno = rubyfmt_rb_num2ll(last_lineno) as LineNumber;
+            // Every array is fetched before any are read, since the GC can
+            // free an array that's only referenced by a slice of its contents
+            let keys_array = rb_funcall(h, intern!("keys"), 0);
+            let values_array = rb_funcall(h, intern!("values"), 0);
+            let lines_array = rb_funcall(rl, intern!("keys"), 0);
+            let spans_array = rb_funcall(statement_spans, intern!("values"), 0);
+            keys = ruby_array_to_slice(keys_array);
+            values = ruby_array_to_slice(values_array);
+            lines = ruby_array_to_slice(lines_array);
+            spans = ruby_array_to_slice(spans_array);
+            fc.source_lines = ruby_array_to_slice(file_lines)
+                .iter()
+                .map(|line| ruby_string_to_str(*line).to_owned())
+                .collect();
+            fc.last_lineno = fc.source_lines.len() as LineNumber;
+            for region in ruby_array_to_slice(disabled_regions) {
+                if let [start_line, end_line] = ruby_array_to_slice(*region) {
+                    fc.disabled_regions.push((
+                        rubyfmt_rb_num2ll(*start_line) as LineNumber,
+                        rubyfmt_rb_num2ll(*end_line) as LineNumber,
+                    ));
+                } else {
+                    raise("expected disabled regions to be [start_line, end_line]");
+                }
+            }
+            for span in spans {
+                if let [_, start_line, end_line, alone] = ruby_array_to_slice(*span) {
+                    if !matches!(rubyfmt_rb_type(*alone), ruby_value_type::RUBY_T_TRUE) {
+                        continue;
+                    }
+                    let start_line = rubyfmt_rb_num2ll(*start_line) as LineNumber;
+                    let end_line = rubyfmt_rb_num2ll(*end_line) as LineNumber;
+                    let lone_end = fc.lone_statement_ends.entry(start_line).or_default();
+                    *lone_end = (*lone_end).max(end_line);
+                } else {
+                    raise("expected statement spans to be [:_rubyfmt_span, start_line, end_line, alone]");
+                }
+            }
         }
         if keys.len() != values.len() {
             raise("expected keys and values to have same length, indicates error");
@@ -55,6 +101,46 @@ impl FileComments {
         line_number < self.last_lineno
     }
 
+    /// The original source of the given (inclusive) range of lines
+    pub fn source_for_lines(&self, start_line: LineNumber, end_line: LineNumber) -> String {
+        let start = (start_line as usize).saturating_sub(1);
+        let end = std::cmp::min(end_line as usize, self.source_lines.len());
+        self.source_lines
+            .get(start..end)
+            .map(|lines| lines.concat())
+            .unwrap_or_default()
+    }
+
+    /// Drops any comments in the given (inclusive) range of lines, e.g.
+    /// because those lines are being copied through verbatim
+    pub fn remove_comments_in_lines(&mut self, start_line: LineNumber, end_line: LineNumber) {
+        self.other_comments
+            .retain(|line_number, _| *line_number < start_line || *line_number > end_line);
+    }
+
+    /// The (inclusive) lines of the region marked with `# rubyfmt:disable` or
+    /// `# rubyfmt:disable-next` that contains the given line, if there is one
+    pub fn disabled_region_containing(
+        &self,
+        line_number: LineNumber,
+    ) -> Option<(LineNumber, LineNumber)> {
+        self.disabled_regions
+            .iter()
+            .find(|(start_line, end_line)| (*start_line..=*end_line).contains(&line_number))
+            .copied()
+    }
+
+    /// The (inclusive) lines of the longest statement starting on the given
+    /// line that's the only code on its lines, if there is one
+    pub fn lone_statement_starting_on(
+        &self,
+        line_number: LineNumber,
+    ) -> Option<(LineNumber, LineNumber)> {
+        self.lone_statement_ends
+            .get(&line_number)
+            .map(|end_line| (line_number, *end_line))
+    }
+
     pub fn has_line(&self, line_number: LineNumber) -> bool {
         self.other_comments.contains_key(&line_number)
     }
@@ -1,10 +1,10 @@
-use std::collections::HashSet;
-
 use crate::delimiters::BreakableDelims;
+use crate::format_options::{HashStyle, ParenStyle, QuoteStyle};
+use crate::formatter::stop_if_cancelled;
 use crate::heredoc_string::HeredocKind;
 use crate::parser_state::{BaseParserState, ConcreteParserState, FormattingContext, RenderFunc};
 use crate::ripper_tree_types::*;
-use crate::types::LineNumber;
+use crate::types::{ColNumber, LineNumber};
 use log::debug;
 
 pub fn format_def(ps: &mut dyn ConcreteParserState, def: Def) {
@@ -695,21 +695,6 @@ pub fn args_has_single_def_expression(args: &ArgsAddStarOrExpressionListOrArgsFo
     false
 }
 
-lazy_static! {
-    static ref RSPEC_METHODS: HashSet<&'static str> = vec!["it", "describe", "test"].into_iter().collect();
-    static ref GEMFILE_METHODS: HashSet<&'static str> = vec![
-        // Gemfile
-        "gem",
-        "source",
-        "ruby",
-        "group",
-    ].into_iter().collect();
-    static ref OPTIONALLY_PARENTHESIZED_METHODS: HashSet<&'static str> =
-        vec!["super", "require", "require_relative",]
-            .into_iter()
-            .collect::<HashSet<_>>();
-}
-
 pub fn use_parens_for_method_call(
     ps: &dyn ConcreteParserState,
     chain: &[CallChainElement],
@@ -732,7 +717,16 @@ pub fn use_parens_for_method_call(
     if matches!(method, IdentOrOpOrKeywordOrConst::Const(..)) {
         return true;
     }
-    if name.starts_with("attr_") && context == FormattingContext::ClassOrModule {
+    if ps.options().paren_style == ParenStyle::Preserve {
+        return original_used_parens || ps.current_formatting_context_requires_parens();
+    }
+    let options = ps.options();
+    if context == FormattingContext::ClassOrModule
+        && options
+            .class_macro_prefixes
+            .iter()
+            .any(|prefix| name.starts_with(prefix.as_str()))
+    {
         return original_used_parens;
     }
 
@@ -763,8 +757,8 @@ pub fn use_parens_for_method_call(
         }
     }
 
-    if OPTIONALLY_PARENTHESIZED_METHODS.contains(name.as_str())
-        || GEMFILE_METHODS.contains(name.as_str())
+    if options.optionally_parenthesized_methods.contains(&name)
+        || options.gemfile_methods.contains(&name)
     {
         return original_used_parens;
     }
@@ -1073,7 +1067,10 @@ pub fn format_symbol_literal(ps: &mut dyn ConcreteParserState, symbol_literal: S
     }
 }
 
-fn all_labelish(assocs: &[AssocNewOrAssocSplat]) -> bool {
+fn all_labelish(ps: &dyn ConcreteParserState, assocs: &[AssocNewOrAssocSplat]) -> bool {
+    if ps.options().hash_style == HashStyle::HashRockets {
+        return false;
+    }
     assocs.iter().all(|assoc| match assoc {
         AssocNewOrAssocSplat::AssocNew(new) => match new.1 {
             AssocKey::Label(_) => true,
@@ -1089,7 +1086,7 @@ pub fn format_assocs(
     sc: SpecialCase,
 ) {
     let len = assocs.len();
-    let all_labelish = all_labelish(&assocs);
+    let all_labelish = all_labelish(ps, &assocs);
     for (idx, assoc) in assocs.into_iter().enumerate() {
         ps.emit_soft_indent();
         format_assoc(ps, assoc, all_labelish);
@@ -1107,7 +1104,7 @@ pub fn format_assocs_single_line(
     assocs: Vec<AssocNewOrAssocSplat>,
 ) {
     let len = assocs.len();
-    let all_labelish = all_labelish(&assocs);
+    let all_labelish = all_labelish(ps, &assocs);
     for (idx, assoc) in assocs.into_iter().enumerate() {
         format_assoc(ps, assoc, all_labelish);
         if idx != len - 1 {
@@ -1126,8 +1123,10 @@ pub fn format_assoc(
         Box::new(|ps| match assoc {
             AssocNewOrAssocSplat::AssocNew(new) => {
                 match new.1 {
+                    // Labels without a value, like `{x:}`, can't be written
+                    // with a hash rocket
                     AssocKey::Label(label) => {
-                        if all_labelish {
+                        if all_labelish || new.2.is_none() {
                             handle_string_and_linecol(ps, label.1, label.2);
                         } else {
                             let colonless_label = label
@@ -1666,9 +1665,22 @@ pub fn format_string_literal(ps: &mut dyn ConcreteParserState, sl: StringLiteral
 
             ps.on_line(start_end.start_line());
 
-            ps.emit_double_quote();
-            format_inner_string(ps, parts, StringType::Quoted);
-            ps.emit_double_quote();
+            // Ripper's string content is escaped for double quotes, so only
+            // strings without any escapes or interpolation can be single quoted
+            let single_quoted = ps.options().quote_style == QuoteStyle::Single
+                && parts.iter().all(|part| match part {
+                    StringContentPart::TStringContent(t) => !t.1.contains(['\\', '\'']),
+                    _ => false,
+                });
+            if single_quoted {
+                ps.emit_ident("'".to_string());
+                format_inner_string(ps, parts, StringType::Quoted);
+                ps.emit_ident("'".to_string());
+            } else {
+                ps.emit_double_quote();
+                format_inner_string(ps, parts, StringType::Quoted);
+                ps.emit_double_quote();
+            }
 
             if ps.at_start_of_line() {
                 ps.emit_newline();
@@ -2700,7 +2712,10 @@ pub fn format_backref(ps: &mut dyn ConcreteParserState, backref: Backref) {
 
 /// Matches call chains on common special-cased names, like
 /// `it`/`describe` for tests and `gem`/`source`/etc. for Gemfiles.
-fn can_elide_parens_for_reserved_names(cc: &[CallChainElement]) -> bool {
+fn can_elide_parens_for_reserved_names(
+    ps: &dyn ConcreteParserState,
+    cc: &[CallChainElement],
+) -> bool {
     if let Some(CallChainElement::Block(Block::BraceBlock(_))) = cc.last() {
         return false;
     };
@@ -2717,8 +2732,8 @@ fn can_elide_parens_for_reserved_names(cc: &[CallChainElement]) -> bool {
             Some(CallChainElement::IdentOrOpOrKeywordOrConst(
                 IdentOrOpOrKeywordOrConst::Ident(Ident(_, ident, _)),
             )) => {
-                let ident = ident.as_str();
-                RSPEC_METHODS.contains(ident) || GEMFILE_METHODS.contains(ident)
+                ps.options().rspec_methods.contains(ident)
+                    || ps.options().gemfile_methods.contains(ident)
             }
             _ => false,
         };
@@ -2772,7 +2787,7 @@ fn format_call_chain_elements(
     // require this (e.g. `MethodAddArg` doesn't enforce invariants like those).
     last_call_use_parens: Option<bool>,
 ) {
-    let elide_parens = can_elide_parens_for_reserved_names(&cc);
+    let elide_parens = can_elide_parens_for_reserved_names(ps, &cc);
     // When set, force all `CallChainElement::ArgsAddStarOrExpressionListOrArgsForward`
     // to use parens, even when empty. This handles cases like `super()` where parens matter
     let mut next_args_list_must_use_parens = false;
@@ -3289,24 +3304,330 @@ pub fn format_when_or_else(ps: &mut dyn ConcreteParserState, tail: WhenOrElse) {
                 format_when_or_else(ps, *tail);
             }
         }
-        WhenOrElse::Else(e) => {
+        WhenOrElse::Else(e) => format_case_else(ps, e),
+    }
+}
+
+pub fn format_case_else(ps: &mut dyn ConcreteParserState, e: CaseElse) {
+    ps.emit_indent();
+    ps.emit_else();
+
+    ps.new_block(Box::new(|ps| {
+        ps.with_start_of_line(
+            true,
+            Box::new(|ps| {
+                ps.on_line(e.2.start_line());
+                ps.emit_newline();
+                for expr in e.1 {
+                    format_expression(ps, expr);
+                }
+
+                ps.wind_dumping_comments_until_line(e.2.end_line());
+            }),
+        );
+    }));
+}
+
+pub fn format_in_or_else(ps: &mut dyn ConcreteParserState, tail: InOrElse) {
+    match tail {
+        InOrElse::In(in_clause) => {
+            let pattern = in_clause.1;
+            let body = in_clause.2;
+            let tail = in_clause.3;
+            let start_end = in_clause.4;
+            ps.on_line(start_end.0);
             ps.emit_indent();
-            ps.emit_else();
+            ps.emit_keyword("in".to_string());
+
+            ps.with_start_of_line(
+                false,
+                Box::new(|ps| {
+                    format_in_pattern(ps, pattern);
+                }),
+            );
 
             ps.new_block(Box::new(|ps| {
                 ps.with_start_of_line(
                     true,
                     Box::new(|ps| {
-                        ps.on_line(e.2.start_line());
                         ps.emit_newline();
-                        for expr in e.1 {
+                        for expr in body {
                             format_expression(ps, expr);
                         }
-
-                        ps.wind_dumping_comments_until_line(e.2.end_line());
                     }),
                 );
             }));
+
+            if let Some(tail) = tail {
+                format_in_or_else(ps, *tail);
+            }
+        }
+        InOrElse::Else(e) => format_case_else(ps, e),
+    }
+}
+
+fn format_in_pattern(ps: &mut dyn ConcreteParserState, in_pattern: InPattern) {
+    let (pattern, guard) = match in_pattern {
+        InPattern::IfGuard(guard) => (guard.2, Some((guard.1, "if"))),
+        InPattern::UnlessGuard(guard) => (guard.2, Some((guard.1, "unless"))),
+        InPattern::Pattern(pattern) => (pattern, None),
+    };
+
+    format_top_level_pattern(ps, pattern);
+
+    if let Some((conditional, name)) = guard {
+        ps.emit_mod_keyword(format!(" {} ", name));
+        format_expression(ps, *conditional);
+    }
+}
+
+/// Formats a pattern that directly follows an `in` or `=>`, including the
+/// space between them
+fn format_top_level_pattern(ps: &mut dyn ConcreteParserState, pattern: Pattern) {
+    if pattern.is_undelimited_list() {
+        // Undelimited lists of patterns break the same way as `when` conditions
+        ps.new_block(Box::new(|ps| {
+            ps.inline_breakable_of(
+                BreakableDelims::for_when(),
+                Box::new(|ps| {
+                    ps.emit_collapsing_newline();
+                    format_pattern(ps, pattern);
+                }),
+            );
+        }));
+    } else {
+        ps.emit_space();
+        format_pattern(ps, pattern);
+    }
+}
+
+pub fn format_rassign(ps: &mut dyn ConcreteParserState, rassign: RAssign) {
+    if ps.at_start_of_line() {
+        ps.emit_indent();
+    }
+
+    ps.with_start_of_line(
+        false,
+        Box::new(|ps| {
+            format_expression(ps, *rassign.1);
+            ps.emit_space();
+            if rassign.2 == "in" {
+                ps.emit_keyword("in".to_string());
+            } else {
+                ps.emit_ident(rassign.2);
+            }
+            format_top_level_pattern(ps, rassign.3);
+        }),
+    );
+
+    if ps.at_start_of_line() {
+        ps.emit_newline();
+    }
+}
+
+pub fn format_pattern(ps: &mut dyn ConcreteParserState, pattern: Pattern) {
+    match pattern {
+        Pattern::AryPtn(aryptn) => format_aryptn(ps, aryptn),
+        Pattern::FndPtn(fndptn) => format_fndptn(ps, fndptn),
+        Pattern::HshPtn(hshptn) => format_hshptn(ps, hshptn),
+        Pattern::Binary(binary) if (binary.2).0 == "|" => {
+            format_pattern_alternatives(ps, binary);
+        }
+        Pattern::Binary(binary) => {
+            format_pattern_operand(ps, *binary.1, false);
+            ps.emit_space();
+            ps.emit_ident((binary.2).0);
+            ps.emit_space();
+            format_pattern_operand(ps, *binary.3, false);
+        }
+        Pattern::Binding(vf) => {
+            bind_var_field(ps, &vf);
+            format_var_field(ps, vf);
+        }
+        Pattern::Pinned(pinned) => {
+            ps.emit_ident("^".to_string());
+            match pinned.1 {
+                PinnedVariable::Ident(i) => handle_string_and_linecol(ps, i.1, i.2),
+                PinnedVariable::IVar(i) => handle_string_and_linecol(ps, i.1, i.2),
+                PinnedVariable::CVar(c) => handle_string_and_linecol(ps, c.1, c.2),
+                PinnedVariable::GVar(g) => handle_string_and_linecol(ps, g.1, g.2),
+            }
+        }
+        Pattern::PinnedExpression(pinned) => {
+            ps.emit_ident("^".to_string());
+            ps.emit_open_paren();
+            format_expression(ps, *pinned.1);
+            ps.emit_close_paren();
+        }
+        Pattern::Value(expr) => format_expression(ps, *expr),
+    }
+}
+
+/// Formats `a | b | c`, breaking it into one alternative per line (wrapped
+/// in parens) if it doesn't fit
+fn format_pattern_alternatives(ps: &mut dyn ConcreteParserState, binary: PatternBinary) {
+    let mut alternatives = vec![*binary.3];
+    let mut left = *binary.1;
+    // `|` is left associative, so `a | b | c` is `(a | b) | c`
+    while let Pattern::Binary(inner) = left {
+        if (inner.2).0 != "|" {
+            left = Pattern::Binary(inner);
+            break;
+        }
+        alternatives.push(*inner.3);
+        left = *inner.1;
+    }
+    alternatives.push(left);
+    alternatives.reverse();
+
+    ps.breakable_of(
+        BreakableDelims::for_pattern_alternatives(),
+        Box::new(|ps| {
+            let len = alternatives.len();
+            for (i, alternative) in alternatives.into_iter().enumerate() {
+                ps.emit_soft_indent();
+                // Bindings bind more loosely than alternatives, so
+                // `a | (b => c)` needs to keep its parens
+                format_pattern_operand(ps, alternative, true);
+                if i + 1 != len {
+                    ps.emit_space();
+                    ps.emit_ident("|".to_string());
+                    ps.emit_soft_newline();
+                }
+            }
+            ps.emit_collapsing_newline();
+        }),
+    );
+}
+
+fn format_pattern_operand(
+    ps: &mut dyn ConcreteParserState,
+    pattern: Pattern,
+    in_alternative: bool,
+) {
+    if in_alternative && pattern.is_binding() {
+        ps.emit_open_paren();
+        format_pattern(ps, pattern);
+        ps.emit_close_paren();
+    } else {
+        format_pattern(ps, pattern);
+    }
+}
+
+fn format_aryptn(ps: &mut dyn ConcreteParserState, aryptn: AryPtn) {
+    let mut elements: Vec<RenderFunc> = Vec::new();
+    for pattern in aryptn.2.unwrap_or_default() {
+        elements.push(Box::new(move |ps| format_pattern(ps, pattern)));
+    }
+    if let Some(rest) = aryptn.3 {
+        elements.push(Box::new(move |ps| format_pattern_rest(ps, "*", rest)));
+    }
+    for pattern in aryptn.4.unwrap_or_default() {
+        elements.push(Box::new(move |ps| format_pattern(ps, pattern)));
+    }
+
+    format_pattern_collection(ps, aryptn.1, aryptn.5, elements);
+}
+
+fn format_fndptn(ps: &mut dyn ConcreteParserState, fndptn: FndPtn) {
+    let pre_rest = fndptn.2;
+    let post_rest = fndptn.4;
+
+    let mut elements: Vec<RenderFunc> = Vec::new();
+    for pattern in fndptn.3 {
+        elements.push(Box::new(move |ps| format_pattern(ps, pattern)));
+    }
+    elements.insert(
+        0,
+        Box::new(move |ps| format_pattern_rest(ps, "*", pre_rest)),
+    );
+    elements.push(Box::new(move |ps| format_pattern_rest(ps, "*", post_rest)));
+
+    format_pattern_collection(ps, fndptn.1, fndptn.5, elements);
+}
+
+fn format_hshptn(ps: &mut dyn ConcreteParserState, hshptn: HshPtn) {
+    let mut elements: Vec<RenderFunc> = Vec::new();
+    for HshPtnPair(label, value) in hshptn.2.unwrap_or_default() {
+        elements.push(Box::new(move |ps| {
+            match value {
+                Some(value) => {
+                    handle_string_and_linecol(ps, label.1, label.2);
+                    ps.emit_space();
+                    format_pattern(ps, value);
+                }
+                None => {
+                    // `in {name:}` binds `name`
+                    ps.bind_variable(label.1.trim_end_matches(':').to_string());
+                    handle_string_and_linecol(ps, label.1, label.2);
+                }
+            }
+        }));
+    }
+    if let Some(rest) = hshptn.3 {
+        elements.push(Box::new(move |ps| format_pattern_rest(ps, "**", rest)));
+    }
+
+    format_pattern_collection(ps, hshptn.1, hshptn.4, elements);
+}
+
+fn format_pattern_rest(ps: &mut dyn ConcreteParserState, splat: &str, rest: PatternRest) {
+    ps.emit_ident(splat.to_string());
+    match rest.1 {
+        Some(PatternRestName::Ident(ident)) => {
+            bind_ident(ps, &ident);
+            format_ident(ps, ident);
+        }
+        Some(PatternRestName::Nil(_)) => ps.emit_ident("nil".to_string()),
+        None => {}
+    }
+}
+
+fn format_pattern_collection(
+    ps: &mut dyn ConcreteParserState,
+    constant: Option<Box<Expression>>,
+    open_delim: Option<String>,
+    elements: Vec<RenderFunc>,
+) {
+    let has_constant = constant.is_some();
+    if let Some(constant) = constant {
+        format_expression(ps, *constant);
+    }
+
+    let delims = match open_delim.as_deref() {
+        Some("[") => BreakableDelims::for_array(),
+        Some("{") => BreakableDelims::for_hash(),
+        Some(_) => BreakableDelims::for_method_call(),
+        None if has_constant => BreakableDelims::for_method_call(),
+        None => {
+            // Undelimited patterns are always rendered inside
+            // of the `in` clause's breakable
+            format_pattern_elements(ps, elements);
+            return;
+        }
+    };
+
+    if elements.is_empty() {
+        ps.emit_single_line_delims(delims);
+    } else {
+        ps.breakable_of(
+            delims,
+            Box::new(|ps| {
+                format_pattern_elements(ps, elements);
+                ps.emit_collapsing_newline();
+            }),
+        );
+    }
+}
+
+fn format_pattern_elements(ps: &mut dyn ConcreteParserState, elements: Vec<RenderFunc>) {
+    let len = elements.len();
+    for (idx, element) in elements.into_iter().enumerate() {
+        ps.emit_soft_indent();
+        element(ps);
+        if idx != len - 1 {
+            ps.emit_comma();
+            ps.emit_soft_newline();
         }
     }
 }
@@ -3337,7 +3658,10 @@ pub fn format_case(ps: &mut dyn ConcreteParserState, case: Case) {
     ps.with_start_of_line(
         true,
         Box::new(|ps| {
-            format_when_or_else(ps, WhenOrElse::When(tail));
+            match tail {
+                WhenOrIn::When(when) => format_when_or_else(ps, WhenOrElse::When(when)),
+                WhenOrIn::In(in_clause) => format_in_or_else(ps, InOrElse::In(in_clause)),
+            }
             ps.emit_end();
         }),
     );
@@ -3720,6 +4044,7 @@ pub fn format_bare_return_args(
 }
 
 pub fn format_expression(ps: &mut dyn ConcreteParserState, expression: Expression) {
+    stop_if_cancelled();
     let expression = normalize(expression);
     debug!("normalized expression: {:?}", expression);
     match expression {
@@ -3772,6 +4097,7 @@ pub fn format_expression(ps: &mut dyn ConcreteParserState, expression: Expressio
         Expression::IfMod(wm) => format_multilinable_mod(ps, wm.1, wm.2, "if".to_string()),
         Expression::UnlessMod(um) => format_multilinable_mod(ps, um.1, um.2, "unless".to_string()),
         Expression::Case(c) => format_case(ps, c),
+        Expression::RAssign(rassign) => format_rassign(ps, rassign),
         Expression::Retry(r) => format_retry(ps, r),
         Expression::Redo(r) => format_redo(ps, r),
         Expression::SClass(sc) => format_sclass(ps, sc),
@@ -3790,12 +4116,124 @@ pub fn format_expression(ps: &mut dyn ConcreteParserState, expression: Expressio
         Expression::Return(ret) => format_return(ps, ret),
         Expression::BeginBlock(begin) => format_begin_block(ps, begin),
         Expression::EndBlock(end) => format_end_block(ps, end),
-        e => {
-            panic!("got unknown token: {:?}", e);
+        Expression::Verbatim(verbatim) => format_verbatim(ps, verbatim),
+        Expression::Unsupported(unsupported) => format_unsupported(ps, unsupported),
+        Expression::VCall(..)
+        | Expression::MethodAddArg(..)
+        | Expression::Command(..)
+        | Expression::CommandCall(..)
+        | Expression::Call(..)
+        | Expression::Super(..) => {
+            unreachable!("method calls have been normalized into `MethodCall`s")
+        }
+        // These are only formatted as parts of other expressions
+        e @ Expression::Params(..) => format_unformattable(ps, "params", e),
+        e @ Expression::Symbol(..) => format_unformattable(ps, "symbol", e),
+        e @ Expression::Const(..) => format_unformattable(ps, "@const", e),
+        e @ Expression::Kw(..) => format_unformattable(ps, "@kw", e),
+    }
+}
+
+pub fn format_unsupported(ps: &mut dyn ConcreteParserState, unsupported: Unsupported) {
+    let Unsupported(node_type, span) = unsupported;
+    let lines = if span.3 { Some((span.1, span.2)) } else { None };
+    copy_unsupported_lines(ps, &node_type, span.1, lines);
+}
+
+fn format_unformattable(ps: &mut dyn ConcreteParserState, node_type: &str, e: Expression) {
+    let start_line = e.start_line().unwrap_or_else(|| ps.current_line_number());
+    let lines = ps.lone_statement_starting_on(start_line);
+    copy_unsupported_lines(ps, node_type, start_line, lines);
+}
+
+// Copies the lines of a statement rubyfmt can't format, if it's the only code on
+// them. Otherwise copying them would also copy whatever else is on them, so the
+// whole file is left unformatted instead.
+fn copy_unsupported_lines(
+    ps: &mut dyn ConcreteParserState,
+    node_type: &str,
+    start_line: LineNumber,
+    lines: Option<(LineNumber, LineNumber)>,
+) {
+    match lines {
+        Some((start_line, end_line)) if ps.at_start_of_line() => {
+            ps.push_warning(format!(
+                "unsupported `{}` on line {}, copying it through unformatted",
+                node_type, start_line,
+            ));
+            ps.emit_verbatim_lines(start_line, end_line);
+            ps.emit_newline();
+        }
+        _ => {
+            ps.push_warning(format!(
+                "unsupported `{}` on line {}, leaving the file unformatted",
+                node_type, start_line,
+            ));
+            ps.mark_unformattable();
         }
     }
 }
 
+pub fn format_verbatim(ps: &mut dyn ConcreteParserState, verbatim: Verbatim) {
+    let span = verbatim.1;
+    let holes = verbatim.3;
+    let (mut start_line, mut end_line, is_alone) = (span.1, span.2, span.3);
+
+    if !is_alone || !ps.at_start_of_line() {
+        // Copying these lines would also copy whatever else is on them
+        ps.push_warning(format!(
+            "formatting is disabled for code on line {} that shares its lines with other code, leaving the file unformatted",
+            start_line,
+        ));
+        ps.mark_unformattable();
+        return;
+    }
+
+    // Copy any blank lines and comments at the edges of the disabled region too,
+    // so that the whole region comes out exactly as it went in
+    if let Some((region_start, _)) = ps.disabled_region_containing(start_line) {
+        start_line = region_start
+            .max(ps.current_line_number() + 1)
+            .min(start_line);
+    }
+    if let Some((_, region_end)) = ps.disabled_region_containing(end_line) {
+        end_line = region_end;
+    }
+
+    // Statements inside this one that enclose lines being formatted are
+    // formatted, and the lines around them are copied
+    let mut next_line = start_line;
+    for VerbatimHole(depth, hole_span, expression) in holes {
+        if hole_span.1 > next_line {
+            ps.emit_verbatim_lines(next_line, hole_span.1 - 1);
+            ps.emit_newline();
+        }
+
+        format_at_depth(ps, depth, expression);
+        next_line = hole_span.2 + 1;
+    }
+
+    if next_line <= end_line {
+        ps.emit_verbatim_lines(next_line, end_line);
+        ps.emit_newline();
+    }
+}
+
+fn format_at_depth(ps: &mut dyn ConcreteParserState, depth: ColNumber, expression: Expression) {
+    if depth == 0 {
+        ps.with_start_of_line(
+            true,
+            Box::new(|ps| {
+                format_expression(ps, expression);
+            }),
+        );
+    } else {
+        ps.new_block(Box::new(move |ps| {
+            format_at_depth(ps, depth - 1, expression);
+        }));
+    }
+}
+
 pub fn format_program(ps: &mut BaseParserState, program: Program, end_data: Option<&str>) {
     ps.flush_start_of_file_comments();
     debug!("{:?}", program);
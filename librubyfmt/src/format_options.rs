@@ -0,0 +1,159 @@
+use serde::{de, Deserialize, Deserializer};
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+pub const DEFAULT_LINE_LENGTH: usize = 120;
+pub const DEFAULT_INDENT_WIDTH: usize = 2;
+
+/// Options for formatting, each of which starts with its default. The CLI
+/// reads them from the nearest `.rubyfmt.toml`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FormatOptions {
+    /// Lines longer than this will be broken up where possible
+    #[serde(deserialize_with = "deserialize_nonzero")]
+    pub line_length: usize,
+    /// The number of spaces for each level of indentation
+    #[serde(deserialize_with = "deserialize_nonzero")]
+    pub indent_width: usize,
+    /// Check that the formatted output parses to the same program as the
+    /// input, failing with `RichFormatError::VerificationFailed` if not
+    pub verify: bool,
+    pub quote_style: QuoteStyle,
+    pub hash_style: HashStyle,
+    pub paren_style: ParenStyle,
+    /// Test DSL methods like `it`, whose arguments are written without
+    /// parentheses when they're called on their own
+    pub rspec_methods: Vec<String>,
+    /// Gemfile DSL methods like `gem`, whose arguments are written without
+    /// parentheses when they're called on their own, and otherwise keep
+    /// whatever parentheses they were written with
+    pub gemfile_methods: Vec<String>,
+    /// Methods like `require` that keep whatever parentheses they were
+    /// written with
+    pub optionally_parenthesized_methods: Vec<String>,
+    /// Methods starting with one of these, like `attr_reader`, keep whatever
+    /// parentheses they were written with when they're called in the body of
+    /// a class or module
+    pub class_macro_prefixes: Vec<String>,
+    /// Only format the statements touching these (1-indexed, inclusive)
+    /// ranges of lines, or the smallest statements enclosing them, leaving
+    /// every other line exactly as it was. Empty formats everything. This
+    /// depends on the file, so it's never read from `.rubyfmt.toml`.
+    #[serde(skip)]
+    pub lines: Vec<RangeInclusive<usize>>,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            line_length: DEFAULT_LINE_LENGTH,
+            indent_width: DEFAULT_INDENT_WIDTH,
+            verify: false,
+            quote_style: QuoteStyle::default(),
+            hash_style: HashStyle::default(),
+            paren_style: ParenStyle::default(),
+            rspec_methods: names(&["it", "describe", "test"]),
+            gemfile_methods: names(&["gem", "source", "ruby", "group"]),
+            optionally_parenthesized_methods: names(&["super", "require", "require_relative"]),
+            class_macro_prefixes: names(&["attr_"]),
+            lines: vec![],
+        }
+    }
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+// A line length of 0 can't fit anything, and an indent width of 0 can't
+// show nesting, so either is almost certainly a mistake
+fn deserialize_nonzero<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = usize::deserialize(deserializer)?;
+    if value == 0 {
+        return Err(de::Error::invalid_value(
+            de::Unexpected::Unsigned(0),
+            &"a number greater than 0",
+        ));
+    }
+    Ok(value)
+}
+
+/// Which quotes string literals are written with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(C)]
+pub enum QuoteStyle {
+    /// Always use double quotes
+    #[default]
+    Double = 0,
+    /// Use single quotes for strings without interpolation or escapes
+    Single = 1,
+}
+
+/// How the keys of hashes and keyword arguments are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(C)]
+pub enum HashStyle {
+    /// `key: value` when every key can be written that way, and
+    /// `:key => value` otherwise
+    #[default]
+    Labels = 0,
+    /// Always `:key => value`, except for keys without a value, like `{x:}`
+    HashRockets = 1,
+}
+
+/// When method calls have parentheses around their arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(C)]
+pub enum ParenStyle {
+    /// Add or remove parentheses following rubyfmt's rules for each method
+    #[default]
+    Normalize = 0,
+    /// Keep parentheses where the input had them, adding them only where
+    /// leaving them out would change the meaning of the code
+    Preserve = 1,
+}
+
+// The CLI parses policies from the same names as `.rubyfmt.toml`, and the C
+// API converts them from their values in `rubyfmt.h`, returning any value
+// that isn't one
+macro_rules! policy_conversions {
+    ($policy:ident { $($name:literal => $variant:ident),* $(,)? }) => {
+        impl FromStr for $policy {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($name => Ok($policy::$variant),)*
+                    _ => Err(format!(
+                        "expected one of: {}",
+                        [$($name),*].join(", ")
+                    )),
+                }
+            }
+        }
+
+        impl TryFrom<u32> for $policy {
+            type Error = u32;
+
+            fn try_from(value: u32) -> Result<Self, Self::Error> {
+                [$($policy::$variant),*]
+                    .iter()
+                    .copied()
+                    .find(|policy| *policy as u32 == value)
+                    .ok_or(value)
+            }
+        }
+    };
+}
+
+policy_conversions!(QuoteStyle { "double" => Double, "single" => Single });
+policy_conversions!(HashStyle { "labels" => Labels, "hash_rockets" => HashRockets });
+policy_conversions!(ParenStyle { "normalize" => Normalize, "preserve" => Preserve });
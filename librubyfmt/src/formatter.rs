@@ -0,0 +1,339 @@
+//! A handle for formatting from any thread.
+//!
+//! The Ruby VM can only be used from the thread that set it up, so the first
+//! `Formatter` sets it up on a thread of its own, and every `Formatter` sends
+//! that thread requests to format one at a time. Requests can be cancelled,
+//! or given a timeout after which they're cancelled. Ripper can't be stopped
+//! part way through parsing, but formatting checks for cancellation before
+//! each expression and breakable, so a cancelled request stops soon after
+//! it's been parsed.
+
+use crate::{format_buffer_with_options, FormatError, FormatOptions, InitStatus, RichFormatError};
+use std::cell::RefCell;
+use std::panic;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Deeply nested code recurses deeply while it's formatted, so the VM thread
+// gets a much larger stack than Rust's default
+const VM_THREAD_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+const RUNNING: u8 = 0;
+const CANCELLED: u8 = 1;
+const TIMED_OUT: u8 = 2;
+
+lazy_static! {
+    // The queue of requests for the VM thread once it's been started, or the
+    // status it failed to start with, which it would fail with again
+    static ref REQUESTS: Mutex<Option<Result<Sender<Request>, InitStatus>>> = Mutex::new(None);
+}
+
+thread_local! {
+    // The request being formatted, on the VM thread
+    static CURRENT_REQUEST: RefCell<Option<Arc<RequestState>>> = const { RefCell::new(None) };
+}
+
+/// A handle for formatting on rubyfmt's VM thread, which can be used from
+/// any number of threads at once
+#[derive(Debug, Clone)]
+pub struct Formatter {
+    requests: Sender<Request>,
+}
+
+// Formatters are used from any thread, and C hosts rely on that too
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Formatter>();
+    assert_send_sync::<FormatRequest>();
+};
+
+/// A request to format a buffer, which can be waited on or cancelled from
+/// any thread. Its result can only be waited for once, and it's cancelled if
+/// it's freed before it finishes.
+#[derive(Debug)]
+pub struct FormatRequest {
+    state: Arc<RequestState>,
+    result: Mutex<Receiver<Result<String, RichFormatError>>>,
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// Formatting failed, as it would have with `format_buffer_with_options`
+    Format(RichFormatError),
+    /// The request was cancelled before it finished
+    Cancelled,
+    /// The request didn't finish within its timeout, so it was cancelled
+    TimedOut,
+}
+
+impl RequestError {
+    pub fn as_format_error(&self) -> FormatError {
+        match self {
+            RequestError::Format(e) => e.as_format_error(),
+            RequestError::Cancelled => FormatError::Cancelled,
+            RequestError::TimedOut => FormatError::TimedOut,
+        }
+    }
+}
+
+struct Request {
+    buffer: String,
+    options: FormatOptions,
+    state: Arc<RequestState>,
+    result: Sender<Result<String, RichFormatError>>,
+}
+
+// Whether a request is still wanted, or why it isn't
+#[derive(Debug, Default)]
+struct RequestState(AtomicU8);
+
+impl RequestState {
+    fn get(&self) -> u8 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // Stops a request that's still running, keeping the first reason it was
+    // stopped for
+    fn stop(&self, reason: u8) {
+        let _ = self
+            .0
+            .compare_exchange(RUNNING, reason, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+// The payload of the unwind that stops a cancelled request
+struct Cancelled;
+
+impl Formatter {
+    /// A handle for the VM thread, which is started and has the Ruby VM set
+    /// up on it the first time this is called. This fails if the VM couldn't
+    /// be set up, e.g. because `rubyfmt_init` already set it up on another
+    /// thread. Once the VM has failed to be set up, this fails without trying
+    /// again.
+    pub fn new() -> Result<Self, InitStatus> {
+        let mut requests = REQUESTS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(requests) = &*requests {
+            return requests.clone().map(|requests| Formatter { requests });
+        }
+
+        let (request_sender, request_receiver) = mpsc::channel();
+        let (init_sender, init_receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("rubyfmt-vm".to_string())
+            .stack_size(VM_THREAD_STACK_SIZE)
+            .spawn(move || {
+                let status = crate::rubyfmt_init();
+                let _ = init_sender.send(status);
+                if status == InitStatus::OK as libc::c_int {
+                    run_requests(request_receiver);
+                }
+            })
+            .map_err(|_| InitStatus::ERROR)?;
+
+        match init_receiver.recv() {
+            Ok(status) if status == InitStatus::OK as libc::c_int => {
+                *requests = Some(Ok(request_sender.clone()));
+                Ok(Formatter {
+                    requests: request_sender,
+                })
+            }
+            _ => {
+                *requests = Some(Err(InitStatus::ERROR));
+                Err(InitStatus::ERROR)
+            }
+        }
+    }
+
+    pub fn format(&self, buf: &str, options: &FormatOptions) -> Result<String, RequestError> {
+        self.submit(buf, options).wait()
+    }
+
+    /// Formats `buf`, cancelling formatting if it takes longer than `timeout`,
+    /// including any time spent waiting for other requests to finish
+    pub fn format_with_timeout(
+        &self,
+        buf: &str,
+        options: &FormatOptions,
+        timeout: Duration,
+    ) -> Result<String, RequestError> {
+        self.submit(buf, options).wait_timeout(timeout)
+    }
+
+    /// Queues a request to format `buf`, without waiting for it to finish
+    pub fn submit(&self, buf: &str, options: &FormatOptions) -> FormatRequest {
+        let state = Arc::new(RequestState::default());
+        let (result_sender, result_receiver) = mpsc::channel();
+        // If the VM thread has stopped the result never arrives, which
+        // waiting on the request reports
+        let _ = self.requests.send(Request {
+            buffer: buf.to_string(),
+            options: options.clone(),
+            state: state.clone(),
+            result: result_sender,
+        });
+
+        FormatRequest {
+            state,
+            result: Mutex::new(result_receiver),
+        }
+    }
+}
+
+impl FormatRequest {
+    /// Stops the request, if it hasn't finished already. Waiting on it then
+    /// returns `RequestError::Cancelled`.
+    pub fn cancel(&self) {
+        self.state.stop(CANCELLED);
+    }
+
+    pub fn wait(&self) -> Result<String, RequestError> {
+        let result = self.result.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        self.finish(result.ok())
+    }
+
+    /// Waits for the request to finish, cancelling it if that takes longer
+    /// than `timeout`
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<String, RequestError> {
+        let result = self
+            .result
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .recv_timeout(timeout);
+        match result {
+            Ok(result) => self.finish(Some(result)),
+            Err(RecvTimeoutError::Timeout) => {
+                self.state.stop(TIMED_OUT);
+                self.finish(None)
+            }
+            Err(RecvTimeoutError::Disconnected) => self.finish(None),
+        }
+    }
+
+    // A request that was stopped part way through fails however it was
+    // stopped, but one that finished anyway still has its output
+    fn finish(
+        &self,
+        result: Option<Result<String, RichFormatError>>,
+    ) -> Result<String, RequestError> {
+        match (result, self.state.get()) {
+            (Some(Ok(output)), _) => Ok(output),
+            (_, CANCELLED) => Err(RequestError::Cancelled),
+            (_, TIMED_OUT) => Err(RequestError::TimedOut),
+            (Some(Err(e)), _) => Err(RequestError::Format(e)),
+            (None, _) => Err(RequestError::Format(RichFormatError::OtherRubyError(
+                "rubyfmt's VM thread stopped, or this request's result was already returned"
+                    .to_string(),
+            ))),
+        }
+    }
+}
+
+impl Drop for FormatRequest {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+// Formats each request in turn, on the VM thread. `REQUESTS` keeps a sender
+// for new `Formatter`s, so this runs for as long as the process does.
+fn run_requests(requests: Receiver<Request>) {
+    for request in requests {
+        // Requests that were stopped while they were queued aren't started
+        if request.state.get() != RUNNING {
+            continue;
+        }
+
+        CURRENT_REQUEST.with(|current| *current.borrow_mut() = Some(request.state.clone()));
+        let result = format_buffer_with_options(&request.buffer, &request.options);
+        CURRENT_REQUEST.with(|current| *current.borrow_mut() = None);
+        let _ = request.result.send(result);
+    }
+}
+
+/// Stops formatting if the request being formatted has been cancelled, by
+/// unwinding to the `catch_panic` around it. Resuming an unwind doesn't run
+/// the panic hook, so this isn't reported as a panic.
+pub(crate) fn stop_if_cancelled() {
+    let cancelled = CURRENT_REQUEST.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .is_some_and(|state| state.get() != RUNNING)
+    });
+    if cancelled {
+        panic::resume_unwind(Box::new(Cancelled));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formatter() -> Formatter {
+        Formatter::new().expect("the VM should start")
+    }
+
+    // Enough code that formatting it takes much longer than the tests wait
+    fn slow_buffer() -> String {
+        "foo(bar, baz) { |qux| qux + 1 }\n".repeat(50_000)
+    }
+
+    #[test]
+    fn cancels_queued_requests() {
+        let formatter = formatter();
+        let slow = formatter.submit(&slow_buffer(), &FormatOptions::default());
+        let queued = formatter.submit("a(1)\n", &FormatOptions::default());
+
+        queued.cancel();
+
+        assert!(matches!(queued.wait(), Err(RequestError::Cancelled)));
+        slow.cancel();
+    }
+
+    #[test]
+    fn times_out_long_requests() {
+        let result = formatter().format_with_timeout(
+            &slow_buffer(),
+            &FormatOptions::default(),
+            Duration::from_millis(10),
+        );
+
+        assert!(matches!(result, Err(RequestError::TimedOut)));
+    }
+
+    #[test]
+    fn formats_after_a_cancelled_request() {
+        let formatter = formatter();
+        let cancelled = formatter.submit(&slow_buffer(), &FormatOptions::default());
+        cancelled.cancel();
+        assert!(matches!(cancelled.wait(), Err(RequestError::Cancelled)));
+
+        let result = formatter.format("a(1)\n", &FormatOptions::default());
+
+        assert_eq!(result.unwrap(), "a(1)\n");
+    }
+
+    #[test]
+    fn waits_for_results_once() {
+        let request = formatter().submit("a(1)\n", &FormatOptions::default());
+
+        assert_eq!(request.wait().unwrap(), "a(1)\n");
+        assert!(matches!(
+            request.wait(),
+            Err(RequestError::Format(RichFormatError::OtherRubyError(_)))
+        ));
+    }
+
+    #[test]
+    fn dropping_a_request_cancels_it() {
+        let request = formatter().submit(&slow_buffer(), &FormatOptions::default());
+        let state = request.state.clone();
+
+        drop(request);
+
+        assert_eq!(state.get(), CANCELLED);
+    }
+}
@@ -33,28 +33,34 @@ pub struct HeredocString {
     pub kind: HeredocKind,
     pub buf: Vec<u8>,
     pub indent: ColNumber,
+    pub indent_width: ColNumber,
 }
 
 impl HeredocString {
-    pub fn new(symbol: String, kind: HeredocKind, buf: Vec<u8>, indent: ColNumber) -> Self {
+    pub fn new(
+        symbol: String,
+        kind: HeredocKind,
+        buf: Vec<u8>,
+        indent: ColNumber,
+        indent_width: ColNumber,
+    ) -> Self {
         HeredocString {
             symbol,
             kind,
             buf,
             indent,
+            indent_width,
         }
     }
 
     pub fn render_as_string(self) -> String {
-        let indent = self.indent;
+        let indent = self.indent + self.indent_width;
         let string = String::from_utf8(self.buf).expect("heredoc is utf8");
 
         if self.kind.is_squiggly() {
             string
                 .split('\n')
-                .map(|l| {
-                    String::from(format!("{}{}", " ".repeat(indent as usize + 2), l).trim_end())
-                })
+                .map(|l| String::from(format!("{}{}", " ".repeat(indent as usize), l).trim_end()))
                 .collect::<Vec<String>>()
                 .join("\n")
         } else {
@@ -14,6 +14,9 @@ pub enum BlanklineReason {
 
 pub struct Intermediary {
     tokens: Vec<ConcreteLineToken>,
+    // How many newlines the tokens render to, kept up to date by the
+    // `*_token` methods, which are the only ones that change `tokens`
+    newline_count: usize,
     index_of_last_hard_newline: usize,
     current_line_metadata: LineMetadata,
     previous_line_metadata: Option<LineMetadata>,
@@ -24,6 +27,7 @@ impl Intermediary {
     pub fn new() -> Self {
         Intermediary {
             tokens: vec![],
+            newline_count: 0,
             current_line_metadata: LineMetadata::new(),
             previous_line_metadata: None,
             index_of_last_hard_newline: 0,
@@ -44,25 +48,25 @@ impl Intermediary {
     // - HardNewline
     // so this method actually pops off the extra empty comment whitespace
     pub fn pop_require_comment_whitespace(&mut self) {
-        self.tokens.pop();
-        self.tokens.pop();
+        self.pop_token();
+        self.pop_token();
         self.index_of_last_hard_newline = self.tokens.len() - 1;
     }
 
     pub fn pop_heredoc_mistake(&mut self) {
-        self.tokens.remove(self.tokens.len() - 1);
-        self.tokens.remove(self.tokens.len() - 1);
+        self.remove_token(self.tokens.len() - 1);
+        self.remove_token(self.tokens.len() - 1);
         self.index_of_last_hard_newline = self.tokens.len() - 1;
     }
 
     pub fn fix_heredoc_duplicate_indent_mistake(&mut self) {
         // Remove duplicate indent
-        self.tokens.remove(self.tokens.len() - 3);
+        self.remove_token(self.tokens.len() - 3);
     }
 
     pub fn fix_heredoc_arg_newline_mistake(&mut self) {
         // Remove duplicate newline
-        self.tokens.remove(self.tokens.len() - 1);
+        self.remove_token(self.tokens.len() - 1);
         self.index_of_last_hard_newline = self.tokens.len() - 1;
     }
 
@@ -87,6 +91,11 @@ impl Intermediary {
         self.tokens
     }
 
+    /// The line of the output that the next token goes on, counting from 1.
+    pub fn current_line_number(&self) -> usize {
+        1 + self.newline_count
+    }
+
     pub fn current_line_length(&self) -> usize {
         if self.tokens.is_empty() {
             return 0;
@@ -182,7 +191,7 @@ impl Intermediary {
                     }
 
                     if module_or_class_before_newline {
-                        self.tokens.pop();
+                        self.pop_token();
                         self.index_of_last_hard_newline = self.tokens.len() - 1;
                     }
                 }
@@ -191,7 +200,7 @@ impl Intermediary {
         }
 
         if do_push {
-            self.tokens.push(lt);
+            self.push_token(lt);
         }
         self.debug_assert_newlines();
     }
@@ -228,12 +237,12 @@ impl Intermediary {
         while self.tokens.len() > 2
             && self.tokens[self.len() - 2].is_single_line_breakable_garbage()
         {
-            self.tokens.remove(self.len() - 2);
+            self.remove_token(self.len() - 2);
         }
     }
 
     pub fn insert_blankline_from_end(&mut self, index_from_end: usize) {
-        self.tokens.insert(
+        self.insert_token(
             self.tokens.len() - index_from_end,
             ConcreteLineToken::HardNewLine,
         )
@@ -241,7 +250,7 @@ impl Intermediary {
 
     pub fn insert_trailing_blankline(&mut self, _bl: BlanklineReason) {
         if self.index_of_last_hard_newline <= 2 {
-            self.tokens.insert(
+            self.insert_token(
                 self.index_of_last_hard_newline,
                 ConcreteLineToken::HardNewLine,
             );
@@ -265,7 +274,7 @@ impl Intermediary {
                 {
                     debug!("{:?}", _bl);
                 }
-                self.tokens.insert(
+                self.insert_token(
                     self.index_of_last_hard_newline,
                     ConcreteLineToken::HardNewLine,
                 );
@@ -275,6 +284,27 @@ impl Intermediary {
         }
     }
 
+    fn push_token(&mut self, token: ConcreteLineToken) {
+        self.newline_count += token.newline_count();
+        self.tokens.push(token);
+    }
+
+    fn pop_token(&mut self) {
+        if let Some(token) = self.tokens.pop() {
+            self.newline_count -= token.newline_count();
+        }
+    }
+
+    fn insert_token(&mut self, index: usize, token: ConcreteLineToken) {
+        self.newline_count += token.newline_count();
+        self.tokens.insert(index, token);
+    }
+
+    fn remove_token(&mut self, index: usize) {
+        let token = self.tokens.remove(index);
+        self.newline_count -= token.newline_count();
+    }
+
     #[cfg(debug_assertions)]
     fn debug_assert_newlines(&self) {
         if self.index_of_last_hard_newline == 0 {
@@ -2,9 +2,18 @@
 #![allow(clippy::upper_case_acronyms, clippy::enum_variant_names)]
 
 use serde::de::value;
+use similar::{DiffTag, TextDiff};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::{c_char, CString};
+use std::fmt;
 use std::io::{Cursor, Write};
+use std::ops::RangeInclusive;
 use std::slice;
 use std::str;
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::Duration;
 
 #[macro_use]
 extern crate lazy_static;
@@ -16,16 +25,21 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 pub type RawStatus = i64;
 
 #[macro_use]
+// Bindings to the Ruby VM, which aren't part of rubyfmt's C API
+/// cbindgen:ignore
 pub mod ruby;
 mod comment_block;
 mod de;
 mod delimiters;
 mod file_comments;
 mod format;
+mod format_options;
+mod formatter;
 mod heredoc_string;
 mod intermediary;
 mod line_metadata;
 mod line_tokens;
+mod panic_isolation;
 mod parser_state;
 mod render_queue_writer;
 mod render_targets;
@@ -34,6 +48,8 @@ mod ruby_ops;
 mod types;
 
 use file_comments::FileComments;
+pub use format_options::{FormatOptions, HashStyle, ParenStyle, QuoteStyle};
+pub use formatter::{FormatRequest, Formatter, RequestError};
 use parser_state::BaseParserState;
 use ruby_ops::{load_rubyfmt, ParseError, Parser, RipperTree};
 
@@ -44,12 +60,16 @@ use simplelog::{ConfigBuilder, LevelFilter, TermLogger, TerminalMode};
 
 extern "C" {
     pub fn Init_ripper();
-    pub fn rb_gc_disable();
 }
 
+/// The version of the C API in `rubyfmt.h`, which changes whenever a
+/// function's signature or behaviour does
+pub const RUBYFMT_API_VERSION: u32 = 2;
+
 pub struct RubyfmtString(Box<str>);
 
 #[derive(Debug, Copy, Clone)]
+#[repr(C)]
 pub enum InitStatus {
     OK = 0,
     ERROR = 1,
@@ -57,10 +77,53 @@ pub enum InitStatus {
 
 #[derive(Debug)]
 pub enum RichFormatError {
-    SyntaxError,
+    /// A syntax error in the input, at a line and column that both count
+    /// from 1. Columns are counted in characters.
+    SyntaxError {
+        line: usize,
+        column: usize,
+        message: String,
+    },
     RipperParseFailure(value::Error),
     IOError(std::io::Error),
     OtherRubyError(String),
+    /// The formatted output doesn't parse to the same program as the input,
+    /// with a diff of the two programs' structure
+    VerificationFailed(String),
+    /// Rubyfmt panicked while formatting, which is always a bug in rubyfmt
+    InternalError {
+        message: String,
+        backtrace: String,
+    },
+}
+
+impl fmt::Display for RichFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RichFormatError::SyntaxError {
+                line,
+                column,
+                message,
+            } => write!(f, "syntax error at {}:{}: {}", line, column, message),
+            RichFormatError::RipperParseFailure(e) => write!(
+                f,
+                "couldn't deserialize ripper's tree, which is a bug in rubyfmt: {}",
+                e
+            ),
+            RichFormatError::IOError(e) => write!(f, "IO error: {}", e),
+            RichFormatError::OtherRubyError(s) => write!(f, "unexpected ruby error: {}", s),
+            RichFormatError::VerificationFailed(diff) => write!(
+                f,
+                "the formatted output doesn't parse to the same program as the input, which is a bug in rubyfmt:\n{}",
+                diff
+            ),
+            RichFormatError::InternalError { message, .. } => write!(
+                f,
+                "internal error, which is a bug in rubyfmt: {}",
+                message
+            ),
+        }
+    }
 }
 
 impl RichFormatError {
@@ -68,38 +131,364 @@ impl RichFormatError {
         self.as_format_error() as i32
     }
 
-    fn as_format_error(&self) -> FormatError {
+    pub fn as_format_error(&self) -> FormatError {
         match self {
-            RichFormatError::SyntaxError => FormatError::SyntaxError,
+            RichFormatError::SyntaxError { .. } => FormatError::SyntaxError,
             RichFormatError::RipperParseFailure(_) => FormatError::RipperParseFailure,
             RichFormatError::IOError(_) => FormatError::IOError,
             RichFormatError::OtherRubyError(_) => FormatError::OtherRubyError,
+            RichFormatError::VerificationFailed(_) => FormatError::VerificationFailed,
+            RichFormatError::InternalError { .. } => FormatError::InternalError,
         }
     }
 }
 
 #[derive(Debug, Copy, Clone)]
+#[repr(C)]
 pub enum FormatError {
     OK = 0,
+    /// The buffer has a ruby syntax error. Non fatal, rubyfmt can still be
+    /// used to format other buffers.
     SyntaxError = 1,
+    /// Rubyfmt couldn't deserialize ripper's tree. This is fatal, and a bug in
+    /// rubyfmt that should be reported with the file that caused it.
     RipperParseFailure = 2,
+    /// An IO error while formatting, which should be impossible and most
+    /// likely a bug in rubyfmt
     IOError = 3,
+    /// An unexpected ruby error, which is a bug in rubyfmt. The process should
+    /// be restarted before formatting anything else.
     OtherRubyError = 4,
-    // Diffs are only necessary in --check mode
+    /// Only from the CLI's `--check` mode
     DiffDetected = 5,
+    /// The formatted output doesn't parse to the same program as the input
+    VerificationFailed = 6,
+    /// Rubyfmt panicked while formatting, which is a bug in rubyfmt. Non
+    /// fatal, but if the ruby VM is no longer usable, later calls fail with
+    /// the unexpected ruby error.
+    InternalError = 7,
+    /// A request to a formatter was cancelled before it finished
+    Cancelled = 8,
+    /// A request to a formatter didn't finish within its timeout, and was
+    /// cancelled
+    TimedOut = 9,
+    /// The buffer passed through the C API isn't valid UTF-8
+    InvalidUtf8 = 10,
+    /// An option passed through the C API is out of range, e.g. a line
+    /// length of 0
+    InvalidOption = 11,
+}
+
+lazy_static! {
+    // The thread that set up the Ruby VM, which is the only one that can use it
+    static ref RUBY_VM_THREAD: Mutex<Option<ThreadId>> = Mutex::new(None);
+}
+
+// Whether the Ruby VM was set up on another thread, so this one can't use it
+fn ruby_vm_on_other_thread() -> bool {
+    RUBY_VM_THREAD
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .is_some_and(|vm_thread| vm_thread != thread::current().id())
 }
 
 pub fn format_buffer(buf: &str) -> Result<String, RichFormatError> {
-    let (tree, file_comments, end_data) = run_parser_on(buf)?;
+    format_buffer_with_options(buf, &FormatOptions::default())
+}
+
+pub fn format_buffer_with_options(
+    buf: &str,
+    options: &FormatOptions,
+) -> Result<String, RichFormatError> {
+    format_buffer_with(buf, options).map(|output| output.output)
+}
+
+/// Formats only the statements touching the given (1-indexed, inclusive)
+/// ranges of lines, or the smallest statements enclosing them, leaving every
+/// other line exactly as it was. An empty list of ranges formats everything.
+pub fn format_lines_with_options(
+    buf: &str,
+    line_ranges: &[RangeInclusive<usize>],
+    options: &FormatOptions,
+) -> Result<String, RichFormatError> {
+    let options = FormatOptions {
+        lines: line_ranges.to_vec(),
+        ..options.clone()
+    };
+    format_buffer_with_options(buf, &options)
+}
+
+/// The formatted code, along with what formatting did to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOutput {
+    pub output: String,
+    /// Whether the output differs from the input at all
+    pub changed: bool,
+    /// Problems that didn't stop formatting, like syntax rubyfmt can't format
+    /// and so left as it was
+    pub warnings: Vec<String>,
+    /// For each line of the output, the (1-indexed) line of the input it was
+    /// copied from unchanged, or `None` if formatting rewrote it
+    pub line_map: Vec<Option<usize>>,
+}
+
+/// Formats `buf` with every option in `options`, including its line range
+/// restrictions. This is what the CLI uses, so everything it can do is
+/// available here too.
+///
+/// A panic while formatting is returned as an `InternalError`, rather than
+/// unwinding into the caller.
+pub fn format_buffer_with(
+    buf: &str,
+    options: &FormatOptions,
+) -> Result<FormatOutput, RichFormatError> {
+    isolated(|| format_buffer_unisolated(buf, options))
+}
+
+fn format_buffer_unisolated(
+    buf: &str,
+    options: &FormatOptions,
+) -> Result<FormatOutput, RichFormatError> {
+    let line_ranges = &options.lines;
+    let (tree, file_comments, end_data) = run_parser_on(buf, line_ranges)?;
     let out_data = vec![];
     let mut output = Cursor::new(out_data);
-    toplevel_format_program(&mut output, tree, file_comments, end_data)?;
+    let warnings = toplevel_format_program(
+        &mut output,
+        &tree,
+        file_comments,
+        end_data.as_deref(),
+        options,
+    )?;
     output.flush().expect("flushing to a vec should never fail");
-    Ok(String::from_utf8(output.into_inner()).expect("we never write invalid UTF-8"))
+    let output = String::from_utf8(output.into_inner()).expect("we never write invalid UTF-8");
+
+    if options.verify {
+        // Statements outside the ranges are left out of the tree, so compare
+        // the whole of the original program
+        let original_tree = if line_ranges.is_empty() {
+            tree
+        } else {
+            run_parser_on(buf, &[])?.0
+        };
+        verify_formatted_program(&original_tree, &output)?;
+    }
+
+    Ok(FormatOutput {
+        changed: output != buf,
+        line_map: line_map(buf, &output),
+        output,
+        warnings,
+    })
 }
 
+// Maps each line of `output` to the line of `input` it's identical to, going
+// by a line diff of the two
+fn line_map(input: &str, output: &str) -> Vec<Option<usize>> {
+    let mut map = Vec::new();
+    for op in TextDiff::from_lines(input, output).ops() {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        match tag {
+            DiffTag::Equal => map.extend(old_range.map(|line| Some(line + 1))),
+            DiffTag::Delete => {}
+            DiffTag::Insert | DiffTag::Replace => map.extend(new_range.map(|_| None)),
+        }
+    }
+    map
+}
+
+// Runs `f`, returning any panic as an `InternalError`, unless an earlier
+// panic left the Ruby VM unusable or this isn't the Ruby VM's thread
+fn isolated<T>(f: impl FnOnce() -> Result<T, RichFormatError>) -> Result<T, RichFormatError> {
+    if ruby_vm_on_other_thread() {
+        return Err(RichFormatError::OtherRubyError(
+            "rubyfmt can only be used from the thread that called rubyfmt_init, use a Formatter to format from other threads".to_string(),
+        ));
+    }
+    if panic_isolation::ruby_vm_unusable() {
+        return Err(RichFormatError::OtherRubyError(
+            "the Ruby VM stopped responding after an earlier internal error".to_string(),
+        ));
+    }
+
+    panic_isolation::catch_panic(f).unwrap_or_else(|(message, backtrace)| {
+        Err(RichFormatError::InternalError { message, backtrace })
+    })
+}
+
+/// The result of formatting a buffer, and then formatting that output again.
+/// Formatting should be idempotent, so the two passes should be the same.
+#[derive(Debug)]
+pub struct IdempotencyCheck {
+    pub first_pass: String,
+    pub second_pass: String,
+    /// Any warnings from the first pass
+    pub warnings: Vec<String>,
+}
+
+impl IdempotencyCheck {
+    pub fn is_idempotent(&self) -> bool {
+        self.first_pass == self.second_pass
+    }
+}
+
+pub fn check_idempotency(
+    buf: &str,
+    options: &FormatOptions,
+) -> Result<IdempotencyCheck, RichFormatError> {
+    // The first pass moves lines around, so both passes format everything
+    let options = FormatOptions {
+        lines: vec![],
+        ..options.clone()
+    };
+    let first_pass = format_buffer_with(buf, &options)?;
+    let second_pass = format_buffer_with_options(&first_pass.output, &options)?;
+    Ok(IdempotencyCheck {
+        first_pass: first_pass.output,
+        second_pass,
+        warnings: first_pass.warnings,
+    })
+}
+
+/// A stage of formatting whose output `dump_lines_with_options` can print,
+/// for working out which stage is responsible for a formatting bug
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DumpStage {
+    /// The s-expression from ripper, as JSON
+    Ripper,
+    /// The tree that ripper's output deserializes to
+    Ast,
+    /// The tokens in the render queue, before breakable entries are laid out
+    Tokens,
+    /// The tokens that are written out, once every breakable entry is laid out
+    FinalTokens,
+}
+
+/// Runs formatting up to `stage` and returns that stage's output, as pretty
+/// JSON for `DumpStage::Ripper` and indented `Debug` output otherwise.
+pub fn dump_lines_with_options(
+    buf: &str,
+    stage: DumpStage,
+    options: &FormatOptions,
+) -> Result<String, RichFormatError> {
+    isolated(|| dump_lines_unisolated(buf, stage, options))
+}
+
+fn dump_lines_unisolated(
+    buf: &str,
+    stage: DumpStage,
+    options: &FormatOptions,
+) -> Result<String, RichFormatError> {
+    let (tree, file_comments, end_data) = run_parser_on(buf, &options.lines)?;
+    if stage == DumpStage::Ripper {
+        let sexp: serde_json::Value =
+            de::from_value(&tree).map_err(RichFormatError::RipperParseFailure)?;
+        return Ok(format!(
+            "{}\n",
+            serde_json::to_string_pretty(&sexp).expect("JSON values always serialize")
+        ));
+    }
+
+    let program: ripper_tree_types::Program =
+        de::from_value(&tree).map_err(RichFormatError::RipperParseFailure)?;
+    if stage == DumpStage::Ast {
+        return Ok(format!("{:#?}\n", program));
+    }
+
+    let mut ps = BaseParserState::new(file_comments, options.clone());
+    format::format_program(&mut ps, program, end_data.as_deref());
+    Ok(match stage {
+        DumpStage::Tokens => format!("{:#?}\n", ps.consume_to_render_queue()),
+        _ => format!("{:#?}\n", ps.into_final_tokens()),
+    })
+}
+
+/// Explains each decision about whether to break up a breakable that affects
+/// `line` of the formatted output: what was considered, how long it would be
+/// on one line, why it was or wasn't broken up, and the formatting contexts
+/// it was in.
+pub fn explain_line_with_options(
+    buf: &str,
+    line: usize,
+    options: &FormatOptions,
+) -> Result<String, RichFormatError> {
+    isolated(|| explain_line_unisolated(buf, line, options))
+}
+
+fn explain_line_unisolated(
+    buf: &str,
+    line: usize,
+    options: &FormatOptions,
+) -> Result<String, RichFormatError> {
+    let (tree, file_comments, end_data) = run_parser_on(buf, &options.lines)?;
+    let program: ripper_tree_types::Program =
+        de::from_value(&tree).map_err(RichFormatError::RipperParseFailure)?;
+    let mut ps = BaseParserState::new(file_comments, options.clone());
+    format::format_program(&mut ps, program, end_data.as_deref());
+    if ps.is_unformattable() {
+        return Ok("Rubyfmt can't format this file, so it's output as it is\n".to_string());
+    }
+
+    let decisions: Vec<String> = ps
+        .into_break_decisions()
+        .into_iter()
+        .filter(|decision| decision.affects_line(line))
+        .map(|decision| decision.to_string())
+        .collect();
+    if decisions.is_empty() {
+        Ok(format!(
+            "No breakables were considered for line {} of the output\n",
+            line
+        ))
+    } else {
+        Ok(decisions.join("\n"))
+    }
+}
+
+// Re-parses the formatted output, and checks that it has the same structure as the
+// original program once locations and the rewrites rubyfmt makes are normalised away
+fn verify_formatted_program(tree: &RipperTree, output: &str) -> Result<(), RichFormatError> {
+    let original_dump = verification_dump_of(tree)?;
+    let formatted_dump = match run_parser_on(output, &[]) {
+        Ok((formatted_tree, ..)) => verification_dump_of(&formatted_tree)?,
+        Err(RichFormatError::SyntaxError {
+            line,
+            column,
+            message,
+        }) => {
+            return Err(RichFormatError::VerificationFailed(format!(
+                "the formatted output has a syntax error at {}:{}: {}",
+                line, column, message
+            )))
+        }
+        Err(e) => return Err(e),
+    };
+
+    if original_dump != formatted_dump {
+        let diff = TextDiff::from_lines(&original_dump, &formatted_dump);
+        return Err(RichFormatError::VerificationFailed(format!(
+            "{}",
+            diff.unified_diff().header("original", "formatted")
+        )));
+    }
+    Ok(())
+}
+
+fn verification_dump_of(tree: &RipperTree) -> Result<String, RichFormatError> {
+    tree.verification_dump().map_err(rich_parse_error)
+}
+
+/// Sets up rubyfmt, once per process, returning `RUBYFMT_INIT_STATUS_ERROR`
+/// if that failed. Formatting functions can only be called from the thread
+/// that called this.
 #[no_mangle]
 pub extern "C" fn rubyfmt_init() -> libc::c_int {
+    clear_last_error();
+    // The Ruby VM can only be set up once, on one thread
+    if ruby_vm_on_other_thread() {
+        return InitStatus::ERROR as libc::c_int;
+    }
+
     init_logger();
     let res = ruby_ops::setup_ruby();
     if res.is_err() {
@@ -116,49 +505,534 @@ pub extern "C" fn rubyfmt_init() -> libc::c_int {
         return InitStatus::ERROR as libc::c_int;
     }
 
+    *RUBY_VM_THREAD.lock().unwrap_or_else(|e| e.into_inner()) = Some(thread::current().id());
     InitStatus::OK as libc::c_int
 }
 
+// What went wrong in the last C API call on a thread, for
+// `rubyfmt_last_error_message` and friends
+struct LastError {
+    code: FormatError,
+    message: CString,
+    // Both count from 1, and are 0 when the error isn't at a location
+    line: usize,
+    column: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
+}
+
+impl LastError {
+    fn new(code: FormatError, message: impl fmt::Display, line: usize, column: usize) -> Self {
+        // Messages can quote the input, which could contain a NUL
+        let message = message.to_string().replace('\0', "\\0");
+        LastError {
+            code,
+            message: CString::new(message).expect("NULs were replaced"),
+            line,
+            column,
+        }
+    }
+}
+
+impl From<RichFormatError> for LastError {
+    fn from(e: RichFormatError) -> Self {
+        let (line, column) = match e {
+            RichFormatError::SyntaxError { line, column, .. } => (line, column),
+            _ => (0, 0),
+        };
+        LastError::new(e.as_format_error(), &e, line, column)
+    }
+}
+
+impl From<RequestError> for LastError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Format(e) => e.into(),
+            RequestError::Cancelled => {
+                LastError::new(FormatError::Cancelled, "the request was cancelled", 0, 0)
+            }
+            RequestError::TimedOut => {
+                LastError::new(FormatError::TimedOut, "the request timed out", 0, 0)
+            }
+        }
+    }
+}
+
+// Every C API call, other than the ones reading it, starts by clearing the
+// thread's last error, so it's only ever about the latest call
+fn clear_last_error() {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = None);
+}
+
+// The buffer passed to a C API call, which is an error if it isn't UTF-8
+unsafe fn input_str<'a>(ptr: *const u8, len: usize) -> Result<&'a str, LastError> {
+    if len == 0 {
+        return Ok("");
+    }
+    let bytes = slice::from_raw_parts(ptr, len);
+    str::from_utf8(bytes).map_err(|e| {
+        let valid = str::from_utf8(&bytes[..e.valid_up_to()]).expect("this part is valid");
+        let line = valid.matches('\n').count() + 1;
+        let column = valid.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+        LastError::new(
+            FormatError::InvalidUtf8,
+            format_args!("the input isn't valid UTF-8: {}", e),
+            line,
+            column,
+        )
+    })
+}
+
+// Returns the output of a C API call, setting `err` and the thread's last
+// error from how it went
+unsafe fn output_to_c(
+    output: Result<String, LastError>,
+    err: *mut FormatError,
+) -> *mut RubyfmtString {
+    match output {
+        Ok(o) => {
+            *err = FormatError::OK;
+            Box::into_raw(Box::new(RubyfmtString(o.into_boxed_str())))
+        }
+        Err(e) => {
+            *err = e.code;
+            LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(e));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Formats the `len` bytes at `ptr`, returning the formatted buffer, which
+/// must be freed with `rubyfmt_string_free`. If they aren't valid UTF-8 or
+/// can't be formatted, this returns null and sets `*err` to why, which
+/// `rubyfmt_last_error_message` describes.
+///
 /// # Safety
-/// this function will fail, very badly, if len specifies more bytes than is
-/// available in the passed buffer pointer. It will also fail if the passed
-/// data isn't utf8.
-/// Please don't pass non-utf8 too small buffers.
+/// `ptr` must point to at least `len` readable bytes, and `err` must not be
+/// null.
 #[no_mangle]
 pub unsafe extern "C" fn rubyfmt_format_buffer(
     ptr: *const u8,
     len: usize,
-    err: *mut i64,
+    err: *mut FormatError,
 ) -> *mut RubyfmtString {
-    let input = str::from_utf8_unchecked(slice::from_raw_parts(ptr, len));
-    let output = format_buffer(input);
-    match output {
-        Ok(o) => {
-            *err = FormatError::OK as i64;
-            Box::into_raw(Box::new(RubyfmtString(o.into_boxed_str())))
+    rubyfmt_format_buffer_with_options(ptr, len, std::ptr::null(), err)
+}
+
+/// Deprecated: use `rubyfmt_options_set_line_length` with
+/// `rubyfmt_format_buffer_with_options` instead. This is kept for callers of
+/// earlier versions, and is the same as `rubyfmt_format_buffer`, but breaks
+/// up lines longer than `line_length` instead of the default of 120 columns.
+/// A `line_length` of 0 is rejected with
+/// `RUBYFMT_FORMAT_ERROR_INVALID_OPTION`.
+///
+/// # Safety
+/// The same as `rubyfmt_format_buffer`.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_format_buffer_with_line_length(
+    ptr: *const u8,
+    len: usize,
+    line_length: usize,
+    err: *mut FormatError,
+) -> *mut RubyfmtString {
+    clear_last_error();
+    if line_length == 0 {
+        let error = LastError::new(
+            FormatError::InvalidOption,
+            "the line length must be greater than 0",
+            0,
+            0,
+        );
+        return output_to_c(Err(error), err);
+    }
+    let options = FormatOptions {
+        line_length,
+        ..FormatOptions::default()
+    };
+    rubyfmt_format_buffer_with_options(ptr, len, &options, err)
+}
+
+/// The same as `rubyfmt_format_buffer`, but formats with `options`, which
+/// must have come from `rubyfmt_options_new`, or be null for the defaults.
+///
+/// # Safety
+/// The same as `rubyfmt_format_buffer`, and `options` must be null or not
+/// have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_format_buffer_with_options(
+    ptr: *const u8,
+    len: usize,
+    options: *const FormatOptions,
+    err: *mut FormatError,
+) -> *mut RubyfmtString {
+    clear_last_error();
+    let default_options = FormatOptions::default();
+    let options = options.as_ref().unwrap_or(&default_options);
+    let output = input_str(ptr, len)
+        .and_then(|input| format_buffer_with_options(input, options).map_err(LastError::from));
+    output_to_c(output, err)
+}
+
+/// Options for formatting, starting with the defaults. Free them with
+/// `rubyfmt_options_free`.
+#[no_mangle]
+pub extern "C" fn rubyfmt_options_new() -> *mut FormatOptions {
+    clear_last_error();
+    Box::into_raw(Box::default())
+}
+
+/// Frees options from `rubyfmt_options_new`, which can't be used afterwards.
+/// Does nothing if `options` is null.
+///
+/// # Safety
+/// `options` must be null or have come from `rubyfmt_options_new`, and not
+/// have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_options_free(options: *mut FormatOptions) {
+    clear_last_error();
+    if !options.is_null() {
+        drop(Box::from_raw(options));
+    }
+}
+
+// The options setters return false, without changing anything, when
+// `options` is null or `set` rejects the value
+unsafe fn set_option(
+    options: *mut FormatOptions,
+    set: impl FnOnce(&mut FormatOptions) -> bool,
+) -> bool {
+    clear_last_error();
+    options.as_mut().is_some_and(set)
+}
+
+/// Sets the line length that longer lines are broken up at. Returns false,
+/// leaving the options as they were, for 0 or if `options` is null.
+///
+/// # Safety
+/// `options` must be null or have come from `rubyfmt_options_new`, and not
+/// have been freed. The same goes for the other `rubyfmt_options_` setters.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_options_set_line_length(
+    options: *mut FormatOptions,
+    line_length: usize,
+) -> bool {
+    set_option(options, |options| {
+        if line_length == 0 {
+            return false;
+        }
+        options.line_length = line_length;
+        true
+    })
+}
+
+/// Sets the number of spaces for each level of indentation. Returns false,
+/// leaving the options as they were, for 0 or if `options` is null.
+///
+/// # Safety
+/// The same as `rubyfmt_options_set_line_length`.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_options_set_indent_width(
+    options: *mut FormatOptions,
+    indent_width: usize,
+) -> bool {
+    set_option(options, |options| {
+        if indent_width == 0 {
+            return false;
+        }
+        options.indent_width = indent_width;
+        true
+    })
+}
+
+/// Sets whether the output is checked to parse the same as the input.
+/// Returns false if `options` is null.
+///
+/// # Safety
+/// The same as `rubyfmt_options_set_line_length`.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_options_set_verify(
+    options: *mut FormatOptions,
+    verify: bool,
+) -> bool {
+    set_option(options, |options| {
+        options.verify = verify;
+        true
+    })
+}
+
+/// Sets how strings are quoted to one of the `RubyfmtQuoteStyle` values.
+/// Returns false, leaving the options as they were, for any other value or
+/// if `options` is null.
+///
+/// # Safety
+/// The same as `rubyfmt_options_set_line_length`.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_options_set_quote_style(
+    options: *mut FormatOptions,
+    quote_style: u32,
+) -> bool {
+    set_option(options, |options| {
+        set_policy(&mut options.quote_style, quote_style)
+    })
+}
+
+/// Sets how hash keys are written to one of the `RubyfmtHashStyle` values.
+/// Returns false, leaving the options as they were, for any other value or
+/// if `options` is null.
+///
+/// # Safety
+/// The same as `rubyfmt_options_set_line_length`.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_options_set_hash_style(
+    options: *mut FormatOptions,
+    hash_style: u32,
+) -> bool {
+    set_option(options, |options| {
+        set_policy(&mut options.hash_style, hash_style)
+    })
+}
+
+/// Sets when method calls have parentheses to one of the `RubyfmtParenStyle`
+/// values. Returns false, leaving the options as they were, for any other
+/// value or if `options` is null.
+///
+/// # Safety
+/// The same as `rubyfmt_options_set_line_length`.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_options_set_paren_style(
+    options: *mut FormatOptions,
+    paren_style: u32,
+) -> bool {
+    set_option(options, |options| {
+        set_policy(&mut options.paren_style, paren_style)
+    })
+}
+
+// C callers can pass any number for a policy, so only valid ones are set
+fn set_policy<T: TryFrom<u32>>(policy: &mut T, value: u32) -> bool {
+    match T::try_from(value) {
+        Ok(value) => {
+            *policy = value;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Only formats the statements touching lines `start` to `end`, which count
+/// from 1 and are inclusive, as well as any other ranges already added.
+/// Returns false, without adding it, if the range is empty or starts at 0,
+/// or if `options` is null.
+///
+/// # Safety
+/// The same as `rubyfmt_options_set_line_length`.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_options_add_line_range(
+    options: *mut FormatOptions,
+    start: usize,
+    end: usize,
+) -> bool {
+    set_option(options, |options| {
+        if start == 0 || end < start {
+            return false;
+        }
+        options.lines.push(start..=end);
+        true
+    })
+}
+
+/// A description of the last error from a C API call on this thread, as a
+/// null terminated string, or null if the last call succeeded. It's valid
+/// until the next call on this thread. Reading the last error, with this,
+/// `rubyfmt_last_error_line` or `rubyfmt_last_error_column`, doesn't clear
+/// it.
+#[no_mangle]
+pub extern "C" fn rubyfmt_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |e| e.message.as_ptr())
+    })
+}
+
+/// The line of the input that the last error on this thread was on, counting
+/// from 1, or 0 if it wasn't at a particular line
+#[no_mangle]
+pub extern "C" fn rubyfmt_last_error_line() -> usize {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ref().map_or(0, |e| e.line))
+}
+
+/// The column, in characters, that the last error on this thread was at,
+/// counting from 1, or 0 if it wasn't at a particular column
+#[no_mangle]
+pub extern "C" fn rubyfmt_last_error_column() -> usize {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ref().map_or(0, |e| e.column))
+}
+
+/// Rubyfmt's version, as a null terminated string
+#[no_mangle]
+pub extern "C" fn rubyfmt_version() -> *const c_char {
+    clear_last_error();
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// The version of the C API this library has, which callers can compare
+/// with the `RUBYFMT_API_VERSION` they were built against
+#[no_mangle]
+pub extern "C" fn rubyfmt_api_version() -> u32 {
+    clear_last_error();
+    RUBYFMT_API_VERSION
+}
+
+/// Starts rubyfmt's VM thread if it isn't running yet, returning a handle for
+/// formatting on it from any thread, or null if the VM couldn't be set up.
+/// This replaces `rubyfmt_init`, which must not be called as well.
+#[no_mangle]
+pub extern "C" fn rubyfmt_formatter_new() -> *mut Formatter {
+    clear_last_error();
+    match Formatter::new() {
+        Ok(formatter) => Box::into_raw(Box::new(formatter)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Frees a formatter from `rubyfmt_formatter_new`, which can't be used
+/// afterwards. Requests it submitted can still be waited on.
+///
+/// # Safety
+/// `formatter` must have come from `rubyfmt_formatter_new`, and not have
+/// been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_formatter_free(formatter: *mut Formatter) {
+    clear_last_error();
+    drop(Box::from_raw(formatter));
+}
+
+/// The same as `rubyfmt_format_buffer_with_options`, but formats on the
+/// formatter's VM thread, so it can be called from any thread. Formatting is
+/// cancelled if it takes longer than `timeout_ms` milliseconds, unless
+/// that's 0.
+///
+/// # Safety
+/// The same as `rubyfmt_format_buffer_with_options`.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_formatter_format(
+    formatter: &Formatter,
+    ptr: *const u8,
+    len: usize,
+    options: *const FormatOptions,
+    timeout_ms: u64,
+    err: *mut FormatError,
+) -> *mut RubyfmtString {
+    let request = rubyfmt_formatter_submit(formatter, ptr, len, options);
+    if request.is_null() {
+        *err = FormatError::InvalidUtf8;
+        return std::ptr::null_mut();
+    }
+    let output = rubyfmt_request_wait(&*request, timeout_ms, err);
+    // Not `rubyfmt_request_free`, which would clear the error from waiting
+    drop(Box::from_raw(request));
+    output
+}
+
+/// The same as `rubyfmt_formatter_format`, but only queues the buffer to be
+/// formatted, returning a request that can be waited on or cancelled from
+/// any thread until it's freed with `rubyfmt_request_free`. The options are
+/// copied, so they can be freed straight away. Returns null if the buffer
+/// isn't valid UTF-8.
+///
+/// # Safety
+/// `ptr` must point to at least `len` readable bytes, and `options` must be
+/// null or not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_formatter_submit(
+    formatter: &Formatter,
+    ptr: *const u8,
+    len: usize,
+    options: *const FormatOptions,
+) -> *mut FormatRequest {
+    clear_last_error();
+    let default_options = FormatOptions::default();
+    let options = options.as_ref().unwrap_or(&default_options);
+    match input_str(ptr, len) {
+        Ok(input) => {
+            let request = formatter.submit(input, options);
+            Box::into_raw(Box::new(request))
         }
         Err(e) => {
-            *err = e.as_format_error() as i64;
-            std::ptr::null::<RubyfmtString>() as _
+            LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(e));
+            std::ptr::null_mut()
         }
     }
 }
 
+/// Stops a request, if it hasn't finished already. Waiting on it then fails
+/// with `RUBYFMT_FORMAT_ERROR_CANCELLED`.
+#[no_mangle]
+pub extern "C" fn rubyfmt_request_cancel(request: &FormatRequest) {
+    clear_last_error();
+    request.cancel();
+}
+
+/// Waits for a request to finish, for at most `timeout_ms` milliseconds
+/// unless that's 0, and returns its output in the same way as
+/// `rubyfmt_format_buffer`. A request can only be waited on once.
+///
+/// # Safety
+/// `err` must not be null.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_request_wait(
+    request: &FormatRequest,
+    timeout_ms: u64,
+    err: *mut FormatError,
+) -> *mut RubyfmtString {
+    clear_last_error();
+    let output = if timeout_ms == 0 {
+        request.wait()
+    } else {
+        request.wait_timeout(Duration::from_millis(timeout_ms))
+    };
+    output_to_c(output.map_err(LastError::from), err)
+}
+
+/// Frees a request from `rubyfmt_formatter_submit`, which can't be used
+/// afterwards, cancelling it if it hasn't finished
+///
+/// # Safety
+/// `request` must have come from `rubyfmt_formatter_submit`, and not have
+/// been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn rubyfmt_request_free(request: *mut FormatRequest) {
+    clear_last_error();
+    drop(Box::from_raw(request));
+}
+
+/// A pointer to the bytes of a `RubyfmtString`, which aren't null
+/// terminated, so should be used with `rubyfmt_string_len`
 #[no_mangle]
 pub extern "C" fn rubyfmt_string_ptr(s: &RubyfmtString) -> *const u8 {
+    clear_last_error();
     s.0.as_ptr()
 }
 
 #[no_mangle]
 pub extern "C" fn rubyfmt_string_len(s: &RubyfmtString) -> usize {
+    clear_last_error();
     s.0.len()
 }
 
+/// Frees a string returned by rubyfmt, which can't be used afterwards
+///
+/// # Safety
+/// `rubyfmt_string` must have come from rubyfmt, and not have been freed
+/// already.
 #[no_mangle]
-extern "C" fn rubyfmt_string_free(rubyfmt_string: *mut RubyfmtString) {
-    unsafe {
-        drop(Box::from_raw(rubyfmt_string));
-    }
+pub unsafe extern "C" fn rubyfmt_string_free(rubyfmt_string: *mut RubyfmtString) {
+    clear_last_error();
+    drop(Box::from_raw(rubyfmt_string));
 }
 
 // Safety: This function expects a functioning Ruby VM
@@ -194,32 +1068,57 @@ unsafe fn load_ripper() -> Result<(), ()> {
         "../ruby_checkout/ext/ripper/lib/ripper/sexp.rb"
     ))?;
 
-    rb_gc_disable();
     Ok(())
 }
 
 pub fn toplevel_format_program<W: Write>(
     writer: &mut W,
-    tree: RipperTree,
+    tree: &RipperTree,
     file_comments: FileComments,
     end_data: Option<&str>,
-) -> Result<(), RichFormatError> {
-    let mut ps = BaseParserState::new(file_comments);
+    options: &FormatOptions,
+) -> Result<Vec<String>, RichFormatError> {
+    let mut ps = BaseParserState::new(file_comments, options.clone());
     let v: ripper_tree_types::Program =
         de::from_value(tree).map_err(RichFormatError::RipperParseFailure)?;
 
     format::format_program(&mut ps, v, end_data);
 
-    ps.write(writer).map_err(RichFormatError::IOError)?;
+    let warnings = ps.warnings().to_vec();
+
+    if ps.is_unformattable() {
+        writer
+            .write_all(ps.original_source().as_bytes())
+            .map_err(RichFormatError::IOError)?;
+    } else {
+        ps.write(writer).map_err(RichFormatError::IOError)?;
+    }
     writer.flush().map_err(RichFormatError::IOError)?;
-    Ok(())
+    Ok(warnings)
+}
+
+fn run_parser_on(
+    buf: &str,
+    line_ranges: &[RangeInclusive<usize>],
+) -> Result<(RipperTree, FileComments, Option<String>), RichFormatError> {
+    Parser::new(buf, line_ranges)
+        .parse()
+        .map_err(rich_parse_error)
 }
 
-fn run_parser_on(buf: &str) -> Result<(RipperTree, FileComments, Option<&str>), RichFormatError> {
-    Parser::new(buf).parse().map_err(|e| match e {
-        ParseError::SyntaxError => RichFormatError::SyntaxError,
+fn rich_parse_error(e: ParseError) -> RichFormatError {
+    match e {
+        ParseError::SyntaxError {
+            line,
+            column,
+            message,
+        } => RichFormatError::SyntaxError {
+            line,
+            column,
+            message,
+        },
         ParseError::OtherRubyError(s) => RichFormatError::OtherRubyError(s),
-    })
+    }
 }
 
 fn init_logger() {
@@ -236,3 +1135,26 @@ fn init_logger() {
         debug!("logger works");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_map_keeps_unchanged_lines() {
+        assert_eq!(line_map("a\nb\n", "a\nb\n"), vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn line_map_drops_rewritten_lines() {
+        assert_eq!(line_map("a( 1 )\nb\n", "a(1)\nb\n"), vec![None, Some(2)]);
+    }
+
+    #[test]
+    fn line_map_drops_inserted_lines() {
+        assert_eq!(
+            line_map("class A\n  a\nend\nb\n", "class A\n  a\nend\n\nb\n"),
+            vec![Some(1), Some(2), Some(3), None, Some(4)]
+        );
+    }
+}
@@ -152,6 +152,26 @@ impl ConcreteLineToken {
         }
     }
 
+    /// The number of newlines in the token's string representation, which
+    /// like `len` doesn't render the token to count them
+    pub fn newline_count(&self) -> usize {
+        use ConcreteLineToken::*;
+        match self {
+            HardNewLine => 1,
+            Keyword { keyword: contents }
+            | Op { op: contents }
+            | DirectPart { part: contents }
+            | LTStringContent { content: contents }
+            | Comment { contents }
+            | Delim { contents }
+            | ConditionalKeyword { contents }
+            | HeredocClose { symbol: contents }
+            | ModKeyword { contents } => contents.matches('\n').count(),
+            HeredocStart { symbol, .. } => symbol.matches('\n').count(),
+            _ => 0,
+        }
+    }
+
     fn is_block_closing_token(&self) -> bool {
         match self {
             Self::End => true,
@@ -343,6 +363,7 @@ impl AbstractLineToken {
         if let Some(values) = heredoc_strings {
             for hds in values {
                 let indent = hds.indent;
+                let indent_width = hds.indent_width;
                 let kind = hds.kind;
                 let symbol = hds.closing_symbol();
 
@@ -356,8 +377,7 @@ impl AbstractLineToken {
                 }
                 res.push(clats_heredoc_close(symbol));
                 res.push(cltats_hard_newline());
-                let indent_depth = if indent != 0 { indent - 2 } else { indent };
-                res.push(clats_indent(indent_depth));
+                res.push(clats_indent(indent.saturating_sub(indent_width)));
             }
         }
         res
@@ -0,0 +1,85 @@
+//! Turning a panic while formatting a file into an error for that file, so
+//! that one file rubyfmt can't handle doesn't stop a whole run.
+//!
+//! Formatting only touches the Ruby VM through `rb_protect`ed calls, which
+//! never leave the VM part way through an operation, so a panic in the Rust
+//! code shouldn't affect it. It's checked after every panic anyway, and if it
+//! stops responding every later file fails rather than being formatted by a
+//! broken VM.
+
+use crate::ruby::{eval_str, rubyfmt_rb_nil_p};
+use backtrace::Backtrace;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe, Location};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+static INSTALL_HOOK: Once = Once::new();
+static RUBY_VM_UNUSABLE: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Whether this thread is inside `catch_panic`
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    // The message and backtrace of the last panic caught on this thread
+    static CAUGHT_PANIC: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, returning the message and backtrace of any panic instead of
+/// letting it unwind further. Panics outside of `catch_panic` are reported by
+/// the panic hook that was installed before as usual.
+pub fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, (String, String)> {
+    INSTALL_HOOK.call_once(|| {
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING.with(Cell::get) {
+                let message = panic_message(info.payload(), info.location());
+                let backtrace = format!("{:?}", Backtrace::new());
+                CAUGHT_PANIC.with(|caught| *caught.borrow_mut() = Some((message, backtrace)));
+            } else {
+                previous_hook(info)
+            }
+        }));
+    });
+
+    let was_catching = CATCHING.with(|catching| catching.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.with(|catching| catching.set(was_catching));
+
+    result.map_err(|_| {
+        if !ruby_vm_responds() {
+            RUBY_VM_UNUSABLE.store(true, Ordering::SeqCst);
+        }
+        CAUGHT_PANIC
+            .with(|caught| caught.borrow_mut().take())
+            .unwrap_or_else(|| ("rubyfmt panicked".to_string(), String::new()))
+    })
+}
+
+/// Whether an earlier panic left the Ruby VM unable to format anything else
+pub fn ruby_vm_unusable() -> bool {
+    RUBY_VM_UNUSABLE.load(Ordering::SeqCst)
+}
+
+// Whether the VM can still parse something, which is what formatting needs
+fn ruby_vm_responds() -> bool {
+    unsafe {
+        match eval_str("Parser.new(\"1\", []).parse") {
+            Ok(tree) => rubyfmt_rb_nil_p(tree) == 0,
+            Err(()) => false,
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send), location: Option<&Location>) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "rubyfmt panicked".to_string());
+
+    match location {
+        Some(location) => format!("{} at {}:{}", message, location.file(), location.line()),
+        None => message,
+    }
+}
@@ -2,9 +2,10 @@ use crate::comment_block::{CommentBlock, Merge};
 use crate::delimiters::BreakableDelims;
 use crate::file_comments::FileComments;
 use crate::format::{format_inner_string, StringType};
+use crate::format_options::FormatOptions;
 use crate::heredoc_string::{HeredocKind, HeredocString};
 use crate::line_tokens::*;
-use crate::render_queue_writer::{RenderQueueWriter, MAX_LINE_LENGTH};
+use crate::render_queue_writer::{BreakDecision, RenderQueueWriter};
 use crate::render_targets::{
     AbstractTokenTarget, BaseQueue, BreakableCallChainEntry, BreakableEntry,
 };
@@ -98,6 +99,7 @@ where
     fn emit_data_end(&mut self);
     fn emit_data(&mut self, data: &str);
     fn emit_single_line_delims(&mut self, delims: BreakableDelims);
+    fn emit_verbatim_lines(&mut self, start_line: LineNumber, end_line: LineNumber);
 
     // other state changers
     fn bind_variable(&mut self, s: String);
@@ -109,6 +111,8 @@ where
     fn shift_comments(&mut self);
     fn shift_comments_at_index(&mut self, index: usize);
     fn wind_line_forward(&mut self);
+    fn push_warning(&mut self, warning: String);
+    fn mark_unformattable(&mut self);
     fn render_heredocs(&mut self, skip: bool);
     fn push_heredoc_content(
         &mut self,
@@ -125,6 +129,16 @@ where
     fn is_absorbing_indents(&self) -> bool;
     fn has_comments_in_line(&self, start_line: LineNumber, end_line: LineNumber) -> bool;
     fn current_line_number(&self) -> u64;
+    fn indent_width(&self) -> ColNumber;
+    fn options(&self) -> &FormatOptions;
+    fn disabled_region_containing(
+        &self,
+        line_number: LineNumber,
+    ) -> Option<(LineNumber, LineNumber)>;
+    fn lone_statement_starting_on(
+        &self,
+        line_number: LineNumber,
+    ) -> Option<(LineNumber, LineNumber)>;
 
     // blocks
     fn start_indent(&mut self);
@@ -177,6 +191,9 @@ pub struct BaseParserState {
     insert_user_newlines: bool,
     spaces_after_last_newline: ColNumber,
     scopes: Vec<Vec<String>>,
+    warnings: Vec<String>,
+    is_unformattable: bool,
+    options: FormatOptions,
 }
 
 impl ConcreteParserState for BaseParserState {
@@ -224,6 +241,7 @@ impl ConcreteParserState for BaseParserState {
             kind,
             data,
             self.current_spaces(),
+            self.indent_width(),
         ));
     }
 
@@ -279,7 +297,7 @@ impl ConcreteParserState for BaseParserState {
         let data = next_ps.render_to_buffer();
 
         let s = str::from_utf8(&data).expect("string is utf8").to_string();
-        s.trim().contains('\n') || s.len() > MAX_LINE_LENGTH
+        s.trim().contains('\n') || s.len() > self.options.line_length
     }
 
     fn will_render_beyond_max_line_length<'a>(&mut self, f: RenderFunc) -> bool {
@@ -292,7 +310,7 @@ impl ConcreteParserState for BaseParserState {
 
         // Add current spaces to account for current indentation level
         (s.split_whitespace().collect::<String>().len() + (self.current_spaces() as usize))
-            > MAX_LINE_LENGTH
+            > self.options.line_length
     }
 
     fn reset_space_count(&mut self) {
@@ -498,6 +516,28 @@ impl ConcreteParserState for BaseParserState {
         self.current_orig_line_number
     }
 
+    fn indent_width(&self) -> ColNumber {
+        self.options.indent_width as ColNumber
+    }
+
+    fn options(&self) -> &FormatOptions {
+        &self.options
+    }
+
+    fn disabled_region_containing(
+        &self,
+        line_number: LineNumber,
+    ) -> Option<(LineNumber, LineNumber)> {
+        self.comments_hash.disabled_region_containing(line_number)
+    }
+
+    fn lone_statement_starting_on(
+        &self,
+        line_number: LineNumber,
+    ) -> Option<(LineNumber, LineNumber)> {
+        self.comments_hash.lone_statement_starting_on(line_number)
+    }
+
     fn emit_def(&mut self, def_name: String) {
         self.emit_def_keyword();
         self.push_concrete_token(ConcreteLineToken::DirectPart {
@@ -758,6 +798,27 @@ impl ConcreteParserState for BaseParserState {
         self.on_line(self.current_orig_line_number + 1);
     }
 
+    fn emit_verbatim_lines(&mut self, start_line: LineNumber, end_line: LineNumber) {
+        // Any comments in these lines get copied along with them
+        self.comments_hash
+            .remove_comments_in_lines(start_line, end_line);
+        self.on_line(start_line);
+
+        let source = self.comments_hash.source_for_lines(start_line, end_line);
+        self.push_concrete_token(ConcreteLineToken::DirectPart {
+            part: source.trim_end_matches(&['\n', '\r'][..]).to_string(),
+        });
+        self.current_orig_line_number = end_line;
+    }
+
+    fn push_warning(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    fn mark_unformattable(&mut self) {
+        self.is_unformattable = true;
+    }
+
     fn current_formatting_context_requires_parens(&self) -> bool {
         self.current_formatting_context() == FormattingContext::Binary
             || self.current_formatting_context() == FormattingContext::IfOp
@@ -845,7 +906,7 @@ impl ConcreteParserState for BaseParserState {
 }
 
 impl BaseParserState {
-    pub fn new(fc: FileComments) -> Self {
+    pub fn new(fc: FileComments, options: FormatOptions) -> Self {
         BaseParserState {
             depth_stack: vec![IndentDepth::new()],
             start_of_line: vec![true],
@@ -861,10 +922,27 @@ impl BaseParserState {
             insert_user_newlines: true,
             spaces_after_last_newline: 0,
             scopes: vec![vec![]],
+            warnings: vec![],
+            is_unformattable: false,
+            options,
         }
     }
 
-    fn consume_to_render_queue(self) -> Vec<ConcreteLineTokenAndTargets> {
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Whether we found something that we can neither format nor copy through
+    /// on its own, in which case the file must be left as-is
+    pub fn is_unformattable(&self) -> bool {
+        self.is_unformattable
+    }
+
+    pub fn original_source(&self) -> String {
+        self.comments_hash.source_for_lines(1, LineNumber::MAX)
+    }
+
+    pub fn consume_to_render_queue(self) -> Vec<ConcreteLineTokenAndTargets> {
         self.render_queue.into_tokens()
     }
 
@@ -917,11 +995,12 @@ impl BaseParserState {
     }
 
     fn current_spaces(&self) -> ColNumber {
-        2 * self
-            .depth_stack
-            .last()
-            .expect("depth stack is never empty")
-            .get()
+        self.indent_width()
+            * self
+                .depth_stack
+                .last()
+                .expect("depth stack is never empty")
+                .get()
     }
 
     fn last_token_is_a_newline(&self) -> bool {
@@ -947,7 +1026,7 @@ impl BaseParserState {
     // Creates a copy of the parser state *with the depth_stack reset*.
     // This is used for heredocs, where we explicitly want to ignore current indentation.
     fn new_with_reset_depth_stack(ps: &BaseParserState) -> Self {
-        let mut next_ps = BaseParserState::new(FileComments::default());
+        let mut next_ps = BaseParserState::new(FileComments::default(), ps.options.clone());
         next_ps.comments_hash = ps.comments_hash.clone();
         next_ps.start_of_line = ps.start_of_line.clone();
         next_ps.current_orig_line_number = ps.current_orig_line_number;
@@ -962,10 +1041,23 @@ impl BaseParserState {
     }
 
     pub fn write<W: Write>(self, writer: &mut W) -> io::Result<()> {
-        let rqw = RenderQueueWriter::new(self.consume_to_render_queue());
+        let options = self.options.clone();
+        let rqw = RenderQueueWriter::new(self.consume_to_render_queue(), options);
         rqw.write(writer)
     }
 
+    /// The tokens that `write` would render, for `DumpStage::FinalTokens`
+    pub fn into_final_tokens(self) -> Vec<ConcreteLineToken> {
+        let options = self.options.clone();
+        RenderQueueWriter::new(self.consume_to_render_queue(), options).into_final_tokens()
+    }
+
+    /// Every decision about whether to break up a breakable, for `--explain`
+    pub fn into_break_decisions(self) -> Vec<BreakDecision> {
+        let options = self.options.clone();
+        RenderQueueWriter::new(self.consume_to_render_queue(), options).into_break_decisions()
+    }
+
     fn dangerously_convert(t: AbstractLineToken) -> ConcreteLineTokenAndTargets {
         match t {
             AbstractLineToken::ConcreteLineToken(clt) => {
@@ -1,39 +1,137 @@
+use crate::format_options::FormatOptions;
+use crate::formatter::stop_if_cancelled;
 use crate::heredoc_string::HeredocKind;
 use crate::intermediary::{BlanklineReason, Intermediary};
 use crate::line_tokens::*;
+use crate::parser_state::FormattingContext;
 use crate::render_targets::{
-    AbstractTokenTarget, BreakableCallChainEntry, BreakableEntry, ConvertType,
+    AbstractTokenTarget, BreakableCallChainEntry, BreakableEntry, ConvertType, MultilineReason,
 };
+use crate::types::ColNumber;
 #[cfg(debug_assertions)]
 use log::debug;
+use std::fmt;
 use std::io::{self, Write};
-
-pub const MAX_LINE_LENGTH: usize = 120;
+use std::ops::RangeInclusive;
 
 pub struct RenderQueueWriter {
     tokens: Vec<ConcreteLineTokenAndTargets>,
+    options: FormatOptions,
+    // Every decision about whether to break up a breakable, when explaining them
+    decisions: Option<Vec<BreakDecision>>,
+}
+
+/// Whether a `BreakableEntry` or `BreakableCallChainEntry` was rendered on
+/// one line or across several, and why
+#[derive(Debug)]
+pub struct BreakDecision {
+    target: &'static str,
+    // The lines of the output, counting from 1, that it was rendered on
+    output_lines: RangeInclusive<usize>,
+    single_line_string_length: usize,
+    line_length: usize,
+    multiline: bool,
+    reasons: Vec<BreakReason>,
+    context: Vec<FormattingContext>,
+}
+
+#[derive(Debug)]
+enum BreakReason {
+    TooLong,
+    Multiline(MultilineReason),
+    HeredocInStringEmbexpr,
+    InStringEmbexpr,
+    Fits,
+}
+
+impl BreakDecision {
+    pub fn affects_line(&self, line: usize) -> bool {
+        self.output_lines.contains(&line)
+    }
+}
+
+impl fmt::Display for BreakDecision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (start, end) = (self.output_lines.start(), self.output_lines.end());
+        if start == end {
+            writeln!(f, "{} on line {}", self.target, start)?;
+        } else {
+            writeln!(f, "{} on lines {} to {}", self.target, start, end)?;
+        }
+        writeln!(
+            f,
+            "  rendered {}",
+            if self.multiline {
+                "across multiple lines"
+            } else {
+                "on one line"
+            }
+        )?;
+        writeln!(
+            f,
+            "  single_line_string_length: {} (line length {})",
+            self.single_line_string_length, self.line_length
+        )?;
+        for reason in &self.reasons {
+            let reason = match reason {
+                BreakReason::TooLong => "it's longer than the line length on one line".to_string(),
+                BreakReason::Multiline(reason) => format!("it's multiline because {}", reason),
+                BreakReason::HeredocInStringEmbexpr => {
+                    "it's inside a string interpolation, but contains a heredoc".to_string()
+                }
+                BreakReason::InStringEmbexpr => {
+                    "it's inside a string interpolation, which is kept on one line".to_string()
+                }
+                BreakReason::Fits => "it fits within the line length".to_string(),
+            };
+            writeln!(f, "  because {}", reason)?;
+        }
+        writeln!(f, "  context: {:?}", self.context)
+    }
 }
 
 impl RenderQueueWriter {
-    pub fn new(tokens: Vec<ConcreteLineTokenAndTargets>) -> Self {
-        RenderQueueWriter { tokens }
+    pub fn new(tokens: Vec<ConcreteLineTokenAndTargets>, options: FormatOptions) -> Self {
+        RenderQueueWriter {
+            tokens,
+            options,
+            decisions: None,
+        }
     }
 
     pub fn write<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        Self::write_final_tokens(writer, self.into_final_tokens())
+    }
+
+    /// Renders the queue into the tokens that are written out, with every
+    /// breakable entry either broken up or kept on one line
+    pub fn into_final_tokens(mut self) -> Vec<ConcreteLineToken> {
         let mut accum = Intermediary::new();
         #[cfg(debug_assertions)]
         {
             debug!("first tokens {:?}", self.tokens);
         }
-        Self::render_as(&mut accum, self.tokens);
-        Self::write_final_tokens(writer, accum.into_tokens())
+        let tokens = std::mem::take(&mut self.tokens);
+        self.render_as(&mut accum, tokens);
+        accum.into_tokens()
+    }
+
+    /// Renders the queue, returning each decision about whether to break up
+    /// a breakable in the order they were made, outermost first
+    pub fn into_break_decisions(mut self) -> Vec<BreakDecision> {
+        let mut accum = Intermediary::new();
+        let tokens = std::mem::take(&mut self.tokens);
+        self.decisions = Some(Vec::new());
+        self.render_as(&mut accum, tokens);
+        self.decisions.unwrap_or_default()
     }
 
-    fn render_as(accum: &mut Intermediary, tokens: Vec<ConcreteLineTokenAndTargets>) {
+    fn render_as(&mut self, accum: &mut Intermediary, tokens: Vec<ConcreteLineTokenAndTargets>) {
         use ConcreteLineToken::*;
         let token_len = tokens.len();
         let mut peekable = tokens.into_iter().enumerate().peekable();
         let mut current_heredoc_kind: Option<HeredocKind> = None;
+        let indent_width = self.options.indent_width as ColNumber;
 
         while let Some((index, mut next_token)) = peekable.next() {
             // Do any additional indentation changes caused by call chain rendering
@@ -48,15 +146,17 @@ impl RenderQueueWriter {
                             ))
                         );
                     if !is_ending_heredoc_token {
-                        next_token = clats_indent(depth + (accum.additional_indent * 2))
+                        next_token = clats_indent(depth + (accum.additional_indent * indent_width))
                     }
                 }
                 ConcreteLineTokenAndTargets::ConcreteLineToken(ConcreteLineToken::Comment {
                     contents,
                 }) => {
                     if !contents.is_empty() {
-                        let mut new_contents: String =
-                            (0..(accum.additional_indent * 2)).map(|_| ' ').collect();
+                        let mut new_contents: String = (0..(accum.additional_indent
+                            * indent_width))
+                            .map(|_| ' ')
+                            .collect();
                         new_contents.push_str(contents.as_str());
                         next_token = ConcreteLineTokenAndTargets::ConcreteLineToken(
                             ConcreteLineToken::Comment {
@@ -72,8 +172,9 @@ impl RenderQueueWriter {
                         .map(|k| k.is_squiggly())
                         .unwrap_or(false)
                     {
-                        let indent: String =
-                            (0..(accum.additional_indent * 2)).map(|_| ' ').collect();
+                        let indent: String = (0..(accum.additional_indent * indent_width))
+                            .map(|_| ' ')
+                            .collect();
                         let new_contents = part
                             .split('\n')
                             .map(|p| {
@@ -98,8 +199,10 @@ impl RenderQueueWriter {
                     // Bare heredocs (e.g. <<FOO) must have the closing ident completely unindented, so
                     // ignore them in this case
                     if current_heredoc_kind.map(|k| !k.is_bare()).unwrap_or(false) {
-                        let mut new_contents: String =
-                            (0..(accum.additional_indent * 2)).map(|_| ' ').collect();
+                        let mut new_contents: String = (0..(accum.additional_indent
+                            * indent_width))
+                            .map(|_| ' ')
+                            .collect();
                         new_contents.push_str(symbol.as_str());
                         next_token = clats_heredoc_close(new_contents);
                     }
@@ -110,10 +213,10 @@ impl RenderQueueWriter {
 
             match next_token {
                 ConcreteLineTokenAndTargets::BreakableEntry(be) => {
-                    Self::format_breakable_entry(accum, be)
+                    self.format_breakable_entry(accum, be)
                 }
                 ConcreteLineTokenAndTargets::BreakableCallChainEntry(bcce) => {
-                    Self::format_breakable_call_chain_entry(accum, bcce)
+                    self.format_breakable_call_chain_entry(accum, bcce)
                 }
                 ConcreteLineTokenAndTargets::ConcreteLineToken(x) => match x {
                     BeginCallChainIndent => accum.additional_indent += 1,
@@ -193,41 +296,142 @@ impl RenderQueueWriter {
         }
     }
 
-    fn format_breakable_entry(accum: &mut Intermediary, be: BreakableEntry) {
+    fn format_breakable_entry(&mut self, accum: &mut Intermediary, be: BreakableEntry) {
+        stop_if_cancelled();
         let length = be.single_line_string_length(accum.current_line_length());
         // We generally will force expressions embedded in strings to be on a single line,
         // but if that expression has a heredoc nested in it, we should let it render across lines
         // so that the collapsing newlines render properly.
         let force_single_line =
             !be.any_collapsing_newline_has_heredoc_content() && be.in_string_embexpr();
+        let multiline =
+            !force_single_line && (length > self.options.line_length || be.is_multiline());
+
+        let decision = if self.decisions.is_some() {
+            let reasons = if force_single_line {
+                vec![BreakReason::InStringEmbexpr]
+            } else {
+                self.length_and_multiline_reasons(length, &be)
+            };
+            self.begin_decision(
+                accum,
+                "BreakableEntry",
+                length,
+                multiline,
+                reasons,
+                be.context(),
+            )
+        } else {
+            None
+        };
 
-        if !force_single_line && (length > MAX_LINE_LENGTH || be.is_multiline()) {
-            Self::render_as(accum, be.into_tokens(ConvertType::MultiLine));
+        if multiline {
+            self.render_as(accum, be.into_tokens(ConvertType::MultiLine));
         } else {
-            Self::render_as(accum, be.into_tokens(ConvertType::SingleLine));
+            self.render_as(accum, be.into_tokens(ConvertType::SingleLine));
             // after running accum looks like this (or some variant):
             // [.., Comma, Space, DirectPart {part: ""}, <close_delimiter>]
             // so we remove items at positions length-2 until there is nothing
             // in that position that is garbage.
             accum.clear_breakable_garbage();
         }
+        self.end_decision(accum, decision);
     }
 
     fn format_breakable_call_chain_entry(
+        &mut self,
         accum: &mut Intermediary,
         mut bcce: BreakableCallChainEntry,
     ) {
+        stop_if_cancelled();
         let length = bcce.single_line_string_length(accum.current_line_length());
         let must_multiline =
             bcce.any_collapsing_newline_has_heredoc_content() && bcce.in_string_embexpr();
-        if must_multiline
-            || ((length > MAX_LINE_LENGTH || bcce.is_multiline()) && !bcce.in_string_embexpr())
-        {
+        let multiline = must_multiline
+            || ((length > self.options.line_length || bcce.is_multiline())
+                && !bcce.in_string_embexpr());
+
+        let decision = if self.decisions.is_some() {
+            let reasons = if must_multiline {
+                vec![BreakReason::HeredocInStringEmbexpr]
+            } else if bcce.in_string_embexpr()
+                && (length > self.options.line_length || bcce.is_multiline())
+            {
+                vec![BreakReason::InStringEmbexpr]
+            } else {
+                self.length_and_multiline_reasons(length, &bcce)
+            };
+            self.begin_decision(
+                accum,
+                "BreakableCallChainEntry",
+                length,
+                multiline,
+                reasons,
+                bcce.context(),
+            )
+        } else {
+            None
+        };
+
+        if multiline {
             let tokens = bcce.into_tokens(ConvertType::MultiLine);
-            Self::render_as(accum, tokens);
+            self.render_as(accum, tokens);
         } else {
             bcce.remove_call_chain_magic_tokens();
-            Self::render_as(accum, bcce.into_tokens(ConvertType::SingleLine));
+            self.render_as(accum, bcce.into_tokens(ConvertType::SingleLine));
+        }
+        self.end_decision(accum, decision);
+    }
+
+    fn length_and_multiline_reasons(
+        &self,
+        length: usize,
+        target: &dyn AbstractTokenTarget,
+    ) -> Vec<BreakReason> {
+        let mut reasons = Vec::new();
+        if length > self.options.line_length {
+            reasons.push(BreakReason::TooLong);
+        }
+        if let Some(reason) = target.multiline_reason() {
+            reasons.push(BreakReason::Multiline(reason));
+        }
+        if reasons.is_empty() {
+            reasons.push(BreakReason::Fits);
+        }
+        reasons
+    }
+
+    // Records a decision that's about to be rendered when decisions are being
+    // explained, returning its index so that `end_decision` can fill in the
+    // last line it was rendered on
+    fn begin_decision(
+        &mut self,
+        accum: &Intermediary,
+        target: &'static str,
+        single_line_string_length: usize,
+        multiline: bool,
+        reasons: Vec<BreakReason>,
+        context: &[FormattingContext],
+    ) -> Option<usize> {
+        let line_length = self.options.line_length;
+        let decisions = self.decisions.as_mut()?;
+        let start_line = accum.current_line_number();
+        decisions.push(BreakDecision {
+            target,
+            output_lines: start_line..=start_line,
+            single_line_string_length,
+            line_length,
+            multiline,
+            reasons,
+            context: context.to_vec(),
+        });
+        Some(decisions.len() - 1)
+    }
+
+    fn end_decision(&mut self, accum: &Intermediary, decision: Option<usize>) {
+        if let (Some(decisions), Some(index)) = (self.decisions.as_mut(), decision) {
+            let decision = &mut decisions[index];
+            decision.output_lines = *decision.output_lines.start()..=accum.current_line_number();
         }
     }
 
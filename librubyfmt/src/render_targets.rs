@@ -4,6 +4,7 @@ use crate::parser_state::FormattingContext;
 use crate::ripper_tree_types::{CallChainElement, Expression, StringLiteral};
 use crate::types::LineNumber;
 use std::collections::HashSet;
+use std::fmt;
 
 fn insert_at<T>(idx: usize, target: &mut Vec<T>, input: &mut Vec<T>) {
     let mut tail = target.split_off(idx);
@@ -46,11 +47,42 @@ impl BaseQueue {
     }
 }
 
+/// Why a breakable has to be rendered across multiple lines, whatever its length
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultilineReason {
+    /// The original code had it across these lines
+    UserNewlines(Vec<LineNumber>),
+    /// It contains a heredoc, whose content has to start on the next line
+    HeredocContent,
+    /// It contains a newline that can't be collapsed, e.g. after a comment
+    HardNewline,
+    /// It's a call chain on a heredoc
+    BeginsWithHeredoc,
+}
+
+impl fmt::Display for MultilineReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultilineReason::UserNewlines(lines) => {
+                let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+                write!(
+                    f,
+                    "the original code had it across lines {}",
+                    lines.join(", ")
+                )
+            }
+            MultilineReason::HeredocContent => write!(f, "it contains a heredoc"),
+            MultilineReason::HardNewline => write!(f, "it contains a comment or hard newline"),
+            MultilineReason::BeginsWithHeredoc => write!(f, "the call chain starts with a heredoc"),
+        }
+    }
+}
+
 pub trait AbstractTokenTarget: std::fmt::Debug {
     fn push(&mut self, lt: AbstractLineToken);
     fn insert_at(&mut self, idx: usize, tokens: &mut Vec<AbstractLineToken>);
     fn into_tokens(self, ct: ConvertType) -> Vec<ConcreteLineTokenAndTargets>;
-    fn is_multiline(&self) -> bool;
+    fn multiline_reason(&self) -> Option<MultilineReason>;
     fn push_line_number(&mut self, number: LineNumber);
     fn single_line_string_length(&self, current_line_length: usize) -> usize;
     fn to_breakable_entry(self: Box<Self>) -> Option<BreakableEntry>;
@@ -62,6 +94,10 @@ pub trait AbstractTokenTarget: std::fmt::Debug {
         self.tokens().len()
     }
 
+    fn is_multiline(&self) -> bool {
+        self.multiline_reason().is_some()
+    }
+
     fn index_of_prev_newline(&self) -> Option<usize> {
         let first_idx = self
             .tokens()
@@ -153,10 +189,18 @@ impl AbstractTokenTarget for BreakableEntry {
         self.line_numbers.insert(number);
     }
 
-    fn is_multiline(&self) -> bool {
-        self.line_numbers.len() > 1
-            || self.any_collapsing_newline_has_heredoc_content()
-            || self.contains_hard_newline()
+    fn multiline_reason(&self) -> Option<MultilineReason> {
+        if self.line_numbers.len() > 1 {
+            let mut lines: Vec<_> = self.line_numbers.iter().copied().collect();
+            lines.sort_unstable();
+            Some(MultilineReason::UserNewlines(lines))
+        } else if self.any_collapsing_newline_has_heredoc_content() {
+            Some(MultilineReason::HeredocContent)
+        } else if self.contains_hard_newline() {
+            Some(MultilineReason::HardNewline)
+        } else {
+            None
+        }
     }
 
     fn tokens(&self) -> &Vec<AbstractLineToken> {
@@ -191,6 +235,10 @@ impl BreakableEntry {
             .any(|fc| fc == &FormattingContext::StringEmbexpr)
     }
 
+    pub fn context(&self) -> &[FormattingContext] {
+        &self.context
+    }
+
     fn contains_hard_newline(&self) -> bool {
         self.tokens.iter().any(|t| {
             matches!(
@@ -307,9 +355,9 @@ impl AbstractTokenTarget for BreakableCallChainEntry {
         self.line_numbers.insert(number);
     }
 
-    fn is_multiline(&self) -> bool {
+    fn multiline_reason(&self) -> Option<MultilineReason> {
         if self.begins_with_heredoc() {
-            return true;
+            return Some(MultilineReason::BeginsWithHeredoc);
         }
 
         let mut call_chain_to_check = self.call_chain.as_slice();
@@ -346,18 +394,20 @@ impl AbstractTokenTarget for BreakableCallChainEntry {
             call_chain_to_check = &call_chain_to_check[1..];
         }
 
-        let chain_is_user_multilined = call_chain_to_check
+        let mut chain_lines: Vec<_> = call_chain_to_check
             .iter()
             .filter_map(|cc_elem| cc_elem.start_line())
             .collect::<HashSet<_>>()
-            .len()
-            > 1;
+            .into_iter()
+            .collect();
 
+        let chain_is_user_multilined = chain_lines.len() > 1;
         if chain_is_user_multilined {
-            return true;
+            chain_lines.sort_unstable();
+            return Some(MultilineReason::UserNewlines(chain_lines));
         }
 
-        false
+        None
     }
 
     fn any_collapsing_newline_has_heredoc_content(&self) -> bool {
@@ -413,6 +463,10 @@ impl BreakableCallChainEntry {
             .any(|fc| fc == &FormattingContext::StringEmbexpr)
     }
 
+    pub fn context(&self) -> &[FormattingContext] {
+        &self.context
+    }
+
     fn begins_with_heredoc(&self) -> bool {
         if let Some(CallChainElement::Expression(expr)) = self.call_chain.first() {
             if let Expression::StringLiteral(string_literal) = &**expr {
@@ -5,7 +5,8 @@ use log::debug;
 use ripper_deserialize::RipperDeserialize;
 use serde::*;
 
-use crate::types::LineNumber;
+use crate::ruby::VALUE;
+use crate::types::{ColNumber, LineNumber};
 
 fn ident_as_cc(i: String, start_end: &StartEnd) -> CallChainElement {
     CallChainElement::IdentOrOpOrKeywordOrConst(IdentOrOpOrKeywordOrConst::Ident(Ident::new(
@@ -143,6 +144,7 @@ pub enum Expression {
     IfMod(IfMod),
     UnlessMod(UnlessMod),
     Case(Case),
+    RAssign(RAssign),
     Retry(Retry),
     Redo(Redo),
     SClass(SClass),
@@ -158,6 +160,9 @@ pub enum Expression {
     Unless(Unless),
     ZSuper(ZSuper),
     Yield0(Yield0),
+    Verbatim(Verbatim),
+    // This must stay last, since it matches any statement
+    Unsupported(Unsupported),
 }
 
 impl Expression {
@@ -237,9 +242,12 @@ impl Expression {
             | Expression::UnlessMod(UnlessMod(_, expr, ..))
             | Expression::Until(Until(_, expr, ..))
             | Expression::For(For(_, _, expr, _))
+            | Expression::RAssign(RAssign(_, expr, ..))
             | Expression::IfOp(IfOp(_, expr, ..)) => expr.start_line(),
             // Miscellaneous expressions with special cases
             Expression::VoidStmt(..) => None,
+            Expression::Unsupported(Unsupported(_, span)) => Some(span.1),
+            Expression::Verbatim(Verbatim(_, span, ..)) => Some(span.1),
             Expression::Paren(ParenExpr(.., paren_expr, _)) => paren_expr.start_line(),
             Expression::MLhs(MLhs(mlhs_inners)) => {
                 mlhs_inners.first().and_then(|mlhs| mlhs.start_line())
@@ -359,6 +367,56 @@ impl<'de> Deserialize<'de> for MLhs {
     }
 }
 
+def_tag!(statement_span_tag, "_rubyfmt_span");
+/// The lines a statement spans, and whether it's the only code on those lines.
+/// `rubyfmt_lib.rb` only includes these in nodes for statements that get
+/// copied through as-is.
+#[derive(Deserialize, Debug, Clone)]
+pub struct StatementSpan(statement_span_tag, pub LineNumber, pub LineNumber, pub bool);
+
+def_tag!(verbatim_tag, "_rubyfmt_verbatim");
+/// One or more neighbouring statements that formatting has been disabled for
+/// with `# rubyfmt:disable` or `# rubyfmt:disable-next`, or that are outside
+/// the lines being formatted. The `bool` is whether the first statement is
+/// the first code on its line.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Verbatim(
+    verbatim_tag,
+    pub StatementSpan,
+    pub bool,
+    pub Vec<VerbatimHole>,
+);
+
+/// A statement inside a verbatim statement that's formatted anyway, because
+/// it encloses some of the lines being formatted, with how many statements
+/// it's nested in.
+#[derive(Deserialize, Debug, Clone)]
+pub struct VerbatimHole(pub ColNumber, pub StatementSpan, pub Expression);
+
+/// A statement that rubyfmt can't deserialize, e.g. `alias $new $old`, or
+/// syntax that's newer than rubyfmt. The `String` is the type of the ripper
+/// node, and the span comes from the statement spans `rubyfmt_lib.rb` keeps.
+#[derive(Debug, Clone)]
+pub struct Unsupported(pub String, pub StatementSpan);
+
+impl<'de> Deserialize<'de> for Unsupported {
+    fn deserialize<D>(deserializer: D) -> Result<Unsupported, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = VALUE::deserialize(deserializer)?;
+        // Only whole statements can be copied through, so anything else
+        // that doesn't deserialize fails the statement it's in instead
+        let span =
+            crate::de::statement_span(value).ok_or_else(|| de::Error::custom("not a statement"))?;
+        let span =
+            StatementSpan::deserialize(span.into_deserializer()).map_err(de::Error::custom)?;
+        let (node_type,): (String,) =
+            Deserialize::deserialize(value.into_deserializer()).map_err(de::Error::custom)?;
+        Ok(Unsupported(node_type, span))
+    }
+}
+
 def_tag!(zsuper_tag, "zsuper");
 #[derive(Deserialize, Debug, Clone)]
 pub struct ZSuper(pub zsuper_tag, pub StartEnd);
@@ -2340,10 +2398,16 @@ def_tag!(case_tag, "case");
 pub struct Case(
     case_tag,
     pub Option<Box<Expression>>,
-    pub When,
+    pub WhenOrIn,
     pub StartEnd,
 );
 
+#[derive(RipperDeserialize, Debug, Clone)]
+pub enum WhenOrIn {
+    When(When),
+    In(In),
+}
+
 def_tag!(when_tag, "when");
 #[derive(Deserialize, Debug, Clone)]
 pub struct When(
@@ -2360,10 +2424,154 @@ pub enum WhenOrElse {
     Else(CaseElse),
 }
 
+def_tag!(rassign_tag, "rassign");
+/// One-line pattern matches, i.e. `expr => pattern` and `expr in pattern`.
+/// The `String` is whichever of those two operators was used.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RAssign(rassign_tag, pub Box<Expression>, pub String, pub Pattern);
+
 def_tag!(case_else_tag, "else");
 #[derive(Deserialize, Debug, Clone)]
 pub struct CaseElse(case_else_tag, pub Vec<Expression>, pub StartEnd);
 
+def_tag!(in_tag, "in");
+#[derive(Deserialize, Debug, Clone)]
+pub struct In(
+    in_tag,
+    pub InPattern,
+    pub Vec<Expression>,
+    pub Option<Box<InOrElse>>,
+    pub StartEnd,
+);
+
+#[derive(RipperDeserialize, Debug, Clone)]
+pub enum InOrElse {
+    In(In),
+    Else(CaseElse),
+}
+
+// Guards on `in` clauses (`in [x, y] if x > y`) are parsed as
+// mod statements wrapping the pattern
+#[derive(RipperDeserialize, Debug, Clone)]
+pub enum InPattern {
+    IfGuard(PatternIfGuard),
+    UnlessGuard(PatternUnlessGuard),
+    Pattern(Pattern),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PatternIfGuard(if_mod_tag, pub Box<Expression>, pub Pattern);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PatternUnlessGuard(unless_mod_tag, pub Box<Expression>, pub Pattern);
+
+#[derive(RipperDeserialize, Debug, Clone)]
+pub enum Pattern {
+    AryPtn(AryPtn),
+    FndPtn(FndPtn),
+    HshPtn(HshPtn),
+    // Alternatives (`a | b`) and bindings (`a => b`)
+    Binary(PatternBinary),
+    Binding(VarField),
+    Pinned(PinnedVarRef),
+    PinnedExpression(PinnedBegin),
+    Value(Box<Expression>),
+}
+
+impl Pattern {
+    /// Top-level array, find, and hash patterns may be written without any
+    /// delimiters, e.g. `in a, *rest` or `in x:, y:`
+    pub fn is_undelimited_list(&self) -> bool {
+        matches!(
+            self,
+            Pattern::AryPtn(AryPtn(_, None, _, _, _, None))
+                | Pattern::FndPtn(FndPtn(_, None, _, _, _, None))
+                | Pattern::HshPtn(HshPtn(_, None, _, _, None))
+        )
+    }
+
+    pub fn is_binding(&self) -> bool {
+        matches!(
+            self,
+            Pattern::Binary(PatternBinary(_, _, BinaryOperator(op, _), _)) if op == "=>"
+        )
+    }
+}
+
+// The trailing `Option<String>` on array, find, and hash patterns is the
+// opening delimiter the pattern was written with, which is `None` for
+// undelimited top-level patterns like `in a, *rest`
+def_tag!(aryptn_tag, "aryptn");
+#[derive(Deserialize, Debug, Clone)]
+pub struct AryPtn(
+    aryptn_tag,
+    pub Option<Box<Expression>>,
+    pub Option<Vec<Pattern>>,
+    pub Option<PatternRest>,
+    pub Option<Vec<Pattern>>,
+    pub Option<String>,
+);
+
+def_tag!(fndptn_tag, "fndptn");
+#[derive(Deserialize, Debug, Clone)]
+pub struct FndPtn(
+    fndptn_tag,
+    pub Option<Box<Expression>>,
+    pub PatternRest,
+    pub Vec<Pattern>,
+    pub PatternRest,
+    pub Option<String>,
+);
+
+def_tag!(hshptn_tag, "hshptn");
+#[derive(Deserialize, Debug, Clone)]
+pub struct HshPtn(
+    hshptn_tag,
+    pub Option<Box<Expression>>,
+    pub Option<Vec<HshPtnPair>>,
+    pub Option<PatternRest>,
+    pub Option<String>,
+);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HshPtnPair(pub Label, pub Option<Pattern>);
+
+// `*rest`/`**rest` in a pattern. The name is missing for anonymous
+// rests (`*`), and is `nil` for `**nil`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PatternRest(var_field_tag, pub Option<PatternRestName>);
+
+def_tag!(nil_rest_tag, "nil");
+#[derive(RipperDeserialize, Debug, Clone)]
+pub enum PatternRestName {
+    Ident(Ident),
+    Nil(nil_rest_tag),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PatternBinary(
+    binary_tag,
+    pub Box<Pattern>,
+    pub BinaryOperator,
+    pub Box<Pattern>,
+);
+
+// `^var`, which ripper gives us as a plain var_ref
+#[derive(Deserialize, Debug, Clone)]
+pub struct PinnedVarRef(var_ref_tag, pub PinnedVariable);
+
+#[derive(RipperDeserialize, Debug, Clone)]
+pub enum PinnedVariable {
+    Ident(Ident),
+    IVar(IVar),
+    CVar(CVar),
+    GVar(GVar),
+}
+
+def_tag!(pinned_begin_tag, "pinned_begin");
+#[derive(Deserialize, Debug, Clone)]
+pub struct PinnedBegin(pinned_begin_tag, pub Box<Expression>);
+
 def_tag!(retry_tag, "retry");
 #[derive(Deserialize, Debug, Clone)]
 pub struct Retry(pub retry_tag, pub StartEnd);
@@ -83,6 +83,11 @@ extern "C" {
     pub fn rb_sym2id(sym: VALUE) -> ID;
     pub fn rb_id2name(id: ID) -> *const libc::c_char;
     pub fn rb_ary_entry(arr: VALUE, idx: libc::c_long) -> VALUE;
+    pub fn rb_ary_new() -> VALUE;
+    pub fn rb_ary_push(arr: VALUE, item: VALUE) -> VALUE;
+    pub fn rb_ll2inum(n: libc::c_longlong) -> VALUE;
+    pub fn rb_gc_register_address(addr: *mut VALUE);
+    pub fn rb_gc_unregister_address(addr: *mut VALUE);
     pub fn rb_raise(cls: VALUE, msg: *const libc::c_char);
     pub fn rb_block_call(
         obj: VALUE,
@@ -94,6 +99,31 @@ extern "C" {
     ) -> VALUE;
 }
 
+/// A VALUE registered with Ruby's GC, so that it and everything it references
+/// stay alive until this is dropped. The GC only finds VALUEs on the stack by
+/// itself, and not ones kept in Rust structs on the heap, so anything that
+/// has to outlive the call that returned it must be rooted.
+#[derive(Debug)]
+pub struct GcRoot(Box<VALUE>);
+
+impl GcRoot {
+    pub fn new(v: VALUE) -> Self {
+        let mut root = Box::new(v);
+        unsafe { rb_gc_register_address(&mut *root) };
+        GcRoot(root)
+    }
+
+    pub fn value(&self) -> VALUE {
+        *self.0
+    }
+}
+
+impl Drop for GcRoot {
+    fn drop(&mut self) {
+        unsafe { rb_gc_unregister_address(&mut *self.0) };
+    }
+}
+
 pub fn current_exception_as_rust_string() -> String {
     let ruby_string = unsafe { eval_str("$!.inspect") }.expect("Error evaluating `$!.inspect`");
     unsafe { ruby_string_to_str(ruby_string) }.to_owned()
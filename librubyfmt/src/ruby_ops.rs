@@ -1,5 +1,6 @@
 use crate::file_comments::FileComments;
 use crate::ruby::*;
+use std::ops::RangeInclusive;
 
 pub fn setup_ruby() -> Result<(), ()> {
     unsafe {
@@ -19,12 +20,16 @@ pub unsafe fn load_rubyfmt() -> Result<(), ()> {
     Ok(())
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Parser(VALUE);
+#[derive(Debug)]
+pub struct Parser(GcRoot);
 
 #[derive(Debug, Clone)]
 pub enum ParseError {
-    SyntaxError,
+    SyntaxError {
+        line: usize,
+        column: usize,
+        message: String,
+    },
     OtherRubyError(String),
 }
 
@@ -33,32 +38,57 @@ impl Parser {
         rb_funcall(parser_instance, intern!("parse"), 0)
     }
 
-    pub fn new(buf: &str) -> Self {
+    unsafe extern "C" fn real_syntax_error(parser_instance: VALUE) -> VALUE {
+        rb_funcall(parser_instance, intern!("syntax_error"), 0)
+    }
+
+    /// A parser for `buf`, which will only format statements touching
+    /// `line_ranges` (1-indexed) unless that's empty
+    pub fn new(buf: &str, line_ranges: &[RangeInclusive<usize>]) -> Self {
         unsafe {
             let buffer_string = rb_utf8_str_new(buf.as_ptr() as _, buf.len() as libc::c_long);
+            let ranges_array = rb_ary_new();
+            for range in line_ranges {
+                let range_array = rb_ary_new();
+                rb_ary_push(range_array, rb_ll2inum(*range.start() as libc::c_longlong));
+                rb_ary_push(range_array, rb_ll2inum(*range.end() as libc::c_longlong));
+                rb_ary_push(ranges_array, range_array);
+            }
             let parser_class = rb_const_get_at(rb_cObject, intern!("Parser"));
-            let parser_instance = rb_funcall(parser_class, intern!("new"), 1, buffer_string);
-            Parser(parser_instance)
+            let parser_instance =
+                rb_funcall(parser_class, intern!("new"), 2, buffer_string, ranges_array);
+            Parser(GcRoot::new(parser_instance))
         }
     }
 
-    pub fn parse(self) -> Result<(RipperTree, FileComments, Option<&'static str>), ParseError> {
+    pub fn parse(&self) -> Result<(RipperTree, FileComments, Option<String>), ParseError> {
         let mut state = 0;
         let maybe_ret_tuple =
-            unsafe { rb_protect(Parser::real_run_parser as _, self.0 as _, &mut state) };
+            unsafe { rb_protect(Parser::real_run_parser as _, self.0.value(), &mut state) };
         if state == 0 {
             if maybe_ret_tuple != Qnil {
-                let ret_tuple = unsafe { ruby_array_to_slice(maybe_ret_tuple) };
-                if let [tree, comments, lines, last_lineno, end_contents] = ret_tuple {
-                    let fc = FileComments::from_ruby_hash(*comments, *lines, *last_lineno);
+                // Reading the comments allocates, so the tuple has to be
+                // rooted for its contents to survive until they're copied
+                let ret_root = GcRoot::new(maybe_ret_tuple);
+                let ret_tuple = unsafe { ruby_array_to_slice(ret_root.value()) };
+                if let [tree, comments, lines, file_lines, end_contents, disabled_regions, statement_spans] =
+                    ret_tuple
+                {
+                    let fc = FileComments::from_ruby_hash(
+                        *comments,
+                        *lines,
+                        *file_lines,
+                        *disabled_regions,
+                        *statement_spans,
+                    );
                     let end_contents = unsafe {
                         if rubyfmt_rb_nil_p(*end_contents) != 0 {
                             None
                         } else {
-                            Some(ruby_string_to_str(*end_contents))
+                            Some(ruby_string_to_str(*end_contents).to_owned())
                         }
                     };
-                    Ok((RipperTree::new(*tree), fc, end_contents))
+                    Ok((RipperTree::new(*tree, *statement_spans), fc, end_contents))
                 } else {
                     panic!(
                         "expected return tuple to match expected, actually got: {}",
@@ -66,24 +96,74 @@ impl Parser {
                     )
                 }
             } else {
-                Err(ParseError::SyntaxError)
+                Err(self.syntax_error())
             }
         } else {
             let s = current_exception_as_rust_string();
             Err(ParseError::OtherRubyError(s))
         }
     }
+
+    // The first syntax error Ripper reported, after `parse` has returned nil
+    fn syntax_error(&self) -> ParseError {
+        let mut state = 0;
+        let error =
+            unsafe { rb_protect(Parser::real_syntax_error as _, self.0.value(), &mut state) };
+        if state != 0 {
+            return ParseError::OtherRubyError(current_exception_as_rust_string());
+        }
+
+        match unsafe { ruby_array_to_slice(error) } {
+            [line, column, message] => unsafe {
+                ParseError::SyntaxError {
+                    line: rubyfmt_rb_num2ll(*line) as usize,
+                    column: rubyfmt_rb_num2ll(*column) as usize,
+                    message: ruby_string_to_str(*message).to_owned(),
+                }
+            },
+            error => panic!(
+                "expected a syntax error of [line, column, message], actually got: {}",
+                error.len(),
+            ),
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct RipperTree(VALUE);
+/// The tree from ripper, which stays rooted while it's being deserialized,
+/// and after that in case it's needed to verify the output. The second root
+/// is the identity hash of each statement node to its `_rubyfmt_span`.
+#[derive(Debug)]
+pub struct RipperTree(GcRoot, GcRoot);
 
 impl RipperTree {
-    pub fn new(v: VALUE) -> Self {
-        RipperTree(v)
+    pub fn new(v: VALUE, statement_spans: VALUE) -> Self {
+        RipperTree(GcRoot::new(v), GcRoot::new(statement_spans))
     }
 
-    pub fn into_value(self) -> VALUE {
-        self.0
+    pub fn value(&self) -> VALUE {
+        self.0.value()
+    }
+
+    pub fn statement_spans(&self) -> VALUE {
+        self.1.value()
+    }
+
+    /// A dump of the tree's structure without any locations, see
+    /// `Parser.verification_dump` in `rubyfmt_lib.rb`
+    pub fn verification_dump(&self) -> Result<String, ParseError> {
+        unsafe extern "C" fn real_verification_dump(tree: VALUE) -> VALUE {
+            let parser_class = rb_const_get_at(rb_cObject, intern!("Parser"));
+            rb_funcall(parser_class, intern!("verification_dump"), 1, tree)
+        }
+
+        let mut state = 0;
+        let dump = unsafe { rb_protect(real_verification_dump as _, self.value(), &mut state) };
+        if state == 0 {
+            Ok(unsafe { ruby_string_to_str(dump) }.to_owned())
+        } else {
+            Err(ParseError::OtherRubyError(
+                current_exception_as_rust_string(),
+            ))
+        }
     }
 }
@@ -0,0 +1,30 @@
+// The Ruby VM can only be set up once, on one thread, so everything that
+// needs it is checked from a single test
+use rubyfmt::{format_buffer_with, rubyfmt_init, FormatOptions, InitStatus};
+
+#[test]
+fn unformattable_syntax_is_a_warning() {
+    assert_eq!(rubyfmt_init(), InitStatus::OK as libc::c_int);
+
+    let copied = format_buffer_with("alias $new $old\n", &FormatOptions::default()).unwrap();
+    assert_eq!(copied.output, "alias $new $old\n");
+    assert_eq!(
+        copied.warnings,
+        vec!["unsupported `var_alias` on line 1, copying it through unformatted"]
+    );
+
+    // The rest of the file still gets formatted
+    let partly_formatted =
+        format_buffer_with("alias $new $old\nfoo( 1 )\n", &FormatOptions::default()).unwrap();
+    assert_eq!(partly_formatted.output, "alias $new $old\nfoo(1)\n");
+    assert!(partly_formatted.changed);
+
+    let unformatted =
+        format_buffer_with("foo( 1 ); alias $new $old\n", &FormatOptions::default()).unwrap();
+    assert_eq!(unformatted.output, "foo( 1 ); alias $new $old\n");
+    assert!(!unformatted.changed);
+    assert_eq!(
+        unformatted.warnings,
+        vec!["unsupported `var_alias` on line 1, leaving the file unformatted"]
+    );
+}
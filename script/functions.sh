@@ -38,6 +38,8 @@ diff_files() {
 
 test_fixtures_folder() {
     current_dir="$1"
+    # Any remaining arguments are passed through to rubyfmt
+    shift
 
     # Fallback to * (all tests)
     fixture_name=${FIXTURE_NAME:-*}
@@ -46,23 +48,34 @@ test_fixtures_folder() {
       actual_file="${expected_file//expected/actual}"
 
       ## Test if the formatting works as expected
-      f_rubyfmt < "$actual_file" > /tmp/out.rb
+      f_rubyfmt "$@" < "$actual_file" > /tmp/out.rb
       diff_files o /tmp/out.rb "$expected_file"
 
       ## Test if the formatting is idempotent
-      f_rubyfmt < "$expected_file" > /tmp/out.rb
+      f_rubyfmt "$@" < "$expected_file" > /tmp/out.rb
       diff_files i /tmp/out.rb "$expected_file"
     done
 
     ## Recurse over ruby version dirs
-    find "$current_dir" -type d -mindepth 1 -maxdepth 1 -name '*2.*' | while read -r dir
+    find "$current_dir" -type d -mindepth 1 -maxdepth 1 -name '[0-9].*' | while read -r dir
     do
         RUBY_VERSION=$(ruby -v | grep -o "[0-9].[0-9]" | head -n 1)
         base="$(basename "$dir")"
         fixture_version=${base#"ruby-"}
         if [[ $(echo "$fixture_version<=$RUBY_VERSION" | bc -l) -ne 0 ]]
         then
-            test_fixtures_folder "$dir"
+            test_fixtures_folder "$dir" "$@"
         fi
     done
 }
+
+# Each directory in e.g. fixtures/line_length is named for the value of
+# `--line-length` its fixtures are formatted with
+test_option_fixtures() {
+    option="$1"
+
+    find "fixtures/${option//-/_}" -type d -mindepth 1 -maxdepth 1 | while read -r dir
+    do
+        test_fixtures_folder "$dir" "--$option" "$(basename "$dir")"
+    done
+}
@@ -36,7 +36,11 @@ uname -a
 ./script/tests/test_array_literals.sh
 ./script/tests/test_methods.sh
 ./script/tests/test_cli_interface.sh
+./script/tests/test_c_header.sh
 ./script/tests/test_c_main.sh
+./script/tests/test_c_soak.sh
+./script/tests/test_c_threads.sh
+./script/tests/test_c_api.sh
 ./script/tests/test_error_handling.sh
 ./script/tests/test_fixtures.sh
 ./script/tests/test_formatting_locks.sh
@@ -0,0 +1,9 @@
+#!/bin/bash
+set -ex
+
+source ./script/functions.sh
+make target/c_api_release
+
+# Options, error messages and locations, and versions all work through the
+# C API
+./target/c_api_release
@@ -0,0 +1,11 @@
+#!/bin/bash
+set -ex
+
+# The committed header should be the one that building generates from
+# librubyfmt/src/lib.rs, so copying that over it shouldn't change it
+./script/update_c_header
+if ! git diff --exit-code -- librubyfmt/include/rubyfmt.h
+then
+    echo "librubyfmt/include/rubyfmt.h is out of date, run script/update_c_header and commit the result"
+    exit 1
+fi
@@ -0,0 +1,8 @@
+#!/bin/bash
+set -ex
+
+source ./script/functions.sh
+make target/c_soak_release
+
+# Memory should stay bounded when one process formats many buffers
+./target/c_soak_release 10000 < ci/methods_stress_test.rb
@@ -0,0 +1,15 @@
+#!/bin/bash
+set -ex
+
+source ./script/functions.sh
+make target/c_main_release target/c_threads_release
+
+# Formatting from several threads through a formatter gives the same output
+# as formatting on the thread that set up the VM
+EXPECTED=$(./target/c_main_release < ci/methods_stress_test.rb | f_md5)
+ACTUAL=$(./target/c_threads_release < ci/methods_stress_test.rb | f_md5)
+if [[ "$EXPECTED" != "$ACTUAL" ]]
+then
+    echo "formatting from other threads is broken"
+    exit 1
+fi
@@ -654,6 +654,569 @@ DIFF
     )
 }
 
+test_respects_config_file() {
+    (
+    cd "$(mktemp -d)"
+    mkdir -p narrow/nested
+    echo "foo(aaaaaaa, bbbbbbb, ccccccc)" > wide.rb
+    echo "foo(aaaaaaa, bbbbbbb, ccccccc)" > narrow/nested/narrow.rb
+
+    echo "line_length = 20" > narrow/.rubyfmt.toml
+
+    f_rubyfmt -i -- .
+
+    cat > wide_expected.rb <<- DIFF
+foo(aaaaaaa, bbbbbbb, ccccccc)
+DIFF
+    cat > narrow_expected.rb <<- DIFF
+foo(
+  aaaaaaa,
+  bbbbbbb,
+  ccccccc
+)
+DIFF
+
+    diff_files o wide_expected.rb wide.rb
+    diff_files o narrow_expected.rb narrow/nested/narrow.rb
+    )
+}
+
+test_verify_flag() {
+    (
+    cd "$(mktemp -d)"
+
+    cat > input.rb <<- RUBY
+a 1,2,3
+foo.bar 'baz'
+RUBY
+    cat > expected.rb <<- RUBY
+a(1, 2, 3)
+foo.bar("baz")
+RUBY
+
+    f_rubyfmt --verify -- input.rb > out.rb
+
+    diff_files o out.rb expected.rb
+    )
+}
+
+test_check_idempotent_flag() {
+    (
+    cd "$(mktemp -d)"
+
+    echo "a 1,2,3" > a_ruby_file_1.rb
+    echo "a(4, 5, 6, 7)" > a_ruby_file_2.rb
+
+    f_rubyfmt --check-idempotent -- a_ruby_file_1.rb a_ruby_file_2.rb > fmt.diff
+
+    # printf instead of echo so we don't get a newline
+    printf "" > expected.diff
+
+    diff_files o expected.diff fmt.diff
+    )
+}
+
+test_jobs_flag_keeps_file_order() {
+    (
+    cd "$(mktemp -d)"
+
+    echo "a 1,2,3" > a_ruby_file_1.rb
+    echo "a 4,5,6,7" > a_ruby_file_2.rb
+    echo "a 8,9" > a_ruby_file_3.rb
+
+    # --check returns non-zero when there are no changes
+    set +e
+    f_rubyfmt --jobs 2 --check -- a_ruby_file_1.rb a_ruby_file_2.rb a_ruby_file_3.rb > fmt.diff
+    set -e
+
+    cat > expected.diff <<- DIFF
+--- a_ruby_file_1.rb
++++ a_ruby_file_1.rb
+@@ -1 +1 @@
+-a 1,2,3
++a(1, 2, 3)
+--- a_ruby_file_2.rb
++++ a_ruby_file_2.rb
+@@ -1 +1 @@
+-a 4,5,6,7
++a(4, 5, 6, 7)
+--- a_ruby_file_3.rb
++++ a_ruby_file_3.rb
+@@ -1 +1 @@
+-a 8,9
++a(8, 9)
+DIFF
+
+    cat expected.diff
+    cat fmt.diff
+
+    diff_files o expected.diff fmt.diff
+
+    # Warnings come out in file order too
+    for n in 1 2 3 4; do
+        echo "alias \$new_$n \$old_$n" > warning_$n.rb
+    done
+
+    f_rubyfmt --jobs 2 -- warning_1.rb warning_2.rb warning_3.rb warning_4.rb > /dev/null 2> warnings.txt
+
+    grep "^Warning! source:" warnings.txt > warning_sources.txt
+    cat > expected_warning_sources.txt <<- TXT
+Warning! source: warning_1.rb
+Warning! source: warning_2.rb
+Warning! source: warning_3.rb
+Warning! source: warning_4.rb
+TXT
+
+    diff_files o expected_warning_sources.txt warning_sources.txt
+    )
+}
+
+test_cache_skips_formatted_files() {
+    (
+    cd "$(mktemp -d)"
+
+    echo "a(1, 2, 3)" > a_ruby_file_1.rb
+
+    f_rubyfmt --check --cache-location cache -- a_ruby_file_1.rb > fmt.diff
+
+    # The formatted file was recorded in the cache
+    test "$(find cache -type f | wc -l)" -eq 1
+
+    # Changing the file means the cached entry no longer applies
+    echo "a 1,2,3" > a_ruby_file_1.rb
+
+    set +e
+    f_rubyfmt --check --cache-location cache -- a_ruby_file_1.rb > fmt.diff
+    set -e
+
+    cat > expected.diff <<- DIFF
+--- a_ruby_file_1.rb
++++ a_ruby_file_1.rb
+@@ -1 +1 @@
+-a 1,2,3
++a(1, 2, 3)
+DIFF
+
+    diff_files o expected.diff fmt.diff
+
+    # --no-cache leaves the cache alone
+    echo "b(1, 2, 3)" > a_ruby_file_1.rb
+    f_rubyfmt --check --no-cache --cache-location cache -- a_ruby_file_1.rb > fmt.diff
+    test "$(find cache -type f | wc -l)" -eq 1
+
+    # Printing to STDOUT doesn't read the cache, so it doesn't write to it either
+    f_rubyfmt --cache-location cache -- a_ruby_file_1.rb > /dev/null
+    test "$(find cache -type f | wc -l)" -eq 1
+    )
+}
+
+test_cache_keeps_reporting_warnings() {
+    (
+    cd "$(mktemp -d)"
+
+    # rubyfmt can't format global variable aliases, so it copies them through
+    # unchanged with a warning
+    echo 'alias $new $old' > a_ruby_file_1.rb
+
+    f_rubyfmt --check --cache-location cache -- a_ruby_file_1.rb 2> first_run.err
+    f_rubyfmt --check --cache-location cache -- a_ruby_file_1.rb 2> second_run.err
+
+    grep -q "unsupported \`var_alias\` on line 1" first_run.err
+    grep -q "unsupported \`var_alias\` on line 1" second_run.err
+    test ! -d cache || test "$(find cache -type f | wc -l)" -eq 0
+    )
+}
+
+lsp_message() {
+    printf 'Content-Length: %d\r\n\r\n%s' "${#1}" "$1"
+}
+
+test_lsp_formatting() {
+    (
+    cd "$(mktemp -d)"
+
+    {
+        lsp_message '{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}'
+        lsp_message '{"jsonrpc":"2.0","method":"initialized","params":{}}'
+        lsp_message '{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///tmp/a.rb","languageId":"ruby","version":1,"text":"a 1,2,3\n"}}}'
+        lsp_message '{"jsonrpc":"2.0","id":2,"method":"textDocument/formatting","params":{"textDocument":{"uri":"file:///tmp/a.rb"},"options":{"tabSize":2,"insertSpaces":true}}}'
+        lsp_message '{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///tmp/a.rb","version":2},"contentChanges":[{"text":"a(1,\n"}]}}'
+        lsp_message '{"jsonrpc":"2.0","id":3,"method":"textDocument/formatting","params":{"textDocument":{"uri":"file:///tmp/a.rb"},"options":{"tabSize":2,"insertSpaces":true}}}'
+        # A newline after an `end` formats what it finishes
+        lsp_message '{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"uri":"file:///tmp/a.rb","version":3},"contentChanges":[{"text":"def foo\n  b 4,5\nend\n\n"}]}}'
+        lsp_message '{"jsonrpc":"2.0","id":4,"method":"textDocument/onTypeFormatting","params":{"textDocument":{"uri":"file:///tmp/a.rb"},"position":{"line":3,"character":0},"ch":"\n","options":{"tabSize":2,"insertSpaces":true}}}'
+        lsp_message '{"jsonrpc":"2.0","id":5,"method":"shutdown"}'
+        lsp_message '{"jsonrpc":"2.0","method":"exit"}'
+    } > input.lsp
+
+    f_rubyfmt lsp < input.lsp > output.lsp
+
+    grep -F '"newText":"a(1, 2, 3)\n"' output.lsp
+    grep -F '"code":"SyntaxError"' output.lsp
+    grep -F 'b(4, 5)' output.lsp
+    )
+}
+
+test_lines_flag() {
+    (
+    cd "$(mktemp -d)"
+
+    cat > input.rb <<- RUBY
+a 1,2,3
+b 1,2,3
+
+def foo
+  c 1,2,3
+  d 1,2,3
+end
+RUBY
+
+    cat > expected.rb <<- RUBY
+a(1, 2, 3)
+b 1,2,3
+
+def foo
+  c 1,2,3
+  d(1, 2, 3)
+end
+RUBY
+
+    f_rubyfmt --lines 1:1 --lines 6:6 -- input.rb > out.rb
+
+    diff_files o out.rb expected.rb
+    )
+}
+
+test_changed_since_flag() {
+    (
+    cd "$(mktemp -d)"
+
+    git init -q .
+    echo "a 1,2,3" > unchanged.rb
+    echo "b(1, 2, 3)" > changed.rb
+    git add .
+    git -c user.name=rubyfmt -c user.email=rubyfmt@example.com commit -qm "Initial commit"
+    echo "b 1,2,3" > changed.rb
+
+    # --check returns non-zero when there are no changes
+    set +e
+    f_rubyfmt --check --changed-since HEAD > fmt.diff
+    set -e
+
+    cat > expected.diff <<- DIFF
+--- ./changed.rb
++++ ./changed.rb
+@@ -1 +1 @@
+-b 1,2,3
++b(1, 2, 3)
+DIFF
+
+    diff_files o expected.diff fmt.diff
+    )
+}
+
+test_staged_flag_with_unstaged_changes() {
+    (
+    cd "$(mktemp -d)"
+
+    git init -q .
+    echo "b(1, 2, 3)" > changed.rb
+    git add .
+    git -c user.name=rubyfmt -c user.email=rubyfmt@example.com commit -qm "Initial commit"
+    echo "b 1,2,3" > changed.rb
+    git add changed.rb
+    printf "b 1,2,3\nc 4,5\n" > changed.rb
+
+    set +e
+    f_rubyfmt --staged 2> fmt.err
+    status=$?
+    set -e
+
+    if [ "$status" -eq 0 ]; then
+        echo "expected --staged to refuse a file with unstaged changes"
+        exit 1
+    fi
+
+    grep -q "changed.rb has unstaged changes" fmt.err
+    if [ "$(cat changed.rb)" != "$(printf "b 1,2,3\nc 4,5")" ]; then
+        echo "expected --staged to leave changed.rb alone"
+        exit 1
+    fi
+    )
+}
+
+test_check_format_flag() {
+    (
+    cd "$(mktemp -d)"
+    printf "a 1,2,3\nb(1, 2, 3)\nc 1,2,3\n" > test.rb
+
+    # --check returns non-zero when there are changes
+    set +e
+    f_rubyfmt --check --format json -- test.rb > report.json
+    status=$?
+    set -e
+
+    if [ "$status" -ne 5 ]; then
+        echo "expected --check --format json to exit 5, got $status"
+        exit 1
+    fi
+
+    cat > expected.json <<- JSON
+{
+  "files": [
+    {
+      "path": "test.rb",
+      "lines": [
+        {
+          "start": 1,
+          "end": 1
+        },
+        {
+          "start": 3,
+          "end": 3
+        }
+      ]
+    }
+  ],
+  "errors": []
+}
+JSON
+
+    diff_files o expected.json report.json
+
+    set +e
+    f_rubyfmt --check --format github -- test.rb > report.txt
+    set -e
+
+    cat > expected.txt <<- TXT
+::error file=test.rb,line=1,endLine=1,title=rubyfmt Unformatted::Rubyfmt would format line 1 differently
+::error file=test.rb,line=3,endLine=3,title=rubyfmt Unformatted::Rubyfmt would format line 3 differently
+TXT
+
+    diff_files o expected.txt report.txt
+
+    # Errors that don't stop rubyfmt still fail the check
+    printf "a(1)\nb(1, 2 3)\n" > bad.rb
+
+    set +e
+    f_rubyfmt --check --format checkstyle -- test.rb bad.rb > report.xml
+    status=$?
+    set -e
+
+    if [ "$status" -ne 1 ]; then
+        echo "expected --check --format checkstyle with a syntax error to exit 1, got $status"
+        exit 1
+    fi
+
+    # Each file has a single <file> element
+    test "$(grep -c '<file name="test.rb">' report.xml)" -eq 1
+    test "$(grep -c '<file name="bad.rb">' report.xml)" -eq 1
+    grep -F 'source="rubyfmt.SyntaxError"' report.xml
+    )
+}
+
+test_syntax_error_location() {
+    (
+    cd "$(mktemp -d)"
+    printf "a(1)\nb(1, 2 3)\nc(1)\n" > test.rb
+
+    set +e
+    f_rubyfmt -- test.rb 2> errors.txt
+    set -e
+
+    # The error points at the line it's on, with a caret under the column
+    grep -E '^test.rb:2:[0-9]+: syntax error' errors.txt
+    grep -F '2 | b(1, 2 3)' errors.txt
+    grep -E '^ *\| *\^$' errors.txt
+    )
+}
+
+test_passthrough_on_error() {
+    (
+    cd "$(mktemp -d)"
+    printf "a(1)\nb(1, 2 3)\n" > input.rb
+
+    # The input comes back unchanged, with the chosen exit code
+    set +e
+    f_rubyfmt --passthrough-on-error --passthrough-exit-code 0 < input.rb > output.rb 2> errors.txt
+    status=$?
+    set -e
+
+    if [ "$status" -ne 0 ]; then
+        echo "expected --passthrough-exit-code 0 to exit 0, got $status"
+        exit 1
+    fi
+
+    diff_files o input.rb output.rb
+    grep -F 'syntax error' errors.txt
+    )
+}
+
+test_reduce_without_failure() {
+    (
+    cd "$(mktemp -d)"
+    echo "a(1, 2, 3)" > test.rb
+
+    # There's nothing to reduce in a file that formats fine
+    if f_rubyfmt reduce test.rb > snippet.rb 2> errors.txt; then
+        echo "expected rubyfmt reduce to fail on a file without errors"
+        exit 1
+    fi
+    grep -F "nothing to reduce" errors.txt
+    )
+}
+
+test_dump_stages() {
+    (
+    cd "$(mktemp -d)"
+    echo "a 1, 2, 3" > test.rb
+
+    f_rubyfmt --dump ripper -- test.rb > ripper.json
+    grep -F '"program"' ripper.json
+
+    f_rubyfmt --dump ast -- test.rb > ast.txt
+    grep -F "Program(" ast.txt
+
+    f_rubyfmt --dump tokens -- test.rb > tokens.txt
+    f_rubyfmt --dump final-tokens -- test.rb > final_tokens.txt
+    grep -F "HardNewLine" final_tokens.txt
+
+    # Nothing is formatted when dumping
+    echo "a 1, 2, 3" | diff - test.rb
+    )
+}
+
+test_explain_line() {
+    (
+    cd "$(mktemp -d)"
+    cat > test.rb <<- RUBY
+a(1, 2, 3)
+b(
+  1
+)
+RUBY
+
+    f_rubyfmt --explain 1 -- test.rb > explanation.txt
+    grep -F "BreakableEntry on line 1" explanation.txt
+    grep -F "it fits within the line length" explanation.txt
+
+    f_rubyfmt --explain 3 -- test.rb > explanation.txt
+    grep -F "rendered across multiple lines" explanation.txt
+    grep -F "multiline because the original code had it across lines 2," explanation.txt
+    )
+}
+
+test_style_policies() {
+    (
+    cd "$(mktemp -d)"
+    echo "foo 'bar', a: \"it's\", b: 1" > input.rb
+
+    cat > .rubyfmt.toml <<- TOML
+quote_style = "single"
+hash_style = "hash_rockets"
+paren_style = "preserve"
+TOML
+
+    cat > configured_expected.rb <<- RUBY
+foo 'bar', :a => "it's", :b => 1
+RUBY
+    cat > overridden_expected.rb <<- RUBY
+foo("bar", a: "it's", b: 1)
+RUBY
+
+    f_rubyfmt -- input.rb > configured.rb
+    f_rubyfmt --quote-style double --hash-style labels --paren-style normalize -- input.rb > overridden.rb
+
+    diff_files o configured_expected.rb configured.rb
+    diff_files o overridden_expected.rb overridden.rb
+    )
+}
+
+test_dsl_method_config() {
+    (
+    cd "$(mktemp -d)"
+
+    cat > input.rb <<- RUBY
+scenario "works" do
+end
+load "a.rb"
+RUBY
+
+    cat > default_expected.rb <<- RUBY
+scenario("works") do
+end
+load("a.rb")
+RUBY
+    cat > configured_expected.rb <<- RUBY
+scenario "works" do
+end
+load "a.rb"
+RUBY
+
+    f_rubyfmt -- input.rb > default.rb
+
+    cat > .rubyfmt.toml <<- TOML
+rspec_methods = ["it", "describe", "test", "scenario"]
+optionally_parenthesized_methods = ["super", "require", "require_relative", "load"]
+TOML
+
+    f_rubyfmt -- input.rb > configured.rb
+
+    diff_files o default_expected.rb default.rb
+    diff_files o configured_expected.rb configured.rb
+    )
+}
+
+test_rejects_zero_widths() {
+    (
+    cd "$(mktemp -d)"
+
+    echo "a 1,2,3" > input.rb
+
+    set +e
+    f_rubyfmt --line-length 0 -- input.rb
+    flag_status=$?
+    set -e
+    if [ "$flag_status" -eq 0 ]; then
+        echo "rubyfmt accepted --line-length 0"
+        exit 1
+    fi
+
+    set +e
+    f_rubyfmt --indent-width 0 -- input.rb
+    indent_flag_status=$?
+    set -e
+    if [ "$indent_flag_status" -eq 0 ]; then
+        echo "rubyfmt accepted --indent-width 0"
+        exit 1
+    fi
+
+    mkdir config
+    echo "a 1,2,3" > config/input.rb
+    echo "line_length = 0" > config/.rubyfmt.toml
+
+    set +e
+    f_rubyfmt --fail-fast -- config/input.rb
+    config_status=$?
+    set -e
+    if [ "$config_status" -eq 0 ]; then
+        echo "rubyfmt accepted line_length = 0 in .rubyfmt.toml"
+        exit 1
+    fi
+
+    echo "indent_width = 0" > config/.rubyfmt.toml
+
+    set +e
+    f_rubyfmt --fail-fast -- config/input.rb
+    indent_config_status=$?
+    set -e
+    if [ "$indent_config_status" -eq 0 ]; then
+        echo "rubyfmt accepted indent_width = 0 in .rubyfmt.toml"
+        exit 1
+    fi
+    )
+}
+
 test_formats_non_rb_files() {
     (
     cd "$(mktemp -d)"
@@ -708,5 +1271,28 @@ test_respects_gitignore
 test_includes_gitignore
 
 test_respects_rubyfmt_ignore_file
+test_respects_config_file
+test_dsl_method_config
+test_rejects_zero_widths
+
+test_verify_flag
+test_check_idempotent_flag
+test_jobs_flag_keeps_file_order
+test_cache_skips_formatted_files
+test_cache_keeps_reporting_warnings
+test_lsp_formatting
+test_lines_flag
+test_changed_since_flag
+test_staged_flag_with_unstaged_changes
+test_check_format_flag
+test_syntax_error_location
+test_passthrough_on_error
+test_reduce_without_failure
+
+test_dump_stages
+
+test_explain_line
+
+test_style_policies
 
 test_formats_non_rb_files
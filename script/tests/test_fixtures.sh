@@ -8,3 +8,7 @@ make
 
 test_fixtures_folder "fixtures/small"
 test_fixtures_folder "fixtures/large"
+test_fixtures_folder "fixtures/verify" --verify
+test_option_fixtures line-length
+test_option_fixtures indent-width
+test_option_fixtures quote-style
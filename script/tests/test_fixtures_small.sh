@@ -6,3 +6,7 @@ source "./script/functions.sh"
 make
 
 test_fixtures_folder "fixtures/small"
+test_fixtures_folder "fixtures/verify" --verify
+test_option_fixtures line-length
+test_option_fixtures indent-width
+test_option_fixtures quote-style
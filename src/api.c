@@ -0,0 +1,85 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include "../librubyfmt/include/rubyfmt.h"
+
+static int failures = 0;
+
+static void check(int ok, const char* what) {
+    if (!ok) {
+        fprintf(stderr, "failed: %s\n", what);
+        failures++;
+    }
+}
+
+// Formats a null terminated buffer, returning its output as a null
+// terminated string, or NULL on error
+static char* format(const char* input, const RubyfmtOptions* options, Rubyfmt_FormatError* status) {
+    RubyfmtString* out = rubyfmt_format_buffer_with_options((const uint8_t*)input, strlen(input), options, status);
+    if (out == NULL) {
+        return NULL;
+    }
+    size_t len = rubyfmt_string_len(out);
+    char* output = malloc(len + 1);
+    memcpy(output, rubyfmt_string_ptr(out), len);
+    output[len] = '\0';
+    rubyfmt_string_free(out);
+    return output;
+}
+
+// Exercises the options, error reporting and versioning in rubyfmt.h
+int main() {
+    check(rubyfmt_api_version() == RUBYFMT_API_VERSION, "the library's API version matches the header's");
+    check(strlen(rubyfmt_version()) > 0, "the library has a version");
+
+    if (rubyfmt_init() != RUBYFMT_INIT_STATUS_OK) {
+        fprintf(stderr, "failed to init\n");
+        exit(1);
+    }
+
+    Rubyfmt_FormatError status = RUBYFMT_FORMAT_ERROR_OK;
+    RubyfmtOptions* options = rubyfmt_options_new();
+    check(rubyfmt_options_set_quote_style(options, RUBYFMT_QUOTE_STYLE_SINGLE), "quote styles can be set");
+    check(rubyfmt_options_set_hash_style(options, RUBYFMT_HASH_STYLE_HASH_ROCKETS), "hash styles can be set");
+    check(!rubyfmt_options_set_paren_style(options, 2), "invalid paren styles are rejected");
+    check(rubyfmt_options_set_indent_width(options, 4), "indent widths can be set");
+    check(!rubyfmt_options_set_indent_width(options, 0), "an indent width of 0 is rejected");
+    check(rubyfmt_options_set_line_length(options, 100), "line lengths can be set");
+    check(!rubyfmt_options_set_line_length(options, 0), "a line length of 0 is rejected");
+    check(!rubyfmt_options_set_verify(NULL, true), "setting null options is rejected");
+    check(!rubyfmt_options_add_line_range(options, 0, 1), "line ranges starting at 0 are rejected");
+    check(!rubyfmt_options_add_line_range(options, 3, 2), "empty line ranges are rejected");
+
+    char* output = format("foo \"bar\", a: 1\n", options, &status);
+    check(status == RUBYFMT_FORMAT_ERROR_OK, "formatting with options succeeds");
+    check(output != NULL && strcmp(output, "foo('bar', :a => 1)\n") == 0, "options are used");
+    check(rubyfmt_last_error_message() == NULL, "success leaves no error");
+    free(output);
+
+    output = format("a = 1\nb = \"\xff\"\n", options, &status);
+    check(output == NULL, "invalid UTF-8 isn't formatted");
+    check(status == RUBYFMT_FORMAT_ERROR_INVALID_UTF8, "invalid UTF-8 is reported");
+    check(rubyfmt_last_error_message() != NULL, "invalid UTF-8 has a message");
+    check(rubyfmt_last_error_line() == 2, "invalid UTF-8 has a line");
+    check(rubyfmt_last_error_column() == 6, "invalid UTF-8 has a column");
+
+    output = format("a(1)\nb(1, 2 3)\nc(1)\n", NULL, &status);
+    check(output == NULL, "syntax errors aren't formatted");
+    check(status == RUBYFMT_FORMAT_ERROR_SYNTAX_ERROR, "syntax errors are reported");
+    check(rubyfmt_last_error_message() != NULL, "syntax errors have a message");
+    check(rubyfmt_last_error_line() == 2, "syntax errors have a line");
+
+    RubyfmtString* unbreakable = rubyfmt_format_buffer_with_line_length((const uint8_t*)"a(1)\n", 5, 0, &status);
+    check(unbreakable == NULL, "a line length of 0 isn't formatted with");
+    check(status == RUBYFMT_FORMAT_ERROR_INVALID_OPTION, "a line length of 0 is reported");
+    check(rubyfmt_last_error_message() != NULL, "a line length of 0 has a message");
+    check(rubyfmt_options_set_verify(options, true), "verification can be set");
+    check(rubyfmt_last_error_message() == NULL, "the next call clears the last error");
+
+    rubyfmt_options_free(options);
+    rubyfmt_options_free(NULL);
+
+    if (failures > 0) {
+        exit(1);
+    }
+}
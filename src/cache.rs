@@ -0,0 +1,90 @@
+//! An on-disk record of file contents that are already formatted.
+//!
+//! Each entry is an empty file named after a hash of rubyfmt's version, the
+//! options a file was formatted with and the file's contents, so there's no
+//! index to keep consistent and concurrent runs can share a cache directory.
+//! Changing any of those gives a different hash, which means stale entries
+//! are never consulted and can be deleted at any time. Errors reading or
+//! writing the cache are ignored, since formatting is always a safe fallback.
+
+use super::{format_options_for, CommandlineOpts};
+use rubyfmt::FormatOptions;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+lazy_static! {
+    // Identifies this build of rubyfmt. The executable's modification time is
+    // included so that development builds, which share a version number,
+    // don't trust each other's entries.
+    static ref BUILD_ID: String = {
+        let modified = std::env::current_exe()
+            .and_then(fs::metadata)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+        format!("rubyfmt {} {}", env!("CARGO_PKG_VERSION"), modified)
+    };
+}
+
+// The cache is only used in modes where an unchanged file produces no
+// output, so it can be skipped. Printing to STDOUT has to print every file,
+// and files that are already formatted can still be dumped or explained.
+fn cache_dir(opts: &CommandlineOpts) -> Option<PathBuf> {
+    if !(opts.check || opts.check_idempotent || opts.in_place)
+        || opts.no_cache
+        || opts.dump.is_some()
+        || opts.explain.is_some()
+    {
+        return None;
+    }
+
+    opts.cache_location
+        .clone()
+        .or_else(|| dirs::cache_dir().map(|dir| dir.join("rubyfmt")))
+}
+
+fn entry_path(cache_dir: &Path, buffer: &str, format_options: &FormatOptions) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(BUILD_ID.as_bytes());
+    hasher.update(format!("\n{:?}\n", format_options).as_bytes());
+    hasher.update(buffer.as_bytes());
+    let key: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    // Spread entries over subdirectories to keep each directory small
+    cache_dir.join(&key[..2]).join(&key[2..])
+}
+
+// Whether a file is known to already be formatted, in which case it can be
+// skipped
+pub fn is_known_formatted(opts: &CommandlineOpts, file_path: &Path, buffer: &str) -> bool {
+    let cache_dir = match cache_dir(opts) {
+        Some(dir) => dir,
+        None => return false,
+    };
+
+    match format_options_for(opts, file_path) {
+        Ok(format_options) => entry_path(&cache_dir, buffer, &format_options).is_file(),
+        Err(_) => false,
+    }
+}
+
+// Remember that formatting `buffer` with these options leaves it unchanged
+pub fn record_formatted(opts: &CommandlineOpts, buffer: &str, format_options: &FormatOptions) {
+    let cache_dir = match cache_dir(opts) {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    let entry = entry_path(&cache_dir, buffer, format_options);
+    if let Some(parent) = entry.parent() {
+        let _ = fs::create_dir_all(parent).and_then(|_| File::create(&entry));
+    }
+}
@@ -0,0 +1,151 @@
+//! Asking git which files, and which lines of them, have changed, for
+//! `--changed-since` and `--staged`.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The files that have changed, keyed by their canonical path. Each file has
+/// the ranges of lines that changed if they were asked for, or `None` if the
+/// whole file is new.
+#[derive(Debug, Default)]
+pub struct ChangedFiles(HashMap<PathBuf, Option<Vec<RangeInclusive<usize>>>>);
+
+impl ChangedFiles {
+    pub fn contains(&self, file_path: &Path) -> bool {
+        file_path
+            .canonicalize()
+            .map(|path| self.0.contains_key(&path))
+            .unwrap_or(false)
+    }
+
+    pub fn lines_for(&self, file_path: &Path) -> Option<&[RangeInclusive<usize>]> {
+        let path = file_path.canonicalize().ok()?;
+        self.0.get(&path)?.as_deref()
+    }
+}
+
+/// Files changed in the working tree since `since`, or staged in the index if
+/// `staged`, along with their changed lines if `with_lines`. Files that have
+/// only had lines removed aren't included when asking for lines, since
+/// there's nothing in them to format. Staged files that also have unstaged
+/// changes are an error, since it's the working tree copy that gets
+/// formatted.
+pub fn changed_files(
+    since: Option<&str>,
+    staged: bool,
+    with_lines: bool,
+) -> Result<ChangedFiles, String> {
+    let toplevel = PathBuf::from(git_string(&["rev-parse", "--show-toplevel"])?.trim_end());
+    let mut diff_args = vec!["diff", "--no-ext-diff", "--no-color"];
+    if staged {
+        diff_args.push("--cached");
+    }
+    if let Some(since) = since {
+        diff_args.push(since);
+    }
+
+    let mut name_args = diff_args.clone();
+    name_args.extend(["--name-only", "--diff-filter=d", "-z"]);
+
+    // The working tree copy is what gets formatted, so formatting a file with
+    // unstaged changes would format those too, and the staged line numbers
+    // wouldn't line up with it
+    let unstaged = if staged {
+        git_string(&["diff", "--no-ext-diff", "--name-only", "-z"])?
+    } else {
+        String::new()
+    };
+
+    let mut files = HashMap::new();
+    for name in git_string(&name_args)?
+        .split('\0')
+        .filter(|n| !n.is_empty())
+    {
+        if unstaged.split('\0').any(|u| u == name) {
+            return Err(format!(
+                "{} has unstaged changes, which `--staged` would format too; stage or stash them first",
+                name
+            ));
+        }
+
+        let path = match toplevel.join(name).canonicalize() {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+
+        let lines = if with_lines {
+            let lines = changed_lines(&diff_args, &path)?;
+            if lines.is_empty() {
+                continue;
+            }
+            Some(lines)
+        } else {
+            None
+        };
+        files.insert(path, lines);
+    }
+
+    // Untracked files haven't been staged, but are otherwise entirely new
+    if !staged {
+        let toplevel_string = toplevel.display().to_string();
+        let untracked = git_string(&[
+            "-C",
+            &toplevel_string,
+            "ls-files",
+            "--others",
+            "--exclude-standard",
+            "-z",
+        ])?;
+        for name in untracked.split('\0').filter(|n| !n.is_empty()) {
+            if let Ok(path) = toplevel.join(name).canonicalize() {
+                files.insert(path, None);
+            }
+        }
+    }
+
+    Ok(ChangedFiles(files))
+}
+
+// The lines of the new version of a file that were added or changed,
+// from the `@@ -a,b +c,d @@` headers of a diff without context
+fn changed_lines(diff_args: &[&str], path: &Path) -> Result<Vec<RangeInclusive<usize>>, String> {
+    let path_string = path.display().to_string();
+    let mut args = diff_args.to_vec();
+    args.extend(["-U0", "--", &path_string]);
+
+    let mut lines = Vec::new();
+    for hunk_header in git_string(&args)?.lines().filter(|l| l.starts_with("@@ ")) {
+        let new_lines = hunk_header
+            .split(' ')
+            .find_map(|part| part.strip_prefix('+'))
+            .unwrap_or("");
+        let (start, count) = match new_lines.split_once(',') {
+            Some((start, count)) => (start.parse(), count.parse()),
+            None => (new_lines.parse(), Ok(1)),
+        };
+
+        match (start, count) {
+            // A count of 0 means lines were only removed
+            (Ok(_), Ok(0)) => {}
+            (Ok(start), Ok(count)) => lines.push(start..=start + count - 1),
+            _ => return Err(format!("couldn't parse the diff hunk `{}`", hunk_header)),
+        }
+    }
+    Ok(lines)
+}
+
+fn git_string(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .map_err(|e| format!("couldn't run git: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr)
+            .trim_end()
+            .to_string());
+    }
+    String::from_utf8(output.stdout).map_err(|e| format!("git's output wasn't UTF-8: {}", e))
+}
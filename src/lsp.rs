@@ -0,0 +1,469 @@
+//! A Language Server Protocol server for `rubyfmt lsp`.
+//!
+//! The server speaks JSON-RPC over STDIN and STDOUT, keeping the Ruby VM that
+//! `rubyfmt_init` starts alive for the whole session so editors don't pay for
+//! it on every save. Documents are synced in full, formatting requests are
+//! answered with line based edits, and any error from formatting a document
+//! is published as a diagnostic on it.
+
+use super::report::error_message;
+use super::{format_options_for, print_warning, rubyfmt_string, CommandlineOpts, ExecutionError};
+use regex::Regex;
+use rubyfmt::RichFormatError;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use similar::{DiffOp, TextDiff};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::process::exit;
+
+// JSON-RPC and LSP error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32803;
+
+// LSP diagnostic severity
+const SEVERITY_ERROR: i64 = 1;
+
+lazy_static! {
+    static ref BLOCK_END_REGEX: Regex = Regex::new(r"\bend$").unwrap();
+}
+
+#[derive(Deserialize)]
+struct TextDocumentIdentifier {
+    uri: String,
+}
+
+#[derive(Deserialize)]
+struct TextDocumentItem {
+    uri: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct Position {
+    line: usize,
+    character: usize,
+}
+
+#[derive(Deserialize)]
+struct Range {
+    start: Position,
+    end: Position,
+}
+
+#[derive(Deserialize)]
+struct ContentChange {
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenParams {
+    text_document: TextDocumentItem,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidChangeParams {
+    text_document: TextDocumentIdentifier,
+    content_changes: Vec<ContentChange>,
+}
+
+// The params for any notification or request that only needs the document
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentParams {
+    text_document: TextDocumentIdentifier,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RangeFormattingParams {
+    text_document: TextDocumentIdentifier,
+    range: Range,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OnTypeFormattingParams {
+    text_document: TextDocumentIdentifier,
+    position: Position,
+    ch: String,
+}
+
+type RequestResult = Result<Value, (i64, String)>;
+
+struct Server<'a> {
+    opts: &'a CommandlineOpts,
+    // The text of each open document, keyed by URI
+    documents: HashMap<String, String>,
+    shutdown_requested: bool,
+}
+
+pub fn run(opts: &CommandlineOpts) -> ! {
+    let mut server = Server {
+        opts,
+        documents: HashMap::new(),
+        shutdown_requested: false,
+    };
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    loop {
+        let body = match read_message(&mut input) {
+            Ok(Some(body)) => body,
+            // The client went away without asking us to exit
+            Ok(None) => exit(1),
+            Err(e) => {
+                eprintln!("rubyfmt lsp couldn't read a message: {}", e);
+                exit(1);
+            }
+        };
+
+        match serde_json::from_slice(&body) {
+            Ok(message) => server.handle_message(message),
+            Err(e) => write_message(&json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": PARSE_ERROR, "message": e.to_string() },
+            })),
+        }
+    }
+}
+
+impl<'a> Server<'a> {
+    fn handle_message(&mut self, message: Value) {
+        let method = match message.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            // We never send requests, so there are no responses to handle
+            None => return,
+        };
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+
+        match message.get("id") {
+            Some(id) => {
+                let result = if self.shutdown_requested {
+                    Err((INVALID_REQUEST, "the server is shutting down".to_string()))
+                } else {
+                    self.handle_request(method, params)
+                };
+                let response = match result {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": message },
+                    }),
+                };
+                write_message(&response);
+            }
+            None => self.handle_notification(method, params),
+        }
+    }
+
+    fn handle_request(&mut self, method: &str, params: Value) -> RequestResult {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": {
+                        "openClose": true,
+                        // Full document sync
+                        "change": 1,
+                        "save": true,
+                    },
+                    "documentFormattingProvider": true,
+                    "documentRangeFormattingProvider": true,
+                    // Triggering on `d` would format as soon as a line reads
+                    // `end`, even if more is about to be typed, like `endpoint`
+                    "documentOnTypeFormattingProvider": {
+                        "firstTriggerCharacter": "\n",
+                    },
+                },
+                "serverInfo": {
+                    "name": "rubyfmt",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            })),
+            "shutdown" => {
+                self.shutdown_requested = true;
+                Ok(Value::Null)
+            }
+            "textDocument/formatting" => {
+                let params: DocumentParams = parse_params(params)?;
+                self.format_edits(&params.text_document.uri, &[])
+            }
+            "textDocument/rangeFormatting" => {
+                let params: RangeFormattingParams = parse_params(params)?;
+                let Range { start, end } = params.range;
+                // LSP lines count from 0, and a range ending at the start of
+                // a line doesn't include that line
+                let end_line = if end.character == 0 && end.line > start.line {
+                    end.line
+                } else {
+                    end.line + 1
+                };
+                self.format_edits(&params.text_document.uri, &[start.line + 1..=end_line])
+            }
+            "textDocument/onTypeFormatting" => {
+                let params: OnTypeFormattingParams = parse_params(params)?;
+                let uri = params.text_document.uri;
+                // A newline is typed after the `end` it finishes
+                let line = if params.ch == "\n" {
+                    params.position.line.checked_sub(1)
+                } else {
+                    None
+                };
+                let typed_line = line.and_then(|line| {
+                    self.documents
+                        .get(&uri)
+                        .and_then(|text| text.lines().nth(line))
+                });
+
+                match (line, typed_line) {
+                    (Some(line), Some(typed_line))
+                        if BLOCK_END_REGEX.is_match(typed_line.trim_end()) =>
+                    {
+                        // Format the statement that the `end` finishes
+                        self.format_edits(&uri, &[line + 1..=line + 1])
+                    }
+                    _ => Ok(json!([])),
+                }
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unsupported method {}", method))),
+        }
+    }
+
+    fn handle_notification(&mut self, method: &str, params: Value) {
+        match method {
+            "exit" => exit(if self.shutdown_requested { 0 } else { 1 }),
+            "textDocument/didOpen" => {
+                if let Ok(params) = parse_params::<DidOpenParams>(params) {
+                    let TextDocumentItem { uri, text } = params.text_document;
+                    self.documents.insert(uri.clone(), text);
+                    let _ = self.format_document(&uri, &[]);
+                }
+            }
+            "textDocument/didChange" => {
+                if let Ok(params) = parse_params::<DidChangeParams>(params) {
+                    if let Some(change) = params.content_changes.into_iter().last() {
+                        self.documents.insert(params.text_document.uri, change.text);
+                    }
+                }
+            }
+            "textDocument/didSave" => {
+                if let Ok(params) = parse_params::<DocumentParams>(params) {
+                    let _ = self.format_document(&params.text_document.uri, &[]);
+                }
+            }
+            "textDocument/didClose" => {
+                if let Ok(params) = parse_params::<DocumentParams>(params) {
+                    let uri = params.text_document.uri;
+                    self.documents.remove(&uri);
+                    publish_diagnostics(&uri, Vec::new());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Formats the given lines of a document, or all of it, publishing
+    // diagnostics for it either way
+    fn format_document(
+        &self,
+        uri: &str,
+        lines: &[RangeInclusive<usize>],
+    ) -> Result<Option<(&str, String)>, (i64, String)> {
+        let text = match self.documents.get(uri) {
+            Some(text) => text,
+            None => return Err((INVALID_PARAMS, format!("{} isn't open", uri))),
+        };
+
+        let path = uri_to_path(uri).unwrap_or_else(|| PathBuf::from(uri));
+        let mut format_options = format_options_for(self.opts, &path).map_err(|e| match e {
+            ExecutionError::ConfigError(e, config_path) => (
+                REQUEST_FAILED,
+                format!(
+                    "Rubyfmt couldn't parse its config file {}: {}",
+                    config_path, e
+                ),
+            ),
+            ExecutionError::IOError(e, config_path) => (
+                REQUEST_FAILED,
+                format!(
+                    "Rubyfmt experienced an IO error reading {}: {}",
+                    config_path, e
+                ),
+            ),
+            _ => (
+                REQUEST_FAILED,
+                "Rubyfmt couldn't find its options".to_string(),
+            ),
+        })?;
+
+        // The editor picks which lines to format, rather than the command line
+        format_options.lines = lines.to_vec();
+        let mut warnings = Vec::new();
+        let formatted = rubyfmt_string(self.opts, &path, text, &format_options, &mut warnings);
+        for warning in &warnings {
+            print_warning(warning, &path);
+        }
+        match formatted {
+            Ok(formatted) => {
+                publish_diagnostics(uri, Vec::new());
+                Ok(formatted.map(|formatted| (text.as_str(), formatted)))
+            }
+            Err(e) => {
+                publish_diagnostics(uri, vec![diagnostic_for(text, &e)]);
+                Ok(None)
+            }
+        }
+    }
+
+    // Edits for each changed group of lines from formatting a document
+    fn format_edits(&self, uri: &str, lines: &[RangeInclusive<usize>]) -> RequestResult {
+        let (before, after) = match self.format_document(uri, lines)? {
+            Some(formatted) => formatted,
+            None => return Ok(json!([])),
+        };
+
+        let diff = TextDiff::from_lines(before, &after);
+        let old_lines = diff.old_slices();
+        let new_lines = diff.new_slices();
+        let position = |line: usize| {
+            if line < old_lines.len() {
+                json!({ "line": line, "character": 0 })
+            } else {
+                end_position(before)
+            }
+        };
+
+        let edits = diff
+            .ops()
+            .iter()
+            .filter(|op| !matches!(op, DiffOp::Equal { .. }))
+            .map(|op| {
+                json!({
+                    "range": {
+                        "start": position(op.old_range().start),
+                        "end": position(op.old_range().end),
+                    },
+                    "newText": new_lines[op.new_range()].concat(),
+                })
+            })
+            .collect();
+        Ok(Value::Array(edits))
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn diagnostic_for(text: &str, err: &RichFormatError) -> Value {
+    // Syntax errors point at where they are, and anything else is about the
+    // whole document
+    let position = match err {
+        RichFormatError::SyntaxError { line, column, .. } => {
+            let line = line.saturating_sub(1);
+            let character = text.lines().nth(line).map_or(0, |text| {
+                text.chars()
+                    .take(column.saturating_sub(1))
+                    .map(char::len_utf16)
+                    .sum()
+            });
+            json!({ "line": line, "character": character })
+        }
+        _ => json!({ "line": 0, "character": 0 }),
+    };
+
+    json!({
+        "range": { "start": position, "end": position },
+        "severity": SEVERITY_ERROR,
+        "code": format!("{:?}", err.as_format_error()),
+        "source": "rubyfmt",
+        "message": error_message(err),
+    })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) {
+    write_message(&json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    }));
+}
+
+// The position after the last character of a document. LSP counts columns
+// in UTF-16 code units.
+fn end_position(text: &str) -> Value {
+    let line = text.matches('\n').count();
+    let last_line = text.rsplit('\n').next().unwrap_or("");
+    json!({ "line": line, "character": last_line.encode_utf16().count() })
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = encoded
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (encoded[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
+
+// Reads the body of the next message, or `None` at the end of input
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(length) = header.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse::<usize>().ok();
+        }
+    }
+
+    let content_length = content_length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    let mut body = vec![0; content_length];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message(message: &Value) {
+    let body = message.to_string();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let written = write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| output.flush());
+    if written.is_err() {
+        // The client has gone away, so there's no one left to talk to
+        exit(1);
+    }
+}
@@ -26,7 +26,7 @@ int main() {
     if (status != 0) {
         exit(status);
     }
-    unsigned char* bytes = rubyfmt_string_ptr(out);
+    const unsigned char* bytes = rubyfmt_string_ptr(out);
     size_t len = rubyfmt_string_len(out);
     fwrite(bytes, sizeof(char), len, stdout);
     rubyfmt_string_free(out);
@@ -3,20 +3,38 @@
 use clap::Parser;
 use ignore::WalkBuilder;
 use regex::Regex;
+use rubyfmt::{FormatOptions, HashStyle, ParenStyle, QuoteStyle};
 use similar::TextDiff;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs::{read_to_string, File, OpenOptions};
 use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::Path;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
 use std::sync::{Arc, Mutex};
 
 #[macro_use]
 extern crate lazy_static;
 
+mod cache;
+mod git;
+mod lsp;
+#[cfg(unix)]
+mod parallel;
+mod reduce;
+mod report;
+
+const CONFIG_FILE_NAME: &str = ".rubyfmt.toml";
+
 lazy_static! {
     static ref MAGIC_COMMENT_REGEX: Regex =
         Regex::new(r"(?m)^#\s*rubyfmt:\s*(?P<enabled>true|false)\s*$").unwrap();
+    // Config files we've already parsed, keyed by their path
+    static ref CONFIG_CACHE: Mutex<HashMap<PathBuf, FormatOptions>> = Mutex::new(HashMap::new());
+    // Files that formatting panicked on, for the summary at the end of a run
+    static ref INTERNAL_ERROR_FILES: Mutex<Vec<String>> = Mutex::new(Vec::new());
 }
 
 /// Simple Enum to exit on errors or not
@@ -29,22 +47,46 @@ enum ErrorExit {
 /// Error enum representing errors in the cli.
 #[derive(Debug)]
 enum ExecutionError {
-    // Errors seen when rubyfmt is executing
-    RubyfmtError(rubyfmt::RichFormatError, String),
+    // Errors seen when rubyfmt is executing, with the line of the file that
+    // a syntax error is on
+    RubyfmtError(rubyfmt::RichFormatError, String, Option<String>),
     // Errors seen when performing IO s
     IOError(io::Error, String),
     // Errors seen when grepping for files
     FileSearchFailure(ignore::Error),
+    // Errors seen when parsing a config file
+    ConfigError(toml::de::Error, String),
+}
+
+impl ExecutionError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            ExecutionError::RubyfmtError(e, ..) => e.as_exit_code(),
+            _ => rubyfmt::FormatError::IOError as i32,
+        }
+    }
 }
 
 /// Rubyfmt CLI
 #[derive(Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct CommandlineOpts {
+    #[clap(subcommand)]
+    command: Option<Subcommand>,
+
     /// Turn on check mode. This outputs diffs of inputs to STDOUT. Will exit non-zero when differences are detected.
     #[clap(short, long)]
     check: bool,
 
+    /// With `--check`, print a report in this format instead of diffs, listing the lines of each file that aren't formatted and any errors.
+    #[clap(long, value_enum, requires = "check")]
+    format: Option<report::ReportFormat>,
+
+    /// Turn on idempotency check mode. This formats each input twice, and outputs diffs between the two passes to STDOUT. Will exit non-zero when differences are detected.
+    #[clap(long, name = "check-idempotent")]
+    check_idempotent: bool,
+
     /// Turn on to ignore gitignored files. Gitignored files are not considered by rubyfmt by default.
     #[clap(long, name = "include-gitignored")]
     include_gitignored: bool,
@@ -65,6 +107,100 @@ struct CommandlineOpts {
     #[clap(short, long, name = "in-place")]
     in_place: bool,
 
+    /// Maximum line length before rubyfmt breaks up a line. Overrides `line_length` from `.rubyfmt.toml`.
+    #[clap(
+        long,
+        name = "line-length",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    line_length: Option<usize>,
+
+    /// Number of spaces for each level of indentation. Overrides `indent_width` from `.rubyfmt.toml`.
+    #[clap(
+        long,
+        name = "indent-width",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    indent_width: Option<usize>,
+
+    /// Which quotes strings are written with: `double`, or `single` for strings without interpolation or escapes. Overrides `quote_style` from `.rubyfmt.toml`.
+    #[clap(long, name = "quote-style", value_name = "STYLE")]
+    quote_style: Option<QuoteStyle>,
+
+    /// How hash keys are written: `labels` where every key in a hash can be a label, or `hash_rockets`. Overrides `hash_style` from `.rubyfmt.toml`.
+    #[clap(long, name = "hash-style", value_name = "STYLE")]
+    hash_style: Option<HashStyle>,
+
+    /// When method calls have parentheses: `normalize` to follow rubyfmt's rules, or `preserve` to keep them as they are. Overrides `paren_style` from `.rubyfmt.toml`.
+    #[clap(long, name = "paren-style", value_name = "STYLE")]
+    paren_style: Option<ParenStyle>,
+
+    /// When a file can't be formatted, output it unchanged to STDOUT and report the error to STDERR, so that rubyfmt is safe to use as a filter. Only applies when printing formatted code to STDOUT.
+    #[clap(
+        long,
+        name = "passthrough-on-error",
+        conflicts_with_all = &["check", "check-idempotent", "in-place"]
+    )]
+    passthrough_on_error: bool,
+
+    /// With `--passthrough-on-error`, the code to exit with when an error stops rubyfmt, such as any error formatting STDIN. Defaults to the error's usual exit code.
+    #[clap(
+        long,
+        name = "passthrough-exit-code",
+        value_name = "CODE",
+        requires = "passthrough-on-error"
+    )]
+    passthrough_exit_code: Option<i32>,
+
+    /// Check that formatting didn't change what each file means, by parsing the formatted output and comparing it with the input. Files that fail are reported as errors and left as they are.
+    #[clap(long)]
+    verify: bool,
+
+    /// Only format the statements touching lines START to END, or the smallest statements enclosing them, and leave the rest of each file as it is. Can be given more than once.
+    #[clap(long, value_name = "START:END", value_parser = parse_line_range)]
+    lines: Vec<RangeInclusive<usize>>,
+
+    /// Only format files that have changed in git since REF, including uncommitted and untracked files.
+    #[clap(long, name = "changed-since", value_name = "REF")]
+    changed_since: Option<String>,
+
+    /// Only format files with changes staged in git.
+    #[clap(long)]
+    staged: bool,
+
+    /// With `--changed-since` or `--staged`, only format the statements touching changed lines, like `--lines`.
+    #[clap(long, name = "changed-lines", conflicts_with = "lines")]
+    changed_lines: bool,
+
+    /// Number of worker processes to format files with. Output is in the same order as with a single worker.
+    #[clap(short, long, default_value = "1")]
+    jobs: usize,
+
+    /// Print one of rubyfmt's intermediate stages for each file instead of formatting it, for debugging rubyfmt: `ripper` is ripper's s-expression as JSON, `ast` is the tree it deserializes to, `tokens` is the render queue before lines are broken up, and `final-tokens` is what gets written out.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "STAGE",
+        conflicts_with_all = &["check", "check-idempotent", "in-place", "passthrough-on-error"]
+    )]
+    dump: Option<DumpStage>,
+
+    /// Explain each decision about whether to break up a call, argument list, array, hash or call chain that affects LINE of the formatted output, instead of formatting. For debugging surprising line breaks.
+    #[clap(
+        long,
+        value_name = "LINE",
+        conflicts_with_all = &["check", "check-idempotent", "in-place", "passthrough-on-error", "dump"]
+    )]
+    explain: Option<usize>,
+
+    /// Don't read or write the cache of files that are already formatted.
+    #[clap(long, name = "no-cache")]
+    no_cache: bool,
+
+    /// Directory for the cache of files that are already formatted. Defaults to a `rubyfmt` directory in the user's cache directory.
+    #[clap(long, name = "cache-location")]
+    cache_location: Option<PathBuf>,
+
     /// Paths for rubyfmt to analyze. By default the output will be printed to STDOUT. See `--in-place` to write files back in-place.
     /// Acceptable paths are:{n}
     /// - File paths (i.e lib/foo/bar.rb){n}
@@ -73,6 +209,34 @@ struct CommandlineOpts {
     /// rubyfmt will use these as input.{n}
     #[clap(name = "include-paths")]
     include_paths: Vec<String>,
+
+    // The files to format when using `--changed-since` or `--staged`
+    #[clap(skip)]
+    changed_files: Option<git::ChangedFiles>,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum Subcommand {
+    /// Run a Language Server Protocol server over STDIN and STDOUT, for formatting from editors.
+    Lsp,
+
+    /// Shrink a file that rubyfmt fails on to a small snippet that fails in the same way, with identifiers renamed so that it can be shared in bug reports.
+    Reduce {
+        /// The file that rubyfmt fails on
+        file: PathBuf,
+
+        /// Write the snippet to this file instead of STDOUT.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Reduce a failure from `--verify`.
+        #[clap(long)]
+        verify: bool,
+
+        /// Don't rename identifiers, for when renaming them makes the error go away.
+        #[clap(long)]
+        keep_identifiers: bool,
+    },
 }
 
 /******************************************************/
@@ -96,7 +260,27 @@ fn handle_ignore_error(err: ignore::Error, error_exit: ErrorExit) {
     }
 }
 
-fn handle_rubyfmt_error(err: rubyfmt::RichFormatError, source: &String, error_exit: ErrorExit) {
+fn handle_git_error(err: String) -> ! {
+    let msg = format!("Rubyfmt couldn't ask git for changed files: {}", err);
+    print_error(&msg, None);
+    exit(rubyfmt::FormatError::IOError as i32);
+}
+
+fn handle_config_error(err: toml::de::Error, source: &String, error_exit: ErrorExit) {
+    let msg = format!("Rubyfmt couldn't parse its config file: {}", err);
+    print_error(&msg, Some(source));
+
+    if error_exit == ErrorExit::Exit {
+        exit(rubyfmt::FormatError::IOError as i32);
+    }
+}
+
+fn handle_rubyfmt_error(
+    err: rubyfmt::RichFormatError,
+    source: &String,
+    source_line: Option<&str>,
+    error_exit: ErrorExit,
+) {
     use rubyfmt::RichFormatError::*;
     let exit_code = err.as_exit_code();
     let e = || {
@@ -105,9 +289,16 @@ fn handle_rubyfmt_error(err: rubyfmt::RichFormatError, source: &String, error_ex
         }
     };
     match err {
-        SyntaxError => {
-            let msg = "Rubyfmt detected a syntax error in the ruby code being executed";
-            print_error(msg, Some(source));
+        SyntaxError {
+            line,
+            column,
+            message,
+        } => {
+            let msg = format!(
+                "Rubyfmt detected a syntax error in the ruby code being executed\n{}",
+                syntax_error_snippet(source, line, column, &message, source_line)
+            );
+            print_error(&msg, Some(source));
             e();
         }
         rubyfmt::RichFormatError::RipperParseFailure(_) => {
@@ -117,7 +308,8 @@ fn handle_rubyfmt_error(err: rubyfmt::RichFormatError, source: &String, error_ex
 Rubyfmt failed to correctly deserialize a tree from ripper. This is a bug that needs to be reported.
 File a bug report at https://github.com/penelopezone/rubyfmt/issues/new.
 Ideally you would include the full source code of the program you ran rubyfmt with.
-If you can't do that for some reason, the best thing you can do is rerun rubyfmt on this program 
+If you can't do that for some reason, `rubyfmt reduce` on the file will shrink it to a small snippet
+with its identifiers renamed that fails in the same way. Otherwise, rerun rubyfmt on this program
 with the debug binary with `2>log_file` on the end and then send us the log file that gets generated.
 ";
             print_error(bug_report, Some(source));
@@ -128,14 +320,83 @@ with the debug binary with `2>log_file` on the end and then send us the log file
             print_error(&msg, Some(source));
             e();
         }
+        VerificationFailed(diff) => {
+            let msg = format!(
+                "Rubyfmt's output doesn't parse to the same program as its input. This is a bug that needs to be reported.
+File a bug report at https://github.com/penelopezone/rubyfmt/issues/new.
+Difference between the original and formatted programs:
+{}",
+                diff
+            );
+            print_error(&msg, Some(source));
+            e();
+        }
         rubyfmt::RichFormatError::OtherRubyError(s) => {
             let msg = format!("Rubyfmt experienced an unexpected ruby error: {}", s);
             print_error(&msg, Some(source));
-            exit(exit_code);
+            e();
+        }
+        InternalError { message, backtrace } => {
+            let msg = format!(
+                "Rubyfmt hit an internal error: {}
+This is a bug that needs to be reported.
+File a bug report at https://github.com/penelopezone/rubyfmt/issues/new.
+Ideally you would include the full source code of the program you ran rubyfmt with,
+or a snippet from `rubyfmt reduce` if you can't share it.
+Backtrace:
+{}",
+                message, backtrace
+            );
+            print_error(&msg, Some(source));
+            INTERNAL_ERROR_FILES.lock().unwrap().push(source.clone());
+            e();
         }
     }
 }
 
+// After a run, list the files that rubyfmt hit internal errors in, since
+// their errors may have been lost among many others
+fn print_internal_error_summary() {
+    if let Some(summary) = report::internal_error_summary(&INTERNAL_ERROR_FILES.lock().unwrap()) {
+        eprintln!("{}", summary);
+    }
+}
+
+// A syntax error in the style of a compiler, pointing at where it is in the
+// line it's on:
+//
+// foo.rb:2:9: syntax error, unexpected end-of-input
+//   |
+// 2 | def foo(
+//   |         ^
+fn syntax_error_snippet(
+    source: &str,
+    line: usize,
+    column: usize,
+    message: &str,
+    source_line: Option<&str>,
+) -> String {
+    let mut snippet = format!("{}:{}:{}: {}", source, line, column, message);
+    if let Some(source_line) = source_line {
+        let gutter = " ".repeat(line.to_string().len());
+        // Keep any tabs before the caret, so that it lines up however wide
+        // they're shown
+        let caret_indent: String = source_line
+            .chars()
+            .take(column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        snippet.push_str(&format!(
+            "\n{gutter} |\n{line} | {source_line}\n{gutter} | {caret_indent}^",
+            gutter = gutter,
+            line = line,
+            source_line = source_line.trim_end(),
+            caret_indent = caret_indent,
+        ));
+    }
+    snippet
+}
+
 fn print_error(msg: &str, file_path: Option<&str>) {
     let mut first_line: String = "Error!".to_string();
 
@@ -146,17 +407,55 @@ fn print_error(msg: &str, file_path: Option<&str>) {
     eprintln!("{}\n{}", first_line, msg);
 }
 
+fn print_warning(msg: &str, file_path: &Path) {
+    eprintln!("Warning! source: {}\n{}", file_path.display(), msg);
+}
+
 fn handle_execution_error(opts: &CommandlineOpts, err: ExecutionError) {
-    let mut exit_type = ErrorExit::NoExit;
-    // If include_paths are empty, this is operating on STDIN which should always exit
-    if opts.fail_fast || opts.include_paths.is_empty() {
-        exit_type = ErrorExit::Exit;
+    let exit_type = error_exit_for(opts, &err);
+    print_execution_error(err, exit_type)
+}
+
+// Whether an error stops rubyfmt, or is only a warning
+fn error_exit_for(opts: &CommandlineOpts, err: &ExecutionError) -> ErrorExit {
+    match err {
+        // If include_paths are empty, this is operating on STDIN which should always exit
+        _ if opts.fail_fast || opts.include_paths.is_empty() => ErrorExit::Exit,
+        // The Ruby VM may not be usable after an unexpected error
+        ExecutionError::RubyfmtError(rubyfmt::RichFormatError::OtherRubyError(_), ..) => {
+            ErrorExit::Exit
+        }
+        _ => ErrorExit::NoExit,
     }
+}
 
+fn print_execution_error(err: ExecutionError, exit_type: ErrorExit) {
     match err {
-        ExecutionError::RubyfmtError(e, path) => handle_rubyfmt_error(e, &path, exit_type),
+        ExecutionError::RubyfmtError(e, path, source_line) => {
+            handle_rubyfmt_error(e, &path, source_line.as_deref(), exit_type)
+        }
         ExecutionError::IOError(e, path) => handle_io_error(e, &path, exit_type),
         ExecutionError::FileSearchFailure(e) => handle_ignore_error(e, exit_type),
+        ExecutionError::ConfigError(e, path) => handle_config_error(e, &path, exit_type),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum DumpStage {
+    Ripper,
+    Ast,
+    Tokens,
+    FinalTokens,
+}
+
+impl From<DumpStage> for rubyfmt::DumpStage {
+    fn from(stage: DumpStage) -> Self {
+        match stage {
+            DumpStage::Ripper => rubyfmt::DumpStage::Ripper,
+            DumpStage::Ast => rubyfmt::DumpStage::Ast,
+            DumpStage::Tokens => rubyfmt::DumpStage::Tokens,
+            DumpStage::FinalTokens => rubyfmt::DumpStage::FinalTokens,
+        }
     }
 }
 
@@ -165,13 +464,70 @@ fn handle_execution_error(opts: &CommandlineOpts, err: ExecutionError) {
 /******************************************************/
 
 fn rubyfmt_string(
+    opts: &CommandlineOpts,
+    _file_path: &Path,
+    buffer: &str,
+    format_options: &FormatOptions,
+    warnings: &mut Vec<String>,
+) -> Result<Option<String>, rubyfmt::RichFormatError> {
+    if !header_allows_formatting(opts, buffer) {
+        return Ok(None);
+    }
+
+    let formatted = rubyfmt::format_buffer_with(buffer, format_options)?;
+    warnings.extend_from_slice(&formatted.warnings);
+    // Only some of the file was formatted if there are lines, so we don't know
+    // that the rest of it is formatted. Files with warnings aren't cached
+    // either, so that the warnings are shown every time.
+    if !formatted.changed && format_options.lines.is_empty() && formatted.warnings.is_empty() {
+        cache::record_formatted(opts, buffer, format_options);
+    }
+    Ok(Some(formatted.output))
+}
+
+// The lines of a file to format, from `--lines` or `--changed-lines`
+fn lines_for<'a>(opts: &'a CommandlineOpts, file_path: &Path) -> &'a [RangeInclusive<usize>] {
+    opts.changed_files
+        .as_ref()
+        .and_then(|changed_files| changed_files.lines_for(file_path))
+        .unwrap_or(&opts.lines)
+}
+
+// The `--dump` output for a file, which is printed whether or not its header
+// allows formatting
+fn rubyfmt_dump(
+    opts: &CommandlineOpts,
+    _file_path: &Path,
+    buffer: &str,
+    format_options: &FormatOptions,
+    _warnings: &mut Vec<String>,
+) -> Result<Option<String>, rubyfmt::RichFormatError> {
+    let stage = opts.dump.expect("only used with --dump");
+    rubyfmt::dump_lines_with_options(buffer, stage.into(), format_options).map(Some)
+}
+
+// The `--explain` output for a file
+fn rubyfmt_explain(
+    opts: &CommandlineOpts,
+    _file_path: &Path,
+    buffer: &str,
+    format_options: &FormatOptions,
+    _warnings: &mut Vec<String>,
+) -> Result<Option<String>, rubyfmt::RichFormatError> {
+    let line = opts.explain.expect("only used with --explain");
+    rubyfmt::explain_line_with_options(buffer, line, format_options).map(Some)
+}
+
+// Whether the magic header comment in a file, if any, allows formatting it
+// under the `--header-opt-in`/`--header-opt-out` flags
+fn header_allows_formatting(
     &CommandlineOpts {
         header_opt_in,
         header_opt_out,
         ..
     }: &CommandlineOpts,
     buffer: &str,
-) -> Result<Option<String>, rubyfmt::RichFormatError> {
+) -> bool {
     if header_opt_in || header_opt_out {
         // Only look at the first 500 bytes for the magic header.
         // This is for performance
@@ -194,20 +550,31 @@ fn rubyfmt_string(
         // If opted in to magic "# rubyfmt: true" header and true is not
         // in the file, return early
         if header_opt_in && Some("true") != matched {
-            return Ok(None);
+            return false;
         }
 
         // If opted in to magic "# rubyfmt: false" header and false is
         // in the file, return early
         if header_opt_out && Some("false") == matched {
-            return Ok(None);
+            return false;
         }
     }
 
-    rubyfmt::format_buffer(buffer).map(Some)
+    true
+}
+
+// This has to happen before the Ruby VM is set up, since the VM only
+// installs its own handler if there isn't one already
+fn install_ctrlc_handler() {
+    ctrlc::set_handler(move || {
+        eprintln!("`rubyfmt` process was terminated. Exiting...");
+        exit(1);
+    })
+    .expect("Error setting Ctrl-C handler");
 }
 
 fn initialize_rubyfmt() {
+    install_ctrlc_handler();
     let res = rubyfmt::rubyfmt_init();
     if res != rubyfmt::InitStatus::OK as libc::c_int {
         panic!(
@@ -237,6 +604,83 @@ fn file_walker_builder(include_paths: Vec<&String>, include_gitignored: bool) ->
     builder
 }
 
+// The options for formatting a file, from its config file and then any
+// options passed on the command line
+fn format_options_for(
+    opts: &CommandlineOpts,
+    file_path: &Path,
+) -> Result<FormatOptions, ExecutionError> {
+    let mut format_options = config_options_for(file_path)?;
+    if let Some(line_length) = opts.line_length {
+        format_options.line_length = line_length;
+    }
+    if let Some(indent_width) = opts.indent_width {
+        format_options.indent_width = indent_width;
+    }
+    if opts.verify {
+        format_options.verify = true;
+    }
+    if let Some(quote_style) = opts.quote_style {
+        format_options.quote_style = quote_style;
+    }
+    if let Some(hash_style) = opts.hash_style {
+        format_options.hash_style = hash_style;
+    }
+    if let Some(paren_style) = opts.paren_style {
+        format_options.paren_style = paren_style;
+    }
+    format_options.lines = lines_for(opts, file_path).to_vec();
+    Ok(format_options)
+}
+
+// Find the options for a file from the nearest config file, looking in the
+// file's directory and then each of its parents, like `.rubyfmtignore`.
+fn config_options_for(file_path: &Path) -> Result<FormatOptions, ExecutionError> {
+    let absolute_path = std::env::current_dir()
+        .map(|cwd| cwd.join(file_path))
+        .unwrap_or_else(|_| file_path.to_path_buf());
+    let config_path = absolute_path
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|path| path.is_file());
+
+    let config_path = match config_path {
+        Some(path) => path,
+        None => return Ok(FormatOptions::default()),
+    };
+
+    let mut cache = CONFIG_CACHE.lock().unwrap();
+    if let Some(format_options) = cache.get(&config_path) {
+        return Ok(format_options.clone());
+    }
+
+    let config_path_string = config_path.display().to_string();
+    let contents = read_to_string(&config_path)
+        .map_err(|e| ExecutionError::IOError(e, config_path_string.clone()))?;
+    let format_options: FormatOptions = toml::from_str(&contents)
+        .map_err(|e| ExecutionError::ConfigError(e, config_path_string))?;
+    cache.insert(config_path, format_options.clone());
+    Ok(format_options)
+}
+
+// Parse a `START:END` range of lines for `--lines`
+fn parse_line_range(s: &str) -> Result<RangeInclusive<usize>, String> {
+    let (start, end) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected START:END, got `{}`", s))?;
+    let start: usize = start
+        .parse()
+        .map_err(|e| format!("invalid start line `{}`: {}", start, e))?;
+    let end: usize = end
+        .parse()
+        .map_err(|e| format!("invalid end line `{}`: {}", end, e))?;
+    if start == 0 || end < start {
+        return Err(format!("expected 1 <= START <= END, got {}:{}", start, end));
+    }
+    Ok(start..=end)
+}
+
 // Parse command line arguments. Expand any input files.
 fn get_command_line_options() -> CommandlineOpts {
     let opts = CommandlineOpts::parse();
@@ -264,13 +708,41 @@ fn get_command_line_options() -> CommandlineOpts {
         }
     }
 
+    let changed_files = if opts.staged || opts.changed_since.is_some() {
+        // Search the current directory for changed files by default,
+        // rather than reading from STDIN
+        if expanded_paths.is_empty() {
+            expanded_paths.push(".".to_string());
+        }
+
+        match git::changed_files(
+            opts.changed_since.as_deref(),
+            opts.staged,
+            opts.changed_lines,
+        ) {
+            Ok(files) => Some(files),
+            Err(e) => handle_git_error(e),
+        }
+    } else {
+        None
+    };
+
     CommandlineOpts {
         include_paths: expanded_paths,
+        changed_files,
         ..opts
     }
 }
 
-fn iterate_input_files(opts: &CommandlineOpts, f: &dyn Fn((&Path, &String))) {
+// Whether a file should be formatted, when only formatting changed files
+fn is_changed_file(opts: &CommandlineOpts, file_path: &Path) -> bool {
+    match &opts.changed_files {
+        Some(changed_files) => changed_files.contains(file_path),
+        None => true,
+    }
+}
+
+fn iterate_input_files(opts: &CommandlineOpts, f: &dyn Fn((&Path, &String)), on_error: ErrorFunc) {
     if opts.include_paths.is_empty() {
         // If not include paths are present, assume user is passing via STDIN
         let mut buffer = String::new();
@@ -288,95 +760,289 @@ fn iterate_input_files(opts: &CommandlineOpts, f: &dyn Fn((&Path, &String))) {
             .expect("reading from stdin to not fail");
         f((Path::new("stdin"), &buffer))
     } else {
-        let mut file_paths = Vec::new();
-        let mut dir_paths = Vec::new();
-        for path in &opts.include_paths {
-            if Path::new(&path).is_file() {
-                file_paths.push(path)
-            } else {
-                dir_paths.push(path)
-            }
-        }
+        iterate_input_paths(
+            opts,
+            &|file_path| match read_input_file(opts, file_path) {
+                Ok(Some(buffer)) => f((file_path, &buffer)),
+                Ok(None) => {}
+                Err(e) => on_error(ExecutionError::IOError(e, file_path.display().to_string())),
+            },
+            on_error,
+        );
+    }
+}
 
-        if !file_paths.is_empty() {
-            for result in file_walker_builder(file_paths, opts.include_gitignored).build() {
-                match result {
-                    Ok(pp) => {
-                        let file_path = pp.path();
-                        let buffer_res = read_to_string(file_path);
+// Calls `f` with the path of each file to format under the include paths,
+// which are either files or directories to search for `.rb` files
+fn iterate_input_paths(opts: &CommandlineOpts, f: &dyn Fn(&Path), on_error: ErrorFunc) {
+    let mut file_paths = Vec::new();
+    let mut dir_paths = Vec::new();
+    for path in &opts.include_paths {
+        if Path::new(&path).is_file() {
+            file_paths.push(path)
+        } else {
+            dir_paths.push(path)
+        }
+    }
 
-                        match buffer_res {
-                            Ok(buffer) => f((file_path, &buffer)),
-                            Err(e) => handle_execution_error(
-                                opts,
-                                ExecutionError::IOError(e, file_path.display().to_string()),
-                            ),
-                        }
+    if !file_paths.is_empty() {
+        for result in file_walker_builder(file_paths, opts.include_gitignored).build() {
+            match result {
+                Ok(pp) => {
+                    let file_path = pp.path();
+                    if is_changed_file(opts, file_path) {
+                        f(file_path)
                     }
-                    Err(e) => handle_execution_error(opts, ExecutionError::FileSearchFailure(e)),
                 }
+                Err(e) => on_error(ExecutionError::FileSearchFailure(e)),
             }
         }
+    }
 
-        if !dir_paths.is_empty() {
-            for result in file_walker_builder(dir_paths, opts.include_gitignored).build() {
-                match result {
-                    Ok(pp) => {
-                        let file_path = pp.path();
-
-                        if file_path.is_file()
-                            && file_path.extension().and_then(OsStr::to_str) == Some("rb")
-                        {
-                            let buffer_res = read_to_string(file_path);
-
-                            match buffer_res {
-                                Ok(buffer) => f((file_path, &buffer)),
-                                Err(e) => handle_execution_error(
-                                    opts,
-                                    ExecutionError::IOError(e, file_path.display().to_string()),
-                                ),
-                            }
-                        }
+    if !dir_paths.is_empty() {
+        for result in file_walker_builder(dir_paths, opts.include_gitignored).build() {
+            match result {
+                Ok(pp) => {
+                    let file_path = pp.path();
+
+                    if file_path.is_file()
+                        && file_path.extension().and_then(OsStr::to_str) == Some("rb")
+                        && is_changed_file(opts, file_path)
+                    {
+                        f(file_path)
                     }
-                    Err(e) => handle_execution_error(opts, ExecutionError::FileSearchFailure(e)),
                 }
+                Err(e) => on_error(ExecutionError::FileSearchFailure(e)),
             }
         }
     }
 }
 
+// The contents of a file to format, or `None` if the cache knows that it's
+// already formatted
+fn read_input_file(opts: &CommandlineOpts, file_path: &Path) -> io::Result<Option<String>> {
+    let buffer = read_to_string(file_path)?;
+    if cache::is_known_formatted(opts, file_path, &buffer) {
+        Ok(None)
+    } else {
+        Ok(Some(buffer))
+    }
+}
+
 type FormattingFunc<'a> = &'a dyn Fn((&Path, &String, Option<String>));
 
+type ErrorFunc<'a> = &'a dyn Fn(ExecutionError);
+
+// The work done for each file, which may happen in a worker process. Any
+// warnings are collected rather than printed, so that they're printed along
+// with the file's result.
+type FileFormatter = fn(
+    &CommandlineOpts,
+    &Path,
+    &str,
+    &FormatOptions,
+    &mut Vec<String>,
+) -> Result<Option<String>, rubyfmt::RichFormatError>;
+
 fn iterate_formatted(opts: &CommandlineOpts, f: FormattingFunc) {
+    iterate_formatted_with(opts, rubyfmt_string, f, &|e| {
+        handle_execution_error(opts, e)
+    })
+}
+
+fn iterate_formatted_with(
+    opts: &CommandlineOpts,
+    formatter: FileFormatter,
+    f: FormattingFunc,
+    on_error: ErrorFunc,
+) {
+    // Standard input is a single file, so there's nothing to parallelize
+    #[cfg(unix)]
+    if opts.jobs > 1 && !opts.include_paths.is_empty() {
+        return parallel::iterate_formatted(opts, formatter, f, on_error);
+    }
+
+    initialize_rubyfmt();
     iterate_input_files(
         opts,
-        &|(file_path, before)| match rubyfmt_string(opts, before) {
+        &|(file_path, before)| match format_input(opts, formatter, file_path, before) {
             Ok(r) => f((file_path, before, r)),
-            Err(e) => handle_execution_error(
-                opts,
-                ExecutionError::RubyfmtError(e, file_path.display().to_string()),
-            ),
+            Err(e) => on_error(e),
         },
+        on_error,
     );
 }
 
+fn format_input(
+    opts: &CommandlineOpts,
+    formatter: FileFormatter,
+    file_path: &Path,
+    buffer: &str,
+) -> Result<Option<String>, ExecutionError> {
+    format_options_for(opts, file_path).and_then(|format_options| {
+        let mut warnings = Vec::new();
+        let result = formatter(opts, file_path, buffer, &format_options, &mut warnings);
+        for warning in &warnings {
+            print_warning(warning, file_path);
+        }
+        result.map_err(|e| rubyfmt_error(e, file_path, buffer))
+    })
+}
+
+// An error from formatting a file, keeping the line a syntax error is on so
+// that it can be shown
+fn rubyfmt_error(err: rubyfmt::RichFormatError, file_path: &Path, buffer: &str) -> ExecutionError {
+    let source_line = match err {
+        rubyfmt::RichFormatError::SyntaxError { line, .. } => buffer
+            .lines()
+            .nth(line.saturating_sub(1))
+            .map(str::to_string),
+        _ => None,
+    };
+    ExecutionError::RubyfmtError(err, file_path.display().to_string(), source_line)
+}
+
 fn puts_stdout(input: &String) {
     write!(io::stdout(), "{}", input).expect("Could not write to stdout");
     io::stdout().flush().expect("flush works");
 }
 
-fn main() {
-    ctrlc::set_handler(move || {
-        eprintln!("`rubyfmt` process was terminated. Exiting...");
-        exit(1);
-    })
-    .expect("Error setting Ctrl-C handler");
+// A diff between formatting a file once and formatting it twice, if they differ
+fn idempotency_diff(
+    opts: &CommandlineOpts,
+    file_path: &Path,
+    buffer: &str,
+    format_options: &FormatOptions,
+    warnings: &mut Vec<String>,
+) -> Result<Option<String>, rubyfmt::RichFormatError> {
+    if !header_allows_formatting(opts, buffer) {
+        return Ok(None);
+    }
+
+    let passes = rubyfmt::check_idempotency(buffer, format_options)?;
+    warnings.extend_from_slice(&passes.warnings);
+    if passes.first_pass == buffer && passes.warnings.is_empty() {
+        cache::record_formatted(opts, buffer, format_options);
+    }
+    Ok(diff_between_passes(file_path, &passes))
+}
+
+// A diff between the two passes of an idempotency check, if they differ
+fn diff_between_passes(file_path: &Path, passes: &rubyfmt::IdempotencyCheck) -> Option<String> {
+    if passes.is_idempotent() {
+        return None;
+    }
 
+    let path_string = file_path.display().to_string();
+    let diff = TextDiff::from_lines(&passes.first_pass, &passes.second_pass);
+    Some(format!(
+        "{}",
+        diff.unified_diff().header(
+            &format!("{} (first pass)", path_string),
+            &format!("{} (second pass)", path_string)
+        )
+    ))
+}
+
+// Print any diffs, exiting non-zero if there were any
+fn exit_with_diffs(all_diffs: &[String]) -> ! {
+    print_internal_error_summary();
+    for diff in all_diffs.iter() {
+        if !diff.is_empty() {
+            puts_stdout(diff);
+        }
+    }
+    exit(diffs_exit_code(all_diffs))
+}
+
+fn diffs_exit_code(all_diffs: &[String]) -> i32 {
+    if all_diffs.iter().any(|diff| !diff.is_empty()) {
+        rubyfmt::FormatError::DiffDetected as i32
+    } else {
+        0
+    }
+}
+
+fn main() {
     let opts = get_command_line_options();
 
     match opts {
-        CommandlineOpts { check: true, .. } => {
+        CommandlineOpts {
+            command: Some(Subcommand::Lsp),
+            ..
+        } => {
+            initialize_rubyfmt();
+            lsp::run(&opts);
+        }
+
+        CommandlineOpts {
+            command:
+                Some(Subcommand::Reduce {
+                    ref file,
+                    ref output,
+                    verify,
+                    keep_identifiers,
+                }),
+            ..
+        } => {
             initialize_rubyfmt();
+            reduce::run(&opts, file, output.as_deref(), verify, keep_identifiers);
+        }
+
+        CommandlineOpts {
+            check_idempotent: true,
+            ..
+        } => {
+            let text_diffs: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+
+            iterate_formatted_with(
+                &opts,
+                idempotency_diff,
+                &|(_, _, diff)| {
+                    if let Some(diff) = diff {
+                        text_diffs.lock().unwrap().push(diff);
+                    }
+                },
+                &|e| handle_execution_error(&opts, e),
+            );
+
+            exit_with_diffs(&text_diffs.lock().unwrap());
+        }
+
+        CommandlineOpts {
+            check: true,
+            format: Some(format),
+            ..
+        } => {
+            let report = RefCell::new(report::Report::default());
+
+            iterate_formatted_with(
+                &opts,
+                rubyfmt_string,
+                &|(file_path, before, after)| {
+                    if let Some(fmtted) = after {
+                        report.borrow_mut().add_file(file_path, before, &fmtted);
+                    }
+                },
+                &|e| {
+                    // Errors that would stop `--check` stop the report early,
+                    // with everything found so far
+                    let error_exit = error_exit_for(&opts, &e);
+                    let mut report = report.borrow_mut();
+                    let exit_code = report.add_error(e);
+                    if error_exit == ErrorExit::Exit {
+                        puts_stdout(&report.render(format));
+                        exit(exit_code);
+                    }
+                },
+            );
+
+            let report = report.into_inner();
+            puts_stdout(&report.render(format));
+            exit(report.exit_code());
+        }
+
+        CommandlineOpts { check: true, .. } => {
             let text_diffs: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
 
             iterate_formatted(&opts, &|(file_path, before, after)| match after {
@@ -391,25 +1057,10 @@ fn main() {
                 }
             });
 
-            let all_diffs = text_diffs.lock().unwrap();
-
-            let mut diffs_reported = 0;
-
-            for diff in all_diffs.iter() {
-                if !diff.is_empty() {
-                    puts_stdout(diff);
-                    diffs_reported += 1
-                }
-            }
-            if diffs_reported > 0 {
-                exit(rubyfmt::FormatError::DiffDetected as i32);
-            } else {
-                exit(0)
-            }
+            exit_with_diffs(&text_diffs.lock().unwrap());
         }
 
         CommandlineOpts { in_place: true, .. } => {
-            initialize_rubyfmt();
             iterate_formatted(&opts, &|(file_path, before, after)| match after {
                 None => {}
                 Some(fmtted) => {
@@ -432,12 +1083,103 @@ fn main() {
             })
         }
 
-        _ => {
+        CommandlineOpts { dump: Some(_), .. } => iterate_formatted_with(
+            &opts,
+            rubyfmt_dump,
+            &|(_, _, dump)| {
+                if let Some(dump) = dump {
+                    puts_stdout(&dump);
+                }
+            },
+            &|e| handle_execution_error(&opts, e),
+        ),
+
+        CommandlineOpts {
+            explain: Some(_), ..
+        } => iterate_formatted_with(
+            &opts,
+            rubyfmt_explain,
+            &|(_, _, explanation)| {
+                if let Some(explanation) = explanation {
+                    puts_stdout(&explanation);
+                }
+            },
+            &|e| handle_execution_error(&opts, e),
+        ),
+
+        CommandlineOpts {
+            passthrough_on_error: true,
+            ..
+        } => {
             initialize_rubyfmt();
-            iterate_formatted(&opts, &|(_, before, after)| match after {
-                Some(fmtted) => puts_stdout(&fmtted),
-                None => puts_stdout(before),
-            })
+            let on_error = |e: ExecutionError| {
+                let error_exit = error_exit_for(&opts, &e);
+                let exit_code = e.exit_code();
+                print_execution_error(e, ErrorExit::NoExit);
+                if error_exit == ErrorExit::Exit {
+                    exit(opts.passthrough_exit_code.unwrap_or(exit_code));
+                }
+            };
+
+            // Files are formatted one at a time here, since each file's
+            // contents need to be at hand to output them if there's an error
+            iterate_input_files(
+                &opts,
+                &|(file_path, before)| match format_input(&opts, rubyfmt_string, file_path, before)
+                {
+                    Ok(Some(fmtted)) => puts_stdout(&fmtted),
+                    Ok(None) => puts_stdout(before),
+                    Err(e) => {
+                        // Output the file as it was, so that nothing is lost
+                        if let ExecutionError::RubyfmtError(..) = e {
+                            puts_stdout(before);
+                        }
+                        on_error(e);
+                    }
+                },
+                &on_error,
+            )
         }
+
+        _ => iterate_formatted(&opts, &|(_, before, after)| match after {
+            Some(fmtted) => puts_stdout(&fmtted),
+            None => puts_stdout(before),
+        }),
+    }
+
+    print_internal_error_summary();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_idempotent_diffs_the_two_passes() {
+        let passes = rubyfmt::IdempotencyCheck {
+            first_pass: "a(\n  1\n)\n".to_string(),
+            second_pass: "a(1)\n".to_string(),
+            warnings: vec![],
+        };
+
+        let diff = diff_between_passes(Path::new("a.rb"), &passes).expect("a diff");
+
+        assert_eq!(
+            diff,
+            "--- a.rb (first pass)\n+++ a.rb (second pass)\n@@ -1,3 +1 @@\n-a(\n-  1\n-)\n+a(1)\n"
+        );
+        assert_ne!(diffs_exit_code(&[diff]), 0);
+    }
+
+    #[test]
+    fn check_idempotent_passes_idempotent_files() {
+        let passes = rubyfmt::IdempotencyCheck {
+            first_pass: "a(1)\n".to_string(),
+            second_pass: "a(1)\n".to_string(),
+            warnings: vec![],
+        };
+
+        assert_eq!(diff_between_passes(Path::new("a.rb"), &passes), None);
+        assert_eq!(diffs_exit_code(&[]), 0);
     }
 }
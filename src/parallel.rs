@@ -0,0 +1,339 @@
+//! Formatting files across several worker processes for `--jobs`.
+//!
+//! Rubyfmt embeds a single Ruby VM, so workers are forked from the main
+//! process before it sets up the VM or starts any threads, and each worker
+//! sets up its own VM. Each worker reads and formats every `jobs`th file and
+//! writes its results back over a pipe as JSON. Results are handled in the
+//! main process in input order as they arrive, so output, warnings, diffs,
+//! errors and exit codes are the same as formatting with a single process.
+
+use super::{
+    format_options_for, initialize_rubyfmt, install_ctrlc_handler, iterate_input_paths,
+    print_warning, read_input_file, rubyfmt_error, CommandlineOpts, ErrorFunc, ExecutionError,
+    FileFormatter, FormattingFunc,
+};
+use rubyfmt::{FormatOptions, RichFormatError};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::iter::{Enumerate, Peekable};
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::vec;
+
+struct InputFile {
+    path: PathBuf,
+    format_options: Result<FormatOptions, ExecutionError>,
+}
+
+type Inputs = Peekable<Enumerate<vec::IntoIter<InputFile>>>;
+
+pub fn iterate_formatted(
+    opts: &CommandlineOpts,
+    formatter: FileFormatter,
+    f: FormattingFunc,
+    on_error: ErrorFunc,
+) {
+    // Only the paths are collected up front, since workers read the files
+    let inputs = RefCell::new(Vec::new());
+    iterate_input_paths(
+        opts,
+        &|file_path| {
+            inputs.borrow_mut().push(InputFile {
+                path: file_path.to_path_buf(),
+                format_options: format_options_for(opts, file_path),
+            })
+        },
+        on_error,
+    );
+    let inputs = inputs.into_inner();
+
+    let jobs = opts.jobs.min(inputs.len()).max(1);
+    // Only the thread that forks carries on in a forked process, so every
+    // worker is forked before the Ctrl-C handler or anything else starts a
+    // thread that could be holding a lock when it's forked
+    let workers: Vec<_> = (0..jobs)
+        .map(|worker| fork_worker(opts, formatter, &inputs, worker, jobs))
+        .collect();
+    install_ctrlc_handler();
+
+    let (sender, records) = mpsc::channel();
+    let pids: Vec<_> = workers
+        .into_iter()
+        .map(|(pid, input)| {
+            let sender = sender.clone();
+            thread::spawn(move || send_records(input, sender));
+            pid
+        })
+        .collect();
+    // The records run out once every worker's pipe has been read to the end
+    drop(sender);
+
+    let mut inputs = inputs.into_iter().enumerate().peekable();
+    let mut pending = HashMap::new();
+    for record in records {
+        pending.insert(record.index, record.outcome);
+        handle_ready_inputs(&mut inputs, &mut pending, f, on_error);
+    }
+    for pid in pids {
+        unsafe {
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+    }
+
+    // Any files left weren't formatted, because their worker exited early
+    for (index, input) in inputs {
+        handle_outcome(input, pending.remove(&index), f, on_error);
+    }
+}
+
+// Handles inputs in order for as long as the next one's outcome has arrived.
+// Workers skip files without options, since those only have an error.
+fn handle_ready_inputs(
+    inputs: &mut Inputs,
+    pending: &mut HashMap<usize, WorkerOutcome>,
+    f: FormattingFunc,
+    on_error: ErrorFunc,
+) {
+    while let Some((index, input)) = inputs.peek() {
+        if input.format_options.is_ok() && !pending.contains_key(index) {
+            return;
+        }
+
+        let (index, input) = inputs.next().expect("this input was just peeked at");
+        handle_outcome(input, pending.remove(&index), f, on_error);
+    }
+}
+
+// Handles what a worker did with a file, which is `None` if the worker exited
+// before getting to it
+fn handle_outcome(
+    input: InputFile,
+    outcome: Option<WorkerOutcome>,
+    f: FormattingFunc,
+    on_error: ErrorFunc,
+) {
+    let InputFile {
+        path,
+        format_options,
+    } = input;
+    if let Err(e) = format_options {
+        return on_error(e);
+    }
+
+    match outcome {
+        None => on_error(rubyfmt_error(
+            RichFormatError::OtherRubyError(
+                "a worker process exited before formatting this file".to_string(),
+            ),
+            &path,
+            "",
+        )),
+        Some(WorkerOutcome::KnownFormatted) => {}
+        Some(WorkerOutcome::ReadFailed(message)) => on_error(ExecutionError::IOError(
+            io::Error::other(message),
+            path.display().to_string(),
+        )),
+        Some(WorkerOutcome::Formatted {
+            buffer,
+            warnings,
+            result,
+        }) => {
+            for warning in &warnings {
+                print_warning(warning, &path);
+            }
+            match result {
+                Ok(r) => f((&path, &buffer, r)),
+                Err(e) => on_error(rubyfmt_error(e.into(), &path, &buffer)),
+            }
+        }
+    }
+}
+
+// Forks a worker that formats the files at `worker`, `worker + jobs`, ... and
+// returns its pid along with the pipe it writes its results to
+fn fork_worker(
+    opts: &CommandlineOpts,
+    formatter: FileFormatter,
+    inputs: &[InputFile],
+    worker: usize,
+    jobs: usize,
+) -> (libc::pid_t, File) {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        panic!("couldn't create a pipe: {}", io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+
+    match unsafe { libc::fork() } {
+        -1 => panic!("couldn't fork a worker: {}", io::Error::last_os_error()),
+        0 => {
+            unsafe {
+                libc::close(read_fd);
+            }
+            let output = unsafe { File::from_raw_fd(write_fd) };
+            // Never return from here: the worker must not carry on as if it
+            // were the main process, even if formatting panics
+            let status = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                run_worker(opts, formatter, inputs, worker, jobs, output)
+            }));
+            let code = match status {
+                Ok(Ok(())) => 0,
+                _ => 1,
+            };
+            unsafe { libc::_exit(code) }
+        }
+        pid => {
+            unsafe {
+                libc::close(write_fd);
+            }
+            (pid, unsafe { File::from_raw_fd(read_fd) })
+        }
+    }
+}
+
+fn run_worker(
+    opts: &CommandlineOpts,
+    formatter: FileFormatter,
+    inputs: &[InputFile],
+    worker: usize,
+    jobs: usize,
+    output: File,
+) -> io::Result<()> {
+    initialize_rubyfmt();
+
+    let mut output = BufWriter::new(output);
+    for (index, input) in inputs.iter().enumerate().skip(worker).step_by(jobs) {
+        if let Ok(format_options) = &input.format_options {
+            let outcome = match read_input_file(opts, &input.path) {
+                Ok(Some(buffer)) => {
+                    let mut warnings = Vec::new();
+                    let result =
+                        formatter(opts, &input.path, &buffer, format_options, &mut warnings);
+                    WorkerOutcome::Formatted {
+                        buffer,
+                        warnings,
+                        result: result.map_err(|e| WorkerError::from(&e)),
+                    }
+                }
+                Ok(None) => WorkerOutcome::KnownFormatted,
+                Err(e) => WorkerOutcome::ReadFailed(e.to_string()),
+            };
+            serde_json::to_writer(&mut output, &WorkerRecord { index, outcome })?;
+            output.write_all(b"\n")?;
+            // The main process handles each result as soon as it's its turn
+            output.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// Sends each record a worker writes to the main thread as it arrives. A
+// worker that died part way through leaves a truncated final record, which
+// is ignored.
+fn send_records(input: File, sender: mpsc::Sender<WorkerRecord>) {
+    let mut input = BufReader::new(input);
+    for record in serde_json::Deserializer::from_reader(&mut input).into_iter::<WorkerRecord>() {
+        let sent = record.map(|record| sender.send(record));
+        if !matches!(sent, Ok(Ok(()))) {
+            break;
+        }
+    }
+    // Keep reading, so that the worker can't block on a full pipe
+    let _ = io::copy(&mut input, &mut io::sink());
+}
+
+// What a worker did with one file, which workers write one per line
+#[derive(Serialize, Deserialize)]
+struct WorkerRecord {
+    index: usize,
+    outcome: WorkerOutcome,
+}
+
+#[derive(Serialize, Deserialize)]
+enum WorkerOutcome {
+    // The cache knows the file is already formatted
+    KnownFormatted,
+    // The file couldn't be read, with why
+    ReadFailed(String),
+    // The file's contents, and what formatting them produced
+    Formatted {
+        buffer: String,
+        warnings: Vec<String>,
+        result: Result<Option<String>, WorkerError>,
+    },
+}
+
+// A `RichFormatError`, with the errors from other crates that it wraps
+// replaced by their messages so that it can be serialized
+#[derive(Serialize, Deserialize)]
+enum WorkerError {
+    SyntaxError {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    RipperParseFailure(String),
+    IOError(String),
+    OtherRubyError(String),
+    VerificationFailed(String),
+    InternalError {
+        message: String,
+        backtrace: String,
+    },
+}
+
+impl From<&RichFormatError> for WorkerError {
+    fn from(e: &RichFormatError) -> Self {
+        match e {
+            RichFormatError::SyntaxError {
+                line,
+                column,
+                message,
+            } => WorkerError::SyntaxError {
+                line: *line,
+                column: *column,
+                message: message.clone(),
+            },
+            RichFormatError::RipperParseFailure(e) => {
+                WorkerError::RipperParseFailure(e.to_string())
+            }
+            RichFormatError::IOError(e) => WorkerError::IOError(e.to_string()),
+            RichFormatError::OtherRubyError(s) => WorkerError::OtherRubyError(s.clone()),
+            RichFormatError::VerificationFailed(s) => WorkerError::VerificationFailed(s.clone()),
+            RichFormatError::InternalError { message, backtrace } => WorkerError::InternalError {
+                message: message.clone(),
+                backtrace: backtrace.clone(),
+            },
+        }
+    }
+}
+
+impl From<WorkerError> for RichFormatError {
+    fn from(e: WorkerError) -> Self {
+        match e {
+            WorkerError::SyntaxError {
+                line,
+                column,
+                message,
+            } => RichFormatError::SyntaxError {
+                line,
+                column,
+                message,
+            },
+            WorkerError::RipperParseFailure(s) => {
+                RichFormatError::RipperParseFailure(serde::de::Error::custom(s))
+            }
+            WorkerError::IOError(s) => RichFormatError::IOError(io::Error::other(s)),
+            WorkerError::OtherRubyError(s) => RichFormatError::OtherRubyError(s),
+            WorkerError::VerificationFailed(s) => RichFormatError::VerificationFailed(s),
+            WorkerError::InternalError { message, backtrace } => {
+                RichFormatError::InternalError { message, backtrace }
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! `rubyfmt reduce`, which shrinks a file that rubyfmt fails on to a small
+//! snippet that fails in the same way, for attaching to bug reports.
+//!
+//! Lines are removed with delta debugging, along with whole indented blocks
+//! (a line, everything indented under it, and its closing `end`) since those
+//! usually have to go at once for the rest of the file to still parse.
+//! Identifiers are then renamed to meaningless names of the same length, so
+//! that the snippet doesn't give away anything about the code it came from
+//! and lines break in the same places, unless `--keep-identifiers` is given.
+
+use super::{format_options_for, print_error, puts_stdout, CommandlineOpts};
+use regex::Regex;
+use rubyfmt::RichFormatError;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_to_string, write};
+use std::path::Path;
+use std::process::exit;
+
+lazy_static! {
+    static ref WORD_REGEX: Regex = Regex::new(r"\b[A-Za-z_][A-Za-z0-9_]*").unwrap();
+    // Words that mean something to ruby or rubyfmt, which can't be renamed
+    // without changing how the snippet parses or is formatted
+    static ref RESERVED_WORDS: HashSet<&'static str> = vec![
+        // Keywords
+        "BEGIN", "END", "__ENCODING__", "__END__", "__FILE__", "__LINE__", "__method__",
+        "alias", "and", "begin", "break", "case", "class", "def", "defined", "do", "else",
+        "elsif", "end", "ensure", "false", "for", "if", "in", "module", "next", "nil", "not",
+        "or", "raise", "redo", "rescue", "retry", "return", "self", "super", "then", "true", "undef",
+        "unless", "until", "when", "while", "yield",
+        // Methods that rubyfmt formats specially
+        "RSpec", "describe", "it", "test", "gem", "source", "ruby", "group", "require",
+        "require_relative", "lambda", "proc", "private", "protected", "public",
+        "attr_reader", "attr_writer", "attr_accessor",
+        // Magic comments
+        "rubyfmt", "disable", "enable", "frozen_string_literal", "encoding", "coding",
+    ]
+    .into_iter()
+    .collect();
+}
+
+pub fn run(
+    opts: &CommandlineOpts,
+    file: &Path,
+    output: Option<&Path>,
+    verify: bool,
+    keep_identifiers: bool,
+) -> ! {
+    let file_string = file.display().to_string();
+    let buffer = match read_to_string(file) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            print_error(
+                &format!("Rubyfmt couldn't read the file to reduce: {}", e),
+                Some(&file_string),
+            );
+            exit(rubyfmt::FormatError::IOError as i32);
+        }
+    };
+    let mut format_options = format_options_for(opts, file).unwrap_or_default();
+    format_options.verify |= verify;
+    // Line numbers change as lines are removed, so the whole file is formatted
+    format_options.lines.clear();
+
+    let failure = match rubyfmt::format_buffer_with_options(&buffer, &format_options) {
+        Ok(_) => {
+            print_error(
+                "Rubyfmt formats this file without any errors, so there's nothing to reduce",
+                Some(&file_string),
+            );
+            exit(1);
+        }
+        Err(RichFormatError::SyntaxError { .. }) => {
+            print_error(
+                "This file has a syntax error, which needs fixing before it can be reduced",
+                Some(&file_string),
+            );
+            exit(rubyfmt::FormatError::SyntaxError as i32);
+        }
+        Err(e) => e,
+    };
+    let fails = |lines: &[String]| {
+        let candidate = lines.concat();
+        match rubyfmt::format_buffer_with_options(&candidate, &format_options) {
+            Ok(_) => false,
+            Err(e) => same_failure(&failure, &e),
+        }
+    };
+
+    let original_line_count = buffer.lines().count();
+    let mut lines: Vec<String> = buffer.split_inclusive('\n').map(String::from).collect();
+    loop {
+        let line_count = lines.len();
+        lines = remove_blocks(lines, &fails);
+        lines = remove_lines(lines, &fails);
+        if lines.len() == line_count {
+            break;
+        }
+    }
+
+    let reduced = lines.concat();
+    let snippet = if keep_identifiers {
+        reduced
+    } else {
+        let anonymised = anonymise(&reduced);
+        if !fails(std::slice::from_ref(&anonymised)) {
+            // The snippet would give away the code it came from, so it's only
+            // written when that's been asked for
+            print_error(
+                "Rubyfmt couldn't rename identifiers without the error going away. Run with \
+                 `--keep-identifiers` to write the snippet with its original identifiers, and \
+                 check it doesn't contain anything private before sharing it",
+                Some(&file_string),
+            );
+            exit(1);
+        }
+        anonymised
+    };
+
+    eprintln!(
+        "Reduced {} from {} to {} lines, which still fail with {:?}",
+        file_string,
+        original_line_count,
+        snippet.lines().count(),
+        failure.as_format_error()
+    );
+    match output {
+        Some(output) => {
+            if let Err(e) = write(output, &snippet) {
+                print_error(
+                    &format!("Rubyfmt couldn't write the reduced snippet: {}", e),
+                    Some(&output.display().to_string()),
+                );
+                exit(rubyfmt::FormatError::IOError as i32);
+            }
+        }
+        None => puts_stdout(&snippet),
+    }
+    exit(0)
+}
+
+// Whether an error is the same failure as the one being reduced. Panics are
+// only the same if they come from the same place in rubyfmt, since a
+// different panic is a different bug.
+fn same_failure(failure: &RichFormatError, e: &RichFormatError) -> bool {
+    match (failure, e) {
+        (
+            RichFormatError::InternalError { message, .. },
+            RichFormatError::InternalError { message: other, .. },
+        ) => panic_location(message) == panic_location(other),
+        _ => failure.as_format_error() as i32 == e.as_format_error() as i32,
+    }
+}
+
+fn panic_location(message: &str) -> &str {
+    message.rsplit(" at ").next().unwrap_or(message)
+}
+
+// Removes each indented block that can be removed, outermost first
+fn remove_blocks(mut lines: Vec<String>, fails: &dyn Fn(&[String]) -> bool) -> Vec<String> {
+    let mut start = 0;
+    while start < lines.len() {
+        if let Some(end) = block_end(&lines, start) {
+            let candidate: Vec<String> = lines[..start]
+                .iter()
+                .chain(&lines[end + 1..])
+                .cloned()
+                .collect();
+            if fails(&candidate) {
+                lines = candidate;
+                continue;
+            }
+        }
+        start += 1;
+    }
+    lines
+}
+
+// The line closing a block that starts at `start`, which is the next line
+// that isn't indented more than it, if that line closes something
+fn block_end(lines: &[String], start: usize) -> Option<usize> {
+    fn indent(line: &str) -> Option<usize> {
+        if line.trim().is_empty() {
+            None
+        } else {
+            Some(line.len() - line.trim_start().len())
+        }
+    }
+
+    let start_indent = indent(&lines[start])?;
+    let (end, end_line) = lines
+        .iter()
+        .enumerate()
+        .skip(start + 1)
+        .find(|(_, line)| indent(line).is_some_and(|indent| indent <= start_indent))?;
+
+    let closes = ["end", "}", "]", ")"]
+        .iter()
+        .any(|closer| end_line.trim_start().starts_with(closer));
+    if end > start + 1 && indent(end_line) == Some(start_indent) && closes {
+        Some(end)
+    } else {
+        None
+    }
+}
+
+// Delta debugging: removes chunks of lines while the failure still happens,
+// halving the size of the chunks whenever none of them can be removed
+fn remove_lines(mut lines: Vec<String>, fails: &dyn Fn(&[String]) -> bool) -> Vec<String> {
+    let mut chunks = 2;
+    while lines.len() >= 2 {
+        let chunk_size = lines.len().div_ceil(chunks);
+        let mut removed_any = false;
+
+        let mut start = 0;
+        while start < lines.len() {
+            let end = (start + chunk_size).min(lines.len());
+            let candidate: Vec<String> = lines[..start]
+                .iter()
+                .chain(&lines[end..])
+                .cloned()
+                .collect();
+            if fails(&candidate) {
+                lines = candidate;
+                removed_any = true;
+            } else {
+                start = end;
+            }
+        }
+
+        if removed_any {
+            chunks = (chunks - 1).max(2);
+        } else if chunk_size == 1 {
+            break;
+        } else {
+            chunks = (chunks * 2).min(lines.len());
+        }
+    }
+    lines
+}
+
+// Renames every identifier, including in strings and comments, to a name of
+// the same length made up from its position in the alphabet. Each word is
+// always renamed to the same thing, so the snippet still means the same.
+fn anonymise(buffer: &str) -> String {
+    let mut renames: HashMap<&str, String> = HashMap::new();
+    let mut output = String::with_capacity(buffer.len());
+    let mut last_end = 0;
+
+    // Kept words stay as they are, so no rename can be one of them, even one
+    // picked before the kept word turns up
+    let mut used: HashSet<String> = WORD_REGEX
+        .find_iter(buffer)
+        .filter(|word| is_kept_word(buffer, word))
+        .map(|word| word.as_str().to_string())
+        .collect();
+
+    for word in WORD_REGEX.find_iter(buffer) {
+        output.push_str(&buffer[last_end..word.start()]);
+        last_end = word.end();
+
+        let name = word.as_str();
+        if is_kept_word(buffer, &word) {
+            output.push_str(name);
+            continue;
+        }
+
+        let renamed = renames.entry(name).or_insert_with(|| {
+            let uppercase = name.starts_with(|c: char| c.is_ascii_uppercase());
+            let candidate = (0..)
+                .map(|n| anonymous_name(n, name.len(), uppercase))
+                .find(|candidate| {
+                    !RESERVED_WORDS.contains(candidate.as_str()) && !used.contains(candidate)
+                })
+                .unwrap();
+            used.insert(candidate.clone());
+            candidate
+        });
+        output.push_str(renamed);
+    }
+
+    output.push_str(&buffer[last_end..]);
+    output
+}
+
+// Escapes, percent literals, regexp options and numbered block parameters
+// don't name anything, so they're left as they are along with reserved words
+fn is_kept_word(buffer: &str, word: &regex::Match) -> bool {
+    let preceding = buffer[..word.start()].chars().next_back();
+    let name = word.as_str();
+    RESERVED_WORDS.contains(name)
+        || matches!(preceding, Some('\\') | Some('%'))
+        || (preceding == Some('/') && name.chars().all(|c| "imxounse".contains(c)))
+        || (name.starts_with('_') && name[1..].chars().all(|c| c.is_ascii_digit()))
+}
+
+// The `n`th name made of letters, which is `len` letters long until there
+// aren't enough of those, and then as long as it needs to be
+fn anonymous_name(mut n: usize, len: usize, uppercase: bool) -> String {
+    let mut letters = Vec::new();
+    while n > 0 || letters.len() < len {
+        letters.push((b'a' + (n % 26) as u8) as char);
+        n /= 26;
+    }
+    letters.reverse();
+    if uppercase {
+        letters[0] = letters[0].to_ascii_uppercase();
+    }
+    letters.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(buffer: &str) -> Vec<String> {
+        buffer.split_inclusive('\n').map(String::from).collect()
+    }
+
+    #[test]
+    fn remove_lines_keeps_only_what_fails() {
+        let fails = |lines: &[String]| lines.concat().contains("boom\n");
+
+        let reduced = remove_lines(lines("a\nboom\nc\nd\n"), &fails);
+
+        assert_eq!(reduced, lines("boom\n"));
+    }
+
+    #[test]
+    fn remove_lines_keeps_lines_that_fail_together() {
+        let fails = |lines: &[String]| {
+            let buffer = lines.concat();
+            buffer.contains("open\n") && buffer.contains("close\n")
+        };
+
+        let reduced = remove_lines(lines("a\nopen\nb\nc\nclose\nd\n"), &fails);
+
+        assert_eq!(reduced, lines("open\nclose\n"));
+    }
+
+    #[test]
+    fn block_end_finds_the_closing_line() {
+        let buffer = lines("def a\n  b\n\n  c\nend\nd\n");
+
+        assert_eq!(block_end(&buffer, 0), Some(4));
+    }
+
+    #[test]
+    fn block_end_needs_a_body_and_a_closer() {
+        assert_eq!(block_end(&lines("a\nend\n"), 0), None);
+        assert_eq!(block_end(&lines("a\n  b\nc\n"), 0), None);
+        assert_eq!(block_end(&lines("\n  b\nend\n"), 0), None);
+    }
+
+    #[test]
+    fn anonymise_renames_identifiers_consistently() {
+        let anonymised = anonymise("def foo(bar)\n  raise Error, bar # bar\nend\n");
+
+        assert_eq!(anonymised, "def aaa(aab)\n  raise Aaaaa, aab # aab\nend\n");
+    }
+
+    #[test]
+    fn anonymise_keeps_escapes_and_numbered_parameters() {
+        let anonymised = anonymise("x = \"\\n\" + %w[y].map { _1 }\n");
+
+        assert_eq!(anonymised, "a = \"\\n\" + %w[b].aaa { _1 }\n");
+    }
+
+    #[test]
+    fn anonymise_never_renames_to_a_kept_word() {
+        let anonymised = anonymise("foo = \"\\aaa\" + bar\n");
+
+        assert_eq!(anonymised, "aab = \"\\aaa\" + aac\n");
+    }
+
+    #[test]
+    fn anonymous_name_counts_in_letters() {
+        assert_eq!(anonymous_name(0, 3, false), "aaa");
+        assert_eq!(anonymous_name(1, 3, false), "aab");
+        assert_eq!(anonymous_name(27, 1, false), "bb");
+        assert_eq!(anonymous_name(0, 3, true), "Aaa");
+    }
+}
@@ -0,0 +1,434 @@
+//! Machine readable reports of `--check` results for `--format`.
+//!
+//! A report lists each file that isn't formatted, with the ranges of its
+//! lines that formatting would change, and each error seen along the way.
+//! It's printed once every file has been checked, so that the output is a
+//! single document that CI and code review tools can parse.
+
+use super::ExecutionError;
+use rubyfmt::{FormatError, RichFormatError};
+use serde::Serialize;
+use serde_json::json;
+use similar::{DiffOp, TextDiff};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    Json,
+    Checkstyle,
+    Sarif,
+    Github,
+}
+
+// The rules a SARIF report can refer to: one for unformatted lines, and one
+// for each kind of error
+const RULES: &[(&str, &str)] = &[
+    (
+        UNFORMATTED_RULE,
+        "Lines that rubyfmt would format differently",
+    ),
+    (
+        "SyntaxError",
+        "A syntax error in the ruby code being formatted",
+    ),
+    (
+        "RipperParseFailure",
+        "A tree from ripper that rubyfmt couldn't deserialize",
+    ),
+    ("IOError", "An error reading files, or their config"),
+    ("OtherRubyError", "An unexpected error from ruby"),
+    (
+        "VerificationFailed",
+        "Formatted output that doesn't parse to the same program as its input",
+    ),
+    ("InternalError", "A bug that made rubyfmt panic"),
+];
+
+const UNFORMATTED_RULE: &str = "Unformatted";
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    files: Vec<UnformattedFile>,
+    errors: Vec<ReportedError>,
+    // Internal errors are bugs, so they're summarized again at the end of
+    // the report, where they won't be lost among the other errors
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    internal_error_files: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct UnformattedFile {
+    path: String,
+    lines: Vec<LineRange>,
+}
+
+#[derive(Debug, Serialize)]
+struct LineRange {
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportedError {
+    kind: String,
+    // The exit code this error would have if it stopped rubyfmt
+    #[serde(skip)]
+    exit_code: i32,
+    path: Option<String>,
+    line: Option<usize>,
+    column: Option<usize>,
+    message: String,
+}
+
+// A single problem to point at, in the shape most report formats want
+struct Annotation<'a> {
+    rule: &'a str,
+    path: Option<&'a str>,
+    lines: Option<(usize, usize)>,
+    column: Option<usize>,
+    message: String,
+}
+
+impl Report {
+    pub fn add_file(&mut self, file_path: &Path, before: &str, after: &str) {
+        let lines = changed_lines(before, after);
+        if !lines.is_empty() {
+            self.files.push(UnformattedFile {
+                path: file_path.display().to_string(),
+                lines: lines
+                    .into_iter()
+                    .map(|lines| LineRange {
+                        start: *lines.start(),
+                        end: *lines.end(),
+                    })
+                    .collect(),
+            });
+        }
+    }
+
+    // Adds an error to the report, returning the exit code it would have if
+    // it stopped rubyfmt
+    pub fn add_error(&mut self, err: ExecutionError) -> i32 {
+        let (format_error, path, location, message) = match err {
+            ExecutionError::RubyfmtError(e, path, _) => {
+                let location = match e {
+                    RichFormatError::SyntaxError { line, column, .. } => Some((line, column)),
+                    _ => None,
+                };
+                (e.as_format_error(), Some(path), location, error_message(&e))
+            }
+            ExecutionError::IOError(e, path) => (
+                FormatError::IOError,
+                Some(path),
+                None,
+                format!("Rubyfmt experienced an IO error: {}", e),
+            ),
+            ExecutionError::FileSearchFailure(e) => (
+                FormatError::IOError,
+                None,
+                None,
+                format!("Rubyfmt experienced an error searching for files: {}", e),
+            ),
+            ExecutionError::ConfigError(e, path) => (
+                FormatError::IOError,
+                Some(path),
+                // toml counts lines and columns from 0
+                e.line_col().map(|(line, column)| (line + 1, column + 1)),
+                format!("Rubyfmt couldn't parse its config file: {}", e),
+            ),
+        };
+
+        if let (FormatError::InternalError, Some(path)) = (format_error, &path) {
+            self.internal_error_files.push(path.clone());
+        }
+
+        self.errors.push(ReportedError {
+            kind: format!("{:?}", format_error),
+            exit_code: format_error as i32,
+            path,
+            line: location.map(|(line, _)| line),
+            column: location.map(|(_, column)| column),
+            message,
+        });
+        format_error as i32
+    }
+
+    // Errors that didn't stop rubyfmt still fail the check, and take
+    // precedence over unformatted files
+    pub fn exit_code(&self) -> i32 {
+        if let Some(error) = self.errors.first() {
+            error.exit_code
+        } else if !self.files.is_empty() {
+            FormatError::DiffDetected as i32
+        } else {
+            0
+        }
+    }
+
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Json => format!("{}\n", serde_json::to_string_pretty(self).unwrap()),
+            ReportFormat::Checkstyle => self.render_checkstyle(),
+            ReportFormat::Sarif => self.render_sarif(),
+            ReportFormat::Github => self.render_github(),
+        }
+    }
+
+    fn annotations(&self) -> Vec<Annotation<'_>> {
+        let unformatted = self.files.iter().flat_map(|file| {
+            file.lines.iter().map(move |lines| Annotation {
+                rule: UNFORMATTED_RULE,
+                path: Some(&file.path),
+                lines: Some((lines.start, lines.end)),
+                column: None,
+                message: if lines.start == lines.end {
+                    format!("Rubyfmt would format line {} differently", lines.start)
+                } else {
+                    format!(
+                        "Rubyfmt would format lines {} to {} differently",
+                        lines.start, lines.end
+                    )
+                },
+            })
+        });
+        let errors = self.errors.iter().map(|error| Annotation {
+            rule: &error.kind,
+            path: error.path.as_deref(),
+            lines: error.line.map(|line| (line, line)),
+            column: error.column,
+            message: error.message.clone(),
+        });
+        let summary =
+            internal_error_summary(&self.internal_error_files).map(|message| Annotation {
+                rule: "InternalError",
+                path: None,
+                lines: None,
+                column: None,
+                message,
+            });
+        unformatted.chain(errors).chain(summary).collect()
+    }
+
+    fn render_checkstyle(&self) -> String {
+        let mut output = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        output.push_str("<checkstyle version=\"4.3\">\n");
+
+        // Every annotation for a file goes in the same <file> element, in the
+        // order the files were first seen
+        let mut files: Vec<(&str, Vec<Annotation>)> = Vec::new();
+        for annotation in self.annotations() {
+            let path = annotation.path.unwrap_or("");
+            match files.iter_mut().find(|(file, _)| *file == path) {
+                Some((_, annotations)) => annotations.push(annotation),
+                None => files.push((path, vec![annotation])),
+            }
+        }
+
+        for (path, annotations) in files {
+            output.push_str(&format!("  <file name=\"{}\">\n", xml_escape(path)));
+            for annotation in annotations {
+                output.push_str(&render_checkstyle_error(&annotation));
+            }
+            output.push_str("  </file>\n");
+        }
+
+        output.push_str("</checkstyle>\n");
+        output
+    }
+
+    fn render_sarif(&self) -> String {
+        let rules: Vec<_> = RULES
+            .iter()
+            .map(|(id, description)| json!({ "id": id, "shortDescription": { "text": description } }))
+            .collect();
+
+        let results: Vec<_> = self
+            .annotations()
+            .into_iter()
+            .map(|annotation| {
+                let mut result = json!({
+                    "ruleId": annotation.rule,
+                    "level": "error",
+                    "message": { "text": annotation.message },
+                });
+                if let Some(path) = annotation.path {
+                    let mut region = json!({});
+                    if let Some((start, end)) = annotation.lines {
+                        region["startLine"] = json!(start);
+                        region["endLine"] = json!(end);
+                    }
+                    if let Some(column) = annotation.column {
+                        region["startColumn"] = json!(column);
+                    }
+
+                    let mut location = json!({ "artifactLocation": { "uri": path } });
+                    if annotation.lines.is_some() {
+                        location["region"] = region;
+                    }
+                    result["locations"] = json!([{ "physicalLocation": location }]);
+                }
+                result
+            })
+            .collect();
+
+        let sarif = json!({
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": "rubyfmt",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "informationUri": "https://github.com/penelopezone/rubyfmt",
+                        "rules": rules,
+                    },
+                },
+                "results": results,
+            }],
+        });
+        format!("{}\n", serde_json::to_string_pretty(&sarif).unwrap())
+    }
+
+    // GitHub Actions workflow commands, which annotate the lines in pull
+    // requests
+    fn render_github(&self) -> String {
+        let mut output = String::new();
+        for annotation in self.annotations() {
+            let mut properties = Vec::new();
+            if let Some(path) = annotation.path {
+                properties.push(format!("file={}", github_escape_property(path)));
+            }
+            if let Some((start, end)) = annotation.lines {
+                properties.push(format!("line={},endLine={}", start, end));
+            }
+            if let Some(column) = annotation.column {
+                properties.push(format!("col={}", column));
+            }
+            properties.push(format!(
+                "title={}",
+                github_escape_property(&format!("rubyfmt {}", annotation.rule))
+            ));
+
+            output.push_str(&format!(
+                "::error {}::{}\n",
+                properties.join(","),
+                github_escape_data(&annotation.message)
+            ));
+        }
+        output
+    }
+}
+
+fn render_checkstyle_error(annotation: &Annotation) -> String {
+    let mut output = String::from("    <error");
+    if let Some((start, _)) = annotation.lines {
+        output.push_str(&format!(" line=\"{}\"", start));
+    }
+    if let Some(column) = annotation.column {
+        output.push_str(&format!(" column=\"{}\"", column));
+    }
+    output.push_str(&format!(
+        " severity=\"error\" message=\"{}\" source=\"rubyfmt.{}\"/>\n",
+        xml_escape(&annotation.message),
+        annotation.rule
+    ));
+    output
+}
+
+// A summary of the files that rubyfmt hit internal errors in, if there were
+// any, since their errors may have been lost among many others
+pub fn internal_error_summary(files: &[String]) -> Option<String> {
+    if files.is_empty() {
+        return None;
+    }
+
+    let mut summary = format!(
+        "Rubyfmt hit internal errors in {} file(s), which weren't formatted:\n",
+        files.len()
+    );
+    for file in files {
+        summary.push_str(&format!("  {}\n", file));
+    }
+    summary.push_str(
+        "Please file a bug report at https://github.com/penelopezone/rubyfmt/issues/new.",
+    );
+    Some(summary)
+}
+
+// The message for an error from formatting a file, without where it came from
+pub fn error_message(err: &RichFormatError) -> String {
+    match err {
+        RichFormatError::SyntaxError { message, .. } => {
+            format!("Rubyfmt detected a syntax error: {}", message)
+        }
+        RichFormatError::RipperParseFailure(e) => format!(
+            "Rubyfmt failed to correctly deserialize a tree from ripper. This is a bug that needs to be reported: {}",
+            e
+        ),
+        RichFormatError::IOError(e) => format!("Rubyfmt experienced an IO error: {}", e),
+        RichFormatError::OtherRubyError(s) => {
+            format!("Rubyfmt experienced an unexpected ruby error: {}", s)
+        }
+        RichFormatError::VerificationFailed(diff) => format!(
+            "Rubyfmt's output doesn't parse to the same program as its input. This is a bug that needs to be reported.\n{}",
+            diff
+        ),
+        RichFormatError::InternalError { message, .. } => format!(
+            "Rubyfmt hit an internal error. This is a bug that needs to be reported: {}",
+            message
+        ),
+    }
+}
+
+// The ranges of lines in `before`, counting from 1, that differ in `after`.
+// Lines that are only inserted are attributed to the line they're inserted
+// before, or the last line if they're inserted at the end.
+fn changed_lines(before: &str, after: &str) -> Vec<RangeInclusive<usize>> {
+    let diff = TextDiff::from_lines(before, after);
+    let line_count = diff.old_slices().len();
+
+    let mut lines: Vec<RangeInclusive<usize>> = Vec::new();
+    for op in diff.ops() {
+        if let DiffOp::Equal { .. } = op {
+            continue;
+        }
+
+        let old_range = op.old_range();
+        let changed = if old_range.is_empty() {
+            let line = (old_range.start + 1).min(line_count).max(1);
+            line..=line
+        } else {
+            old_range.start + 1..=old_range.end
+        };
+
+        match lines.last_mut() {
+            Some(last) if *changed.start() <= last.end() + 1 => {
+                *last = *last.start()..=*changed.end().max(last.end());
+            }
+            _ => lines.push(changed),
+        }
+    }
+    lines
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+        .replace('\n', "&#10;")
+}
+
+fn github_escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn github_escape_property(s: &str) -> String {
+    github_escape_data(s)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
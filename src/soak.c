@@ -0,0 +1,55 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/resource.h>
+#include "../librubyfmt/include/rubyfmt.h"
+
+// Formats STDIN over and over, like an editor or language server would, and
+// fails if memory use keeps growing once the first formats have warmed up
+int main(int argc, char** argv) {
+    long iterations = argc > 1 ? atol(argv[1]) : 10000;
+    long warmup = iterations / 10;
+
+    int buf_size = 1024;
+    int bytes_read = 0;
+    unsigned char* buf = malloc(sizeof(char)*buf_size);
+    int nread = fread(buf, sizeof(char), buf_size, stdin);
+    while (nread == buf_size) {
+        bytes_read += nread;
+        int old_buf_size = buf_size;
+        buf_size *= 2;
+        buf = realloc(buf, sizeof(char)*buf_size);
+        nread = fread(buf+bytes_read, sizeof(char), buf_size-old_buf_size, stdin);
+    }
+    bytes_read += nread;
+
+    int res = rubyfmt_init();
+    if (res != RUBYFMT_INIT_STATUS_OK) {
+        fprintf(stderr, "failed to init\n");
+        exit(1);
+    }
+
+    struct rusage usage;
+    long warm_max_rss = 0;
+    for (long i = 0; i < iterations; i++) {
+        enum Rubyfmt_FormatError status = RUBYFMT_FORMAT_ERROR_OK;
+        RubyfmtString* out = rubyfmt_format_buffer(buf, bytes_read, &status);
+        if (status != 0) {
+            fprintf(stderr, "formatting failed on iteration %ld\n", i);
+            exit(status);
+        }
+        rubyfmt_string_free(out);
+
+        if (i == warmup) {
+            getrusage(RUSAGE_SELF, &usage);
+            warm_max_rss = usage.ru_maxrss;
+        }
+    }
+
+    getrusage(RUSAGE_SELF, &usage);
+    fprintf(stderr, "max RSS after %ld formats: %ld, after %ld formats: %ld\n",
+            warmup + 1, warm_max_rss, iterations, usage.ru_maxrss);
+    if (usage.ru_maxrss > warm_max_rss + warm_max_rss / 2) {
+        fprintf(stderr, "memory use kept growing\n");
+        exit(1);
+    }
+}
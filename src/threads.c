@@ -0,0 +1,82 @@
+#include <pthread.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include "../librubyfmt/include/rubyfmt.h"
+
+#define THREADS 4
+#define FORMATS_PER_THREAD 25
+
+static RubyfmtFormatter* formatter;
+static unsigned char* buf;
+static size_t buf_len;
+
+// Formats STDIN repeatedly, returning the last output, or NULL if any format
+// failed or gave different output
+static void* format_repeatedly(void* arg) {
+    char* expected = NULL;
+    size_t expected_len = 0;
+    for (int i = 0; i < FORMATS_PER_THREAD; i++) {
+        enum Rubyfmt_FormatError status = RUBYFMT_FORMAT_ERROR_OK;
+        RubyfmtString* out = rubyfmt_formatter_format(formatter, buf, buf_len, NULL, 0, &status);
+        if (status != RUBYFMT_FORMAT_ERROR_OK) {
+            fprintf(stderr, "formatting failed with %d\n", status);
+            return NULL;
+        }
+
+        size_t len = rubyfmt_string_len(out);
+        if (expected == NULL) {
+            expected = malloc(len);
+            memcpy(expected, rubyfmt_string_ptr(out), len);
+            expected_len = len;
+        } else if (len != expected_len || memcmp(expected, rubyfmt_string_ptr(out), len) != 0) {
+            fprintf(stderr, "formatting gave different output on another pass\n");
+            return NULL;
+        }
+        rubyfmt_string_free(out);
+    }
+    *(size_t*)arg = expected_len;
+    return expected;
+}
+
+// Formats STDIN from several threads at once through a RubyfmtFormatter,
+// and prints the output once every thread agrees on it
+int main() {
+    size_t buf_size = 1024;
+    buf = malloc(buf_size);
+    size_t nread;
+    while ((nread = fread(buf + buf_len, 1, buf_size - buf_len, stdin)) > 0) {
+        buf_len += nread;
+        if (buf_len == buf_size) {
+            buf_size *= 2;
+            buf = realloc(buf, buf_size);
+        }
+    }
+
+    formatter = rubyfmt_formatter_new();
+    if (formatter == NULL) {
+        fprintf(stderr, "failed to init\n");
+        exit(1);
+    }
+
+    pthread_t threads[THREADS];
+    size_t lens[THREADS];
+    for (int i = 0; i < THREADS; i++) {
+        pthread_create(&threads[i], NULL, format_repeatedly, &lens[i]);
+    }
+
+    char* outputs[THREADS];
+    for (int i = 0; i < THREADS; i++) {
+        pthread_join(threads[i], (void**)&outputs[i]);
+        if (outputs[i] == NULL) {
+            exit(1);
+        }
+        if (lens[i] != lens[0] || memcmp(outputs[i], outputs[0], lens[0]) != 0) {
+            fprintf(stderr, "threads got different output\n");
+            exit(1);
+        }
+    }
+
+    fwrite(outputs[0], 1, lens[0], stdout);
+    rubyfmt_formatter_free(formatter);
+}